@@ -1,11 +1,39 @@
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
-use std::path::Path;
-use std::process::Command;
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::error::XavError;
+use crate::ffms::VidInf;
+use crate::progs::{ProgsBar, Sink};
+
+/// How the source is cut into chunks.
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum SplitMethod {
+    #[default]
+    Scene,
+    Keyframe,
+    Fixed,
+}
+
+impl SplitMethod {
+    #[must_use]
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "scene" => Some(Self::Scene),
+            "keyframe" => Some(Self::Keyframe),
+            "fixed" => Some(Self::Fixed),
+            _ => None,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Scene {
     pub s_frame: usize,
     pub e_frame: usize,
+    pub params: Option<String>,
 }
 
 #[derive(Clone)]
@@ -13,8 +41,10 @@ pub struct Chunk {
     pub idx: usize,
     pub start: usize,
     pub end: usize,
+    pub params: Option<String>,
 }
 
+#[derive(Clone, Copy)]
 pub struct ChunkComp {
     pub idx: usize,
     pub frames: usize,
@@ -25,31 +55,239 @@ pub struct ResumeInf {
     pub chnks_done: Vec<ChunkComp>,
 }
 
-pub fn load_scenes(path: &Path, t_frames: usize) -> Result<Vec<Scene>, Box<dyn std::error::Error>> {
+fn check_header(header: &str, inf: &VidInf, warn: &Sink) -> Result<(), XavError> {
+    let parts: Vec<&str> = header.split_whitespace().collect();
+    let [res, fps, frames] = parts[..] else {
+        return Err(XavError::Scenes(format!("Invalid scene file header: {header}")));
+    };
+
+    let source_res = format!("{}x{}", inf.width, inf.height);
+    let source_fps = format!("{}/{}", inf.fps_num, inf.fps_den);
+    if res != source_res || fps != source_fps {
+        return Err(XavError::Scenes(format!(
+            "Scene file was made for {res} @ {fps} but the source is {source_res} @ {source_fps}"
+        )));
+    }
+
+    if frames.parse::<usize>().ok() != Some(inf.frames) {
+        warn.warn(&format!(
+            "Scene file was made for {frames} frames but the source has {}",
+            inf.frames
+        ));
+    }
+
+    Ok(())
+}
+
+/// Frames covered by a scene file whose header matches the source resolution and frame rate.
+pub fn covered_frames(content: &str, inf: &VidInf) -> Option<usize> {
+    let header = content.lines().find_map(|line| line.trim().strip_prefix('#'))?;
+    let parts: Vec<&str> = header.split_whitespace().collect();
+    let [res, fps, frames] = parts[..] else { return None };
+    (res == format!("{}x{}", inf.width, inf.height)
+        && fps == format!("{}/{}", inf.fps_num, inf.fps_den))
+    .then(|| frames.parse().ok())
+    .flatten()
+}
+
+pub fn read_cuts(content: &str) -> Vec<(usize, Option<String>)> {
+    let mut cuts: Vec<(usize, Option<String>)> = content
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let (frame, params) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let params = params.trim();
+            Some((frame.parse().ok()?, (!params.is_empty()).then(|| params.to_string())))
+        })
+        .collect();
+
+    cuts.sort_by_key(|&(f, _)| f);
+    cuts.dedup_by_key(|&mut (f, _)| f);
+    cuts
+}
+
+pub fn load_scenes(path: &Path, inf: &VidInf, warn: &Sink) -> Result<Vec<Scene>, XavError> {
+    let t_frames = inf.frames;
     let content = fs::read_to_string(path)?;
-    let mut s_frames: Vec<usize> =
-        content.lines().filter_map(|line| line.trim().parse().ok()).collect();
+    if let Some(header) = content.lines().find_map(|line| line.trim().strip_prefix('#')) {
+        check_header(header, inf, warn)?;
+    }
 
-    s_frames.sort_unstable();
+    let mut s_frames = read_cuts(&content);
+
+    let over = s_frames.iter().filter(|&&(f, _)| f >= t_frames).count();
+    if over > 0 {
+        warn.warn(&format!(
+            "{over} scene cuts are beyond the source frame count ({t_frames}), ignoring them. The \
+             scene file may belong to a different source"
+        ));
+        s_frames.retain(|&(f, _)| f < t_frames);
+    }
 
     let mut scenes = Vec::new();
     for i in 0..s_frames.len() {
-        let s = s_frames[i];
-        let e = s_frames.get(i + 1).copied().unwrap_or(t_frames);
-        scenes.push(Scene { s_frame: s, e_frame: e });
+        let (s, ref params) = s_frames[i];
+        let e = s_frames.get(i + 1).map_or(t_frames, |&(f, _)| f);
+        scenes.push(Scene { s_frame: s, e_frame: e, params: params.clone() });
     }
 
     Ok(scenes)
 }
 
+pub fn fixed_scenes(t_frames: usize, len: usize) -> Vec<Scene> {
+    (0..t_frames)
+        .step_by(len)
+        .map(|s| Scene { s_frame: s, e_frame: (s + len).min(t_frames), params: None })
+        .collect()
+}
+
+pub fn trim_scenes(scenes: &[Scene], trim: &[(usize, usize, bool)], t_frames: usize) -> Vec<Scene> {
+    let mut keep: Vec<(usize, usize)> =
+        trim.iter().filter(|t| !t.2).map(|&(s, e, _)| (s, e.min(t_frames))).collect();
+    if keep.is_empty() {
+        keep.push((0, t_frames));
+    }
+
+    let is_kept = |f: usize| {
+        keep.iter().any(|&(s, e)| (s..e).contains(&f))
+            && !trim.iter().any(|&(s, e, drop)| drop && (s..e).contains(&f))
+    };
+
+    let mut trimmed = Vec::new();
+    for scene in scenes {
+        let mut run = None;
+        for f in scene.s_frame..=scene.e_frame {
+            let kept = f < scene.e_frame && is_kept(f);
+            match (run, kept) {
+                (None, true) => run = Some(f),
+                (Some(s), false) => {
+                    trimmed.push(Scene { s_frame: s, e_frame: f, params: scene.params.clone() });
+                    run = None;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    trimmed
+}
+
+/// Moves every scene start to the nearest source keyframe within `max_shift` frames, dropping
+/// scenes that collapse. Returns the scenes and how many starts had no keyframe in reach.
+pub fn align_scenes(
+    scenes: &[Scene],
+    keyframes: &[usize],
+    max_shift: usize,
+) -> (Vec<Scene>, usize) {
+    let Some(last) = scenes.last() else { return (Vec::new(), 0) };
+    let mut starts: Vec<(usize, Option<String>)> = Vec::with_capacity(scenes.len());
+    let mut missed = 0;
+    for (i, scene) in scenes.iter().enumerate() {
+        let s = scene.s_frame;
+        let near = match keyframes.binary_search(&s) {
+            Ok(_) => Some(s),
+            Err(k) => [k.checked_sub(1).map(|j| keyframes[j]), keyframes.get(k).copied()]
+                .into_iter()
+                .flatten()
+                .filter(|&f| f.abs_diff(s) <= max_shift && f < last.e_frame)
+                .min_by_key(|&f| f.abs_diff(s)),
+        };
+        let start = if i == 0 {
+            s
+        } else if let Some(f) = near {
+            f
+        } else {
+            missed += 1;
+            s
+        };
+        if starts.last().is_some_and(|&(prev, _)| start <= prev) {
+            continue;
+        }
+        starts.push((start, scene.params.clone()));
+    }
+
+    let aligned = starts
+        .iter()
+        .enumerate()
+        .map(|(i, (s, params))| Scene {
+            s_frame: *s,
+            e_frame: starts.get(i + 1).map_or(last.e_frame, |&(f, _)| f),
+            params: params.clone(),
+        })
+        .collect();
+    (aligned, missed)
+}
+
 pub fn chunkify(scenes: &[Scene]) -> Vec<Chunk> {
     scenes
         .iter()
         .enumerate()
-        .map(|(i, s)| Chunk { idx: i, start: s.s_frame, end: s.e_frame })
+        .map(|(i, s)| Chunk { idx: i, start: s.s_frame, end: s.e_frame, params: s.params.clone() })
         .collect()
 }
 
+/// What happens to the source audio when the output is muxed.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    Copy,
+    Opus,
+    Aac,
+}
+
+impl AudioCodec {
+    #[must_use]
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "copy" => Some(Self::Copy),
+            "opus" => Some(Self::Opus),
+            "aac" => Some(Self::Aac),
+            _ => None,
+        }
+    }
+
+    const fn ffmpeg_codec(self) -> Option<&'static str> {
+        match self {
+            Self::Copy => None,
+            Self::Opus => Some("libopus"),
+            Self::Aac => Some("aac"),
+        }
+    }
+}
+
+pub struct Audio<'a> {
+    pub source: &'a Path,
+    pub codec: AudioCodec,
+    pub bitrate: Option<u32>,
+}
+
+fn prep_audio(audio: &Audio, work_dir: &Path) -> Result<Option<PathBuf>, XavError> {
+    let Some(codec) = audio.codec.ffmpeg_codec() else {
+        return Ok(Some(audio.source.to_path_buf()));
+    };
+    if !crate::ffms::has_audio(audio.source) {
+        return Ok(None);
+    }
+
+    let path = work_dir.join("audio.mka");
+    let mut cmd = Command::new("ffmpeg");
+    cmd.args(["-v", "error", "-y", "-i"]).arg(audio.source).args(["-map", "0:a", "-c:a", codec]);
+    if let Some(kbps) = audio.bitrate {
+        cmd.arg("-b:a").arg(format!("{kbps}k"));
+    }
+    let out = cmd.arg(&path).stdin(Stdio::null()).output()?;
+    if !out.status.success() {
+        return Err(XavError::Merge(format!(
+            "Audio encode failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )));
+    }
+    Ok(Some(path))
+}
+
+fn add_audio(cmd: &mut Command, path: &Path) {
+    cmd.args(["-D", "-S", "-B", "-M", "-T", "--no-chapters", "--no-global-tags"]).arg(path);
+}
+
 pub fn get_resume(work_dir: &Path) -> Option<ResumeInf> {
     let path = work_dir.join("done.txt");
     path.exists()
@@ -70,12 +308,18 @@ pub fn get_resume(work_dir: &Path) -> Option<ResumeInf> {
                 }
             }
 
+            let compacted = merged_prefix(work_dir);
+            chnks_done.retain(|c| {
+                c.idx < compacted
+                    || fs::metadata(work_dir.join("encode").join(format!("{:04}.ivf", c.idx)))
+                        .is_ok_and(|m| m.len() > 32)
+            });
             Some(ResumeInf { chnks_done })
         })
         .flatten()
 }
 
-pub fn save_resume(data: &ResumeInf, work_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+pub fn save_resume(data: &ResumeInf, work_dir: &Path) -> Result<(), XavError> {
     let path = work_dir.join("done.txt");
     let mut content = String::new();
 
@@ -94,28 +338,342 @@ pub fn save_resume(data: &ResumeInf, work_dir: &Path) -> Result<(), Box<dyn std:
     Ok(())
 }
 
+pub fn partial_path(output: &Path) -> PathBuf {
+    output.with_extension("partial.ivf")
+}
+
+pub fn prefix_path(work_dir: &Path) -> PathBuf {
+    work_dir.join("prefix.ivf")
+}
+
+/// Number of leading chunks already merged into `prefix.ivf` and removed from `encode/`.
+pub fn merged_prefix(work_dir: &Path) -> usize {
+    fs::read_to_string(work_dir.join("prefix.txt"))
+        .ok()
+        .and_then(|s| s.split_whitespace().next()?.parse().ok())
+        .unwrap_or(0)
+}
+
+pub fn dir_size(dir: &Path) -> u64 {
+    fs::read_dir(dir).map_or(0, |entries| {
+        entries.filter_map(Result::ok).filter_map(|e| e.metadata().ok()).map(|m| m.len()).sum()
+    })
+}
+
+pub struct PartialOut {
+    path: PathBuf,
+    encode_dir: PathBuf,
+    next_idx: usize,
+    frames: u64,
+    state: Option<PathBuf>,
+}
+
+impl PartialOut {
+    pub fn new(path: PathBuf, encode_dir: PathBuf) -> Self {
+        let _ = fs::remove_file(&path);
+        Self { path, encode_dir, next_idx: 0, frames: 0, state: None }
+    }
+
+    /// Continues a prefix whose progress is recorded in `state`, dropping any append that was
+    /// cut short before it was recorded.
+    pub fn resume(path: PathBuf, encode_dir: PathBuf, state: PathBuf) -> Self {
+        let saved = fs::read_to_string(&state).ok().and_then(|s| {
+            let mut it = s.split_whitespace();
+            Some((
+                it.next()?.parse::<usize>().ok()?,
+                it.next()?.parse::<u64>().ok()?,
+                it.next()?.parse::<u64>().ok()?,
+            ))
+        });
+
+        let (next_idx, frames) = match saved {
+            Some((next_idx, frames, len))
+                if fs::metadata(&path).is_ok_and(|m| m.len() >= len)
+                    && fs::OpenOptions::new()
+                        .write(true)
+                        .open(&path)
+                        .and_then(|f| f.set_len(len))
+                        .is_ok() =>
+            {
+                (next_idx, frames)
+            }
+            _ => {
+                let _ = fs::remove_file(&path);
+                let _ = fs::remove_file(&state);
+                (0, 0)
+            }
+        };
+
+        Self { path, encode_dir, next_idx, frames, state: Some(state) }
+    }
+
+    /// Starts from a copy of `other` when chunks it holds are no longer in `encode/`.
+    pub fn seed(&mut self, other: &Self) -> Result<(), XavError> {
+        if other.next_idx > self.next_idx {
+            fs::copy(&other.path, &self.path)?;
+            self.next_idx = other.next_idx;
+            self.frames = other.frames;
+        }
+        Ok(())
+    }
+
+    pub fn append_ready(&mut self, done: &[ChunkComp]) -> Result<(), XavError> {
+        while done.iter().any(|c| c.idx == self.next_idx) {
+            let ivf = fs::read(self.encode_dir.join(format!("{:04}.ivf", self.next_idx)))?;
+            self.append(&ivf)?;
+            self.next_idx += 1;
+            if let Some(ref state) = self.state {
+                let tmp = state.with_extension("tmp");
+                let len = fs::metadata(&self.path)?.len();
+                fs::write(&tmp, format!("{} {} {len}\n", self.next_idx, self.frames))?;
+                fs::rename(tmp, state)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the chunks that are recorded as merged.
+    pub fn prune(&self) {
+        for idx in 0..self.next_idx {
+            let _ = fs::remove_file(self.encode_dir.join(format!("{idx:04}.ivf")));
+        }
+    }
+
+    fn append(&mut self, ivf: &[u8]) -> Result<(), XavError> {
+        let (buf, frames) = rebase_ivf(ivf, self.frames)?;
+        let mut out =
+            fs::OpenOptions::new().create(true).truncate(false).write(true).open(&self.path)?;
+
+        out.seek(SeekFrom::End(0))?;
+        out.write_all(&buf)?;
+        self.frames += frames;
+
+        out.seek(SeekFrom::Start(24))?;
+        out.write_all(&u32::try_from(self.frames).unwrap_or(u32::MAX).to_le_bytes())?;
+        Ok(())
+    }
+}
+
+/// Shifts the frame timestamps of an IVF chunk by `offset` frames. The file header is kept only for
+/// the first chunk of a stream, where `offset` is 0. A truncated last frame is left out. Returns
+/// the bytes and the frame count.
+fn rebase_ivf(ivf: &[u8], offset: u64) -> Result<(Vec<u8>, u64), XavError> {
+    if ivf.len() < 32 {
+        return Err(XavError::Merge("Invalid IVF chunk".into()));
+    }
+
+    let header_len = usize::from(u16::from_le_bytes([ivf[6], ivf[7]]));
+    let mut buf = Vec::with_capacity(ivf.len());
+
+    if offset == 0 {
+        buf.extend_from_slice(&ivf[..header_len]);
+    }
+
+    let mut pos = header_len;
+    let mut frames = 0;
+    while pos + 12 <= ivf.len() {
+        let size =
+            u32::from_le_bytes([ivf[pos], ivf[pos + 1], ivf[pos + 2], ivf[pos + 3]]) as usize;
+        let pts = u64::from_le_bytes(ivf[pos + 4..pos + 12].try_into().unwrap_or_default());
+        let end = pos + 12 + size;
+        if end > ivf.len() {
+            break;
+        }
+
+        buf.extend_from_slice(&ivf[pos..pos + 4]);
+        buf.extend_from_slice(&(pts + offset).to_le_bytes());
+        buf.extend_from_slice(&ivf[pos + 12..end]);
+
+        pos = end;
+        frames += 1;
+    }
+
+    Ok((buf, frames))
+}
+
+/// One IVF stream of the finished chunks in index order, holding back chunks that finish early.
+/// It only ever appends, so the target can be a named pipe. The header frame count stays 0.
+pub struct InOrderOut {
+    out: Option<fs::File>,
+    encode_dir: PathBuf,
+    warn: Sink,
+    next_idx: usize,
+    frames: u64,
+    pending: BTreeSet<usize>,
+}
+
+impl InOrderOut {
+    pub fn new(path: &Path, encode_dir: PathBuf, warn: Sink) -> Result<Self, XavError> {
+        let out = fs::OpenOptions::new().create(true).truncate(true).write(true).open(path)?;
+        Ok(Self {
+            out: Some(out),
+            encode_dir,
+            warn,
+            next_idx: 0,
+            frames: 0,
+            pending: BTreeSet::new(),
+        })
+    }
+
+    pub fn push(&mut self, idx: usize) {
+        self.pending.insert(idx);
+        while self.pending.remove(&self.next_idx) {
+            if let Err(e) = self.write_next() {
+                self.warn.warn(&format!("Stopped writing the --in-order stream: {e}"));
+                self.out = None;
+            }
+            self.next_idx += 1;
+        }
+    }
+
+    fn write_next(&mut self) -> Result<(), XavError> {
+        let Some(ref mut out) = self.out else { return Ok(()) };
+        let ivf = fs::read(self.encode_dir.join(format!("{:04}.ivf", self.next_idx)))?;
+        let (mut buf, frames) = rebase_ivf(&ivf, self.frames)?;
+        if self.frames == 0 {
+            buf[24..28].fill(0);
+        }
+        out.write_all(&buf)?;
+        self.frames += frames;
+        Ok(())
+    }
+}
+
+/// Appends the source frames of a finished chunk that failed to decode to `dropped.txt`.
+pub fn save_dropped(work_dir: &Path, frames: &[usize]) -> Result<(), XavError> {
+    if frames.is_empty() {
+        return Ok(());
+    }
+    let lines: String = frames.iter().map(|f| format!("{f}\n")).collect();
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(work_dir.join("dropped.txt"))?
+        .write_all(lines.as_bytes())?;
+    Ok(())
+}
+
+pub fn get_dropped(work_dir: &Path) -> BTreeSet<usize> {
+    fs::read_to_string(work_dir.join("dropped.txt"))
+        .map(|content| content.lines().filter_map(|l| l.trim().parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Gives every encoded frame its source timestamp, so the time of a dropped frame goes to the
+/// frame before it. Chunks whose encoded count does not match `dropped` are spread evenly.
+#[allow(clippy::cast_precision_loss)]
+pub fn write_timestamps(
+    chunks: &[Chunk],
+    ts: &[f64],
+    encoded: &HashMap<usize, usize>,
+    dropped: &BTreeSet<usize>,
+    path: &Path,
+) -> Result<f64, XavError> {
+    use std::fmt::Write;
+
+    let mut content = String::from("# timestamp format v2\n");
+    let mut t = 0.0;
+    for c in chunks {
+        let len = c.end - c.start;
+        let n = encoded.get(&c.idx).copied().unwrap_or(len);
+        if n == len - dropped.range(c.start..c.end).count() {
+            for f in c.start..c.end {
+                if !dropped.contains(&f) {
+                    let _ = writeln!(content, "{t:.6}");
+                }
+                t += ts[f + 1] - ts[f];
+            }
+        } else {
+            let step = (ts[c.end] - ts[c.start]) / n.max(1) as f64;
+            for _ in 0..n {
+                let _ = writeln!(content, "{t:.6}");
+                t += step;
+            }
+        }
+    }
+
+    fs::write(path, content)?;
+    Ok(t / 1000.0)
+}
+
+/// Fails naming every chunk whose IVF is missing or holds no frames. Those chunks are dropped
+/// from the resume state so `-r` encodes them again.
+fn check_chunks(encode_dir: &Path, chunks: &[Chunk]) -> Result<(), XavError> {
+    let compacted = encode_dir.parent().map_or(0, merged_prefix);
+    let missing: Vec<usize> = chunks
+        .iter()
+        .map(|c| c.idx)
+        .filter(|&idx| {
+            idx >= compacted
+                && fs::metadata(encode_dir.join(format!("{idx:04}.ivf")))
+                    .map_or(true, |m| m.len() <= 32)
+        })
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    let list: Vec<String> = missing.iter().map(ToString::to_string).collect();
+    Err(XavError::Encode(format!(
+        "{} of {} chunks have no output in {} (chunk {}). Their frames could not be decoded or \
+         the encoder failed, rerun with -r to encode them",
+        missing.len(),
+        chunks.len(),
+        encode_dir.display(),
+        list.join(", ")
+    )))
+}
+
+/// Everything besides the chunks that goes into the final mux.
+pub struct MuxOpts<'a> {
+    pub provenance: String,
+    pub audio: Option<Audio<'a>>,
+    pub deterministic: bool,
+    /// Where the merge progress goes, `None` to run mkvmerge quietly.
+    pub progs: Option<&'a Sink>,
+}
+
 pub fn merge_out(
     encode_dir: &Path,
     output: &Path,
-    inf: &crate::ffms::VidInf,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut files: Vec<_> = fs::read_dir(encode_dir)?
-        .filter_map(Result::ok)
-        .filter(|e| e.path().extension().is_some_and(|ext| ext == "ivf"))
-        .collect();
+    inf: &VidInf,
+    chunks: &[Chunk],
+    timestamps: Option<&Path>,
+    opts: &MuxOpts,
+) -> Result<(), XavError> {
+    check_chunks(encode_dir, chunks)?;
+    let audio = match (opts.audio.as_ref(), encode_dir.parent()) {
+        (Some(a), Some(work_dir)) => prep_audio(a, work_dir)?,
+        _ => None,
+    };
+    let compacted = encode_dir.parent().map_or(0, merged_prefix);
+    let mut idxs: Vec<usize> =
+        chunks.iter().map(|c| c.idx).filter(|&idx| idx >= compacted).collect();
+    idxs.sort_unstable();
+    let mut files: Vec<PathBuf> =
+        idxs.into_iter().map(|idx| encode_dir.join(format!("{idx:04}.ivf"))).collect();
+    if compacted > 0 {
+        files.insert(0, encode_dir.with_file_name("prefix.ivf"));
+    }
 
-    files.sort_by_key(|e| {
-        e.path()
-            .file_stem()
-            .and_then(|s| s.to_str())
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(0)
-    });
+    if let Some(work_dir) = encode_dir.parent() {
+        let mut manifest = String::new();
+        for file in &files {
+            use std::fmt::Write;
+            let _ = writeln!(
+                manifest,
+                "file '{}'",
+                file.strip_prefix(work_dir).unwrap_or(file).display()
+            );
+        }
+        fs::write(work_dir.join("concat.txt"), manifest)?;
+    }
 
-    let mut cmd = Command::new("mkvmerge");
-    cmd.arg("-q")
-        .arg("-o")
-        .arg(output)
+    let merged = encode_dir.with_file_name("merged.mkv");
+    let mut cmd = mkvmerge(opts);
+    cmd.arg("-o")
+        .arg(if timestamps.is_some() { &merged } else { output })
         .arg("-A")
         .arg("-S")
         .arg("-B")
@@ -126,16 +684,129 @@ pub fn merge_out(
         .arg("--no-date")
         .arg("--disable-language-ietf");
 
+    colorize_mkv(&mut cmd, inf);
+
+    let tags = encode_dir.with_file_name("tags.xml");
+    fs::write(&tags, tags_xml(&opts.provenance))?;
+    cmd.arg("--global-tags").arg(&tags);
+
     for (i, file) in files.iter().enumerate() {
         if i == 0 {
-            cmd.arg(file.path());
+            cmd.arg(file);
         } else {
-            cmd.arg("+").arg(file.path());
+            cmd.arg("+").arg(file);
         }
     }
+    if let Some(ref path) = audio
+        && timestamps.is_none()
+    {
+        add_audio(&mut cmd, path);
+    }
 
     cmd.arg("--default-duration").arg(format!("0:{}/{}fps", inf.fps_num, inf.fps_den));
 
-    cmd.status()?;
+    run_mkvmerge(&mut cmd, opts.progs)?;
+    let Some(ts_path) = timestamps else { return Ok(()) };
+
+    let mut cmd = mkvmerge(opts);
+    cmd.arg("-o")
+        .arg(output)
+        .arg("--timestamps")
+        .arg(format!("0:{}", ts_path.display()))
+        .arg(&merged);
+    if let Some(ref path) = audio {
+        add_audio(&mut cmd, path);
+    }
+    run_mkvmerge(&mut cmd, opts.progs)?;
+    fs::remove_file(merged)?;
     Ok(())
 }
+
+fn tags_xml(provenance: &str) -> String {
+    let escaped = provenance.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    format!(
+        "<?xml version=\"1.0\"?>\n<Tags><Tag><Simple><Name>ENCODER_SETTINGS</\
+         Name><String>{escaped}</String></Simple></Tag></Tags>\n"
+    )
+}
+
+fn colorize_mkv(cmd: &mut Command, inf: &VidInf) {
+    let known = |v: Option<i32>| v.filter(|&v| v != 2);
+    if let Some(cp) = known(inf.color_primaries) {
+        cmd.arg("--colour-primaries").arg(format!("0:{cp}"));
+    }
+    if let Some(tc) = known(inf.transfer_characteristics) {
+        cmd.arg("--colour-transfer-characteristics").arg(format!("0:{tc}"));
+    }
+    if let Some(mc) = known(inf.matrix_coefficients) {
+        cmd.arg("--colour-matrix-coefficients").arg(format!("0:{mc}"));
+    }
+    if let Some(cr) = inf.color_range {
+        cmd.arg("--colour-range").arg(format!("0:{}", cr + 1));
+    }
+    match inf.chroma_sample_position {
+        Some(1) => {
+            cmd.arg("--chroma-siting").arg("0:1,2");
+        }
+        Some(2) => {
+            cmd.arg("--chroma-siting").arg("0:1,1");
+        }
+        _ => {}
+    }
+
+    let nums = |s: &str| -> Vec<String> {
+        s.split(|c: char| !c.is_ascii_digit() && c != '.')
+            .filter(|n| !n.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    if let Some(ref md) = inf.mastering_display
+        && let [gx, gy, bx, by, rx, ry, wx, wy, max, min] = nums(md).as_slice()
+    {
+        cmd.arg("--chromaticity-coordinates").arg(format!("0:{rx},{ry},{gx},{gy},{bx},{by}"));
+        cmd.arg("--white-colour-coordinates").arg(format!("0:{wx},{wy}"));
+        cmd.arg("--max-luminance").arg(format!("0:{max}"));
+        cmd.arg("--min-luminance").arg(format!("0:{min}"));
+    }
+    if let Some(ref cl) = inf.content_light
+        && let [cll, fall] = nums(cl).as_slice()
+    {
+        cmd.arg("--max-content-light").arg(format!("0:{cll}"));
+        cmd.arg("--max-frame-light").arg(format!("0:{fall}"));
+    }
+}
+
+fn mkvmerge(opts: &MuxOpts) -> Command {
+    let mut cmd = Command::new("mkvmerge");
+    cmd.arg(if opts.progs.is_none() { "-q" } else { "--gui-mode" });
+    if opts.deterministic {
+        cmd.arg("--deterministic").arg("xav");
+    }
+    cmd
+}
+
+fn run_mkvmerge(cmd: &mut Command, sink: Option<&Sink>) -> Result<(), XavError> {
+    let fail = |e: std::io::Error| XavError::Merge(format!("Failed to run mkvmerge: {e}"));
+    let status = if let Some(sink) = sink {
+        let mut child = cmd.stdout(Stdio::piped()).spawn().map_err(fail)?;
+        let mut progs = ProgsBar::new(Some(sink));
+        if let Some(out) = child.stdout.take() {
+            for line in BufReader::new(out).lines().map_while(Result::ok) {
+                if let Some(perc) = line.strip_prefix("#GUI#progress ") {
+                    progs.up_merge(perc.trim_end_matches('%').parse().unwrap_or(0));
+                } else if let Some(err) = line.strip_prefix("#GUI#error ") {
+                    sink.put(&format!("\r\x1b[2Kmkvmerge: {err}\n"));
+                }
+            }
+        }
+        progs.finish();
+        child.wait().map_err(fail)?
+    } else {
+        cmd.status().map_err(fail)?
+    };
+    if status.code().is_some_and(|c| c <= 1) {
+        Ok(())
+    } else {
+        Err(XavError::Merge(format!("mkvmerge failed with {status}")))
+    }
+}
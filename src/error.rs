@@ -0,0 +1,83 @@
+use std::fmt;
+
+/// Failure kinds of the pipeline, one per stage so callers can react to them.
+#[derive(Debug)]
+pub enum XavError {
+    Args(String),
+    Missing(String),
+    Index(String),
+    Decode(String),
+    Scenes(String),
+    Grain(String),
+    Encode(String),
+    Merge(String),
+    Metric(String),
+    Stopped(String),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for XavError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Args(msg)
+            | Self::Missing(msg)
+            | Self::Index(msg)
+            | Self::Decode(msg)
+            | Self::Scenes(msg)
+            | Self::Grain(msg)
+            | Self::Encode(msg)
+            | Self::Merge(msg)
+            | Self::Metric(msg)
+            | Self::Stopped(msg) => f.write_str(msg),
+            Self::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl XavError {
+    #[must_use]
+    pub const fn exit_code(&self) -> i32 {
+        match self {
+            Self::Args(_) => 2,
+            Self::Missing(_) => 3,
+            Self::Index(_) | Self::Decode(_) | Self::Scenes(_) => 4,
+            Self::Grain(_) | Self::Encode(_) | Self::Metric(_) => 5,
+            Self::Merge(_) => 6,
+            Self::Stopped(_) => 7,
+            Self::Io(_) => 1,
+        }
+    }
+}
+
+impl std::error::Error for XavError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for XavError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<std::ffi::NulError> for XavError {
+    fn from(e: std::ffi::NulError) -> Self {
+        Self::Args(e.to_string())
+    }
+}
+
+impl From<std::num::ParseIntError> for XavError {
+    fn from(e: std::num::ParseIntError) -> Self {
+        Self::Args(e.to_string())
+    }
+}
+
+impl From<std::num::ParseFloatError> for XavError {
+    fn from(e: std::num::ParseFloatError) -> Self {
+        Self::Args(e.to_string())
+    }
+}
@@ -1,7 +1,10 @@
 use std::ffi::CString;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use crate::error::XavError;
+use crate::progs::Sink;
+
 #[repr(C)]
 pub struct FFMS_ErrorInfo {
     error_type: i32,
@@ -10,6 +13,17 @@ pub struct FFMS_ErrorInfo {
     buffer_size: i32,
 }
 
+impl FFMS_ErrorInfo {
+    const fn new(buf: &mut [i8; 1024]) -> Self {
+        Self { error_type: 0, sub_type: 0, buffer: buf.as_mut_ptr(), buffer_size: 1024 }
+    }
+
+    fn msg(&self, ctx: &str) -> String {
+        let detail = unsafe { std::ffi::CStr::from_ptr(self.buffer) }.to_string_lossy();
+        if detail.is_empty() { ctx.to_string() } else { format!("{ctx}: {detail}") }
+    }
+}
+
 #[repr(C)]
 struct FFMS_VideoProperties {
     fps_denominator: i32,
@@ -69,6 +83,20 @@ pub struct FFMS_Frame {
     pub chroma_location: i32,
 }
 
+#[repr(C)]
+struct FFMS_TrackTimeBase {
+    num: i64,
+    den: i64,
+}
+
+#[repr(C)]
+struct FFMS_FrameInfo {
+    pts: i64,
+    _repeat_pict: i32,
+    key_frame: i32,
+    _original_pts: i64,
+}
+
 type IndexCallback = extern "C" fn(current: i64, tot: i64, ic_private: *mut libc::c_void) -> i32;
 
 unsafe extern "C" {
@@ -107,9 +135,27 @@ unsafe extern "C" {
         err: *mut FFMS_ErrorInfo,
     ) -> i32;
     fn FFMS_ReadIndex(idx_file: *const i8, err: *mut FFMS_ErrorInfo) -> *mut libc::c_void;
+    fn FFMS_GetTrackFromIndex(idx: *mut libc::c_void, track: i32) -> *mut libc::c_void;
+    fn FFMS_GetTimeBase(track: *mut libc::c_void) -> *const FFMS_TrackTimeBase;
+    fn FFMS_GetFrameInfo(track: *mut libc::c_void, frame: i32) -> *const FFMS_FrameInfo;
+    fn FFMS_GetNumFrames(track: *mut libc::c_void) -> i32;
+    fn FFMS_GetNumTracks(idx: *mut libc::c_void) -> i32;
+    fn FFMS_GetTrackType(track: *mut libc::c_void) -> i32;
+}
+
+/// Source colors of an HDR input that is tone-mapped to BT.709 before encoding.
+#[cfg(feature = "vship")]
+#[derive(Clone)]
+pub struct Tonemap {
+    pub matrix: i32,
+    pub transfer: i32,
+    pub primaries: i32,
+    pub full_range: bool,
+    pub peak: f64,
 }
 
 #[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct VidInf {
     pub width: u32,
     pub height: u32,
@@ -120,16 +166,39 @@ pub struct VidInf {
     pub transfer_characteristics: Option<i32>,
     pub matrix_coefficients: Option<i32>,
     pub is_10bit: bool,
+    pub out_10bit: bool,
+    pub vfr: bool,
+    pub gray: bool,
     pub color_range: Option<i32>,
     pub chroma_sample_position: Option<i32>,
     pub mastering_display: Option<String>,
     pub content_light: Option<String>,
+    #[cfg(feature = "vship")]
+    pub tonemap: Option<Tonemap>,
+}
+
+pub struct IdxOpts {
+    pub cache: Option<PathBuf>,
+    pub seek_mode: Option<i32>,
+    pub track: Option<i32>,
 }
 
 pub struct VidIdx {
     pub path: String,
     pub track: i32,
     pub idx_handle: *mut libc::c_void,
+    pub seek_mode: Option<i32>,
+}
+
+pub fn parse_seek_mode(mode: &str) -> Option<i32> {
+    match mode {
+        "linear-norewind" => Some(-1),
+        "linear" => Some(0),
+        "normal" => Some(1),
+        "unsafe" => Some(2),
+        "aggressive" => Some(3),
+        _ => None,
+    }
 }
 
 extern "C" fn idx_progs(current: i64, tot: i64, ic_private: *mut libc::c_void) -> i32 {
@@ -142,26 +211,37 @@ extern "C" fn idx_progs(current: i64, tot: i64, ic_private: *mut libc::c_void) -
     0
 }
 
+pub fn idx_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.ffidx", path.display()))
+}
+
 impl VidIdx {
-    pub fn new(path: &Path, quiet: bool) -> Result<Arc<Self>, Box<dyn std::error::Error>> {
+    pub fn new(path: &Path, out: Option<&Sink>, opts: &IdxOpts) -> Result<Arc<Self>, XavError> {
         unsafe {
             FFMS_Init(0, 0);
 
             let source = CString::new(path.to_str().unwrap())?;
-            let mut err = std::mem::zeroed::<FFMS_ErrorInfo>();
+            let mut msg = [0i8; 1024];
+            let mut err = FFMS_ErrorInfo::new(&mut msg);
 
-            let idx_path = format!("{}.ffidx", path.display());
-            let idx_cstr = CString::new(idx_path.as_str())?;
+            let idx_cstr =
+                opts.cache.as_deref().map(|p| CString::new(p.to_str().unwrap())).transpose()?;
 
-            let idx = if std::path::Path::new(&idx_path).exists() {
-                FFMS_ReadIndex(idx_cstr.as_ptr(), std::ptr::addr_of_mut!(err))
+            let idx = if let Some(ref idx_cstr) = idx_cstr
+                && opts.cache.as_deref().is_some_and(Path::exists)
+            {
+                let idx = FFMS_ReadIndex(idx_cstr.as_ptr(), std::ptr::addr_of_mut!(err));
+                if idx.is_null() {
+                    return Err(XavError::Index(err.msg("Failed to read idx")));
+                }
+                idx
             } else {
                 let idxer = FFMS_CreateIndexer(source.as_ptr(), std::ptr::addr_of_mut!(err));
                 if idxer.is_null() {
-                    return Err("Failed to create idxer".into());
+                    return Err(XavError::Index(err.msg("Failed to create idxer")));
                 }
 
-                let mut progs = crate::progs::ProgsBar::new(quiet);
+                let mut progs = crate::progs::ProgsBar::new(out);
                 FFMS_SetProgressCallback(
                     idxer,
                     idx_progs,
@@ -173,16 +253,42 @@ impl VidIdx {
                 progs.finish();
 
                 if idx.is_null() {
-                    return Err("Failed to idx file".into());
+                    return Err(XavError::Index(err.msg("Failed to idx file")));
                 }
 
-                FFMS_WriteIndex(idx_cstr.as_ptr(), idx, std::ptr::addr_of_mut!(err));
+                if let Some(ref idx_cstr) = idx_cstr {
+                    FFMS_WriteIndex(idx_cstr.as_ptr(), idx, std::ptr::addr_of_mut!(err));
+                }
                 idx
             };
 
-            let track = FFMS_GetFirstIndexedTrackOfType(idx, 0, std::ptr::addr_of_mut!(err));
+            let track = if let Some(track) = opts.track {
+                let is_video = track < FFMS_GetNumTracks(idx) && {
+                    let t = FFMS_GetTrackFromIndex(idx, track);
+                    FFMS_GetTrackType(t) == 0 && FFMS_GetNumFrames(t) > 0
+                };
+                if !is_video {
+                    FFMS_DestroyIndex(idx);
+                    return Err(XavError::Index(format!(
+                        "Track {track} in {} is not a video track",
+                        path.display()
+                    )));
+                }
+                track
+            } else {
+                FFMS_GetFirstIndexedTrackOfType(idx, 0, std::ptr::addr_of_mut!(err))
+            };
+            if track < 0 {
+                FFMS_DestroyIndex(idx);
+                return Err(XavError::Index(format!("No video track found in {}", path.display())));
+            }
 
-            Ok(Arc::new(Self { path: path.to_str().unwrap().to_string(), track, idx_handle: idx }))
+            Ok(Arc::new(Self {
+                path: path.to_str().unwrap().to_string(),
+                track,
+                idx_handle: idx,
+                seek_mode: opts.seek_mode,
+            }))
         }
     }
 }
@@ -200,8 +306,54 @@ impl Drop for VidIdx {
 unsafe impl Send for VidIdx {}
 unsafe impl Sync for VidIdx {}
 
-fn get_chroma_loc(path: &str, frame_chroma: i32) -> Option<i32> {
-    let ffmpeg_value = std::process::Command::new("ffprobe")
+/// Codec of stream `track`, or of the first video stream like FFMS picks without one.
+pub fn get_codec(path: &Path, track: Option<i32>) -> Option<String> {
+    let stream = track.map_or_else(|| "v:0".to_string(), |t| t.to_string());
+    let out = std::process::Command::new("ffprobe")
+        .args(["-v", "quiet", "-select_streams", &stream, "-show_entries", "stream=codec_name"])
+        .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+        .arg(path)
+        .output()
+        .ok()?;
+    let codec = String::from_utf8_lossy(&out.stdout).trim().to_string();
+    (!codec.is_empty()).then_some(codec)
+}
+
+pub fn count_frames(path: &Path) -> Option<usize> {
+    let out = std::process::Command::new("ffprobe")
+        .args(["-v", "quiet", "-select_streams", "v:0", "-count_packets"])
+        .args([
+            "-show_entries",
+            "stream=nb_read_packets",
+            "-of",
+            "default=noprint_wrappers=1:nokey=1",
+        ])
+        .arg(path)
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&out.stdout).trim().parse().ok()
+}
+
+/// Whether the file has an audio stream. Assumes it does when ffprobe cannot tell.
+pub fn has_audio(path: &Path) -> bool {
+    std::process::Command::new("ffprobe")
+        .args([
+            "-v",
+            "quiet",
+            "-select_streams",
+            "a",
+            "-show_entries",
+            "stream=index",
+            "-of",
+            "csv=p=0",
+        ])
+        .arg(path)
+        .output()
+        .map_or(true, |out| !out.status.success() || !out.stdout.trim_ascii().is_empty())
+}
+
+fn get_chroma_loc(path: &str, frame_chroma: i32, warn: Option<&Sink>) -> Option<i32> {
+    let probed = std::process::Command::new("ffprobe")
         .args([
             "-v",
             "quiet",
@@ -224,36 +376,127 @@ fn get_chroma_loc(path: &str, frame_chroma: i32) -> Option<i32> {
             } else {
                 None
             }
-        })
-        .or_else(|| (frame_chroma != 0).then_some(frame_chroma));
+        });
+
+    if probed.is_none()
+        && let Some(warn) = warn
+    {
+        warn.warn(
+            "Could not probe chroma location with ffprobe, the chroma sample position is guessed. \
+             Use --chroma-location to set it",
+        );
+    }
 
-    match ffmpeg_value? {
+    match probed.or_else(|| (frame_chroma != 0).then_some(frame_chroma))? {
         1 => Some(1),
         3 => Some(2),
         _ => None,
     }
 }
 
-pub fn get_vidinf(idx: &Arc<VidIdx>) -> Result<VidInf, Box<dyn std::error::Error>> {
+pub fn get_keyframes(idx: &Arc<VidIdx>, frames: usize) -> Vec<usize> {
+    unsafe {
+        let track = FFMS_GetTrackFromIndex(idx.idx_handle, idx.track);
+        if track.is_null() {
+            return Vec::new();
+        }
+        let known = usize::try_from(FFMS_GetNumFrames(track)).unwrap_or(0).min(frames);
+        (0..known)
+            .filter(|&i| {
+                let info = FFMS_GetFrameInfo(track, i32::try_from(i).unwrap_or(0));
+                !info.is_null() && (*info).key_frame != 0
+            })
+            .collect()
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub fn get_timestamps(idx: &Arc<VidIdx>, frames: usize) -> Vec<f64> {
+    unsafe {
+        let track = FFMS_GetTrackFromIndex(idx.idx_handle, idx.track);
+        let tb = if track.is_null() { std::ptr::null() } else { FFMS_GetTimeBase(track) };
+        let known = if tb.is_null() || (*tb).den == 0 {
+            0
+        } else {
+            usize::try_from(FFMS_GetNumFrames(track)).unwrap_or(0).min(frames)
+        };
+        let mut ts: Vec<f64> = (0..known)
+            .map_while(|i| {
+                let info = FFMS_GetFrameInfo(track, i32::try_from(i).unwrap_or(0));
+                (!info.is_null()).then(|| (*info).pts as f64 * (*tb).num as f64 / (*tb).den as f64)
+            })
+            .collect();
+
+        let last_dur = match ts.len() {
+            0 => 0.0,
+            1 => 1.0,
+            n => ts[n - 1] - ts[n - 2],
+        };
+        while ts.len() <= frames {
+            ts.push(ts.last().copied().unwrap_or(0.0) + last_dur);
+        }
+        ts
+    }
+}
+
+fn is_vfr(ts: &[f64]) -> bool {
+    if ts.len() < 3 {
+        return false;
+    }
+
+    let mut gaps: Vec<f64> = ts.windows(2).map(|w| w[1] - w[0]).collect();
+    gaps.sort_unstable_by(f64::total_cmp);
+    let median = gaps[gaps.len() / 2];
+    let off = gaps.iter().filter(|&&g| (g - median).abs() > median * 0.1).count();
+    off * 20 > gaps.len()
+}
+
+/// Formats mastering display metadata the way `SvtAv1EncApp`, rav1e and the mkvmerge tags read it:
+/// chromaticities as 0-1 fractions and luminance in cd/m². Values in the HEVC/x265 fixed point
+/// units (0.00002 for chromaticities, 0.0001 cd/m² for luminance) are scaled down first, so the
+/// HDR10 sample `G(13250,34500)B(7500,3000)R(34000,16000)WP(15635,16450)L(10000000,50)` becomes
+/// `G(0.2650,0.6900)B(0.1500,0.0600)R(0.6800,0.3200)WP(0.3127,0.3290)L(1000.0000,0.0050)`.
+///
+/// `xy` is green, blue, red and the white point.
+pub fn fmt_mastering_display(xy: [(f64, f64); 4], max_lum: f64, min_lum: f64) -> String {
+    let coord = |v: f64| if v > 1.0 { v / 50_000.0 } else { v };
+    let (max_lum, min_lum) = if max_lum > 10_000.0 {
+        (max_lum / 10_000.0, min_lum / 10_000.0)
+    } else {
+        (max_lum, min_lum)
+    };
+    let [g, b, r, wp] = xy.map(|(x, y)| (coord(x), coord(y)));
+    format!(
+        "G({:.4},{:.4})B({:.4},{:.4})R({:.4},{:.4})WP({:.4},{:.4})L({max_lum:.4},{min_lum:.4})",
+        g.0, g.1, b.0, b.1, r.0, r.1, wp.0, wp.1
+    )
+}
+
+pub fn get_vidinf(idx: &Arc<VidIdx>, warn: Option<&Sink>) -> Result<VidInf, XavError> {
     unsafe {
         let source = CString::new(idx.path.as_str())?;
-        let mut err = std::mem::zeroed::<FFMS_ErrorInfo>();
+        let mut msg = [0i8; 1024];
+        let mut err = FFMS_ErrorInfo::new(&mut msg);
 
         let video = FFMS_CreateVideoSource(
             source.as_ptr(),
             idx.track,
             idx.idx_handle,
             1,
-            1,
+            idx.seek_mode.unwrap_or(1),
             std::ptr::addr_of_mut!(err),
         );
 
         if video.is_null() {
-            return Err("Failed to create vid src".into());
+            return Err(XavError::Decode(err.msg("Failed to create vid src")));
         }
 
         let props = FFMS_GetVideoProperties(video);
         let frame = FFMS_GetFrame(video, 0, std::ptr::addr_of_mut!(err));
+        if frame.is_null() {
+            FFMS_DestroyVideoSource(video);
+            return Err(XavError::Decode(err.msg("Failed to get frame")));
+        }
 
         let matrix_coeff = if (*frame).matrix_coefficients == 3 {
             (*props).color_space
@@ -272,23 +515,25 @@ pub fn get_vidinf(idx: &Arc<VidIdx>) -> Result<VidInf, Box<dyn std::error::Error
             _ => None,
         };
 
-        let chroma_sample_position = get_chroma_loc(&idx.path, (*frame).chroma_location);
+        let chroma_sample_position = get_chroma_loc(&idx.path, (*frame).chroma_location, warn);
 
         let mastering_display = if (*props).has_mastering_display_primaries != 0
             && (*props).has_mastering_display_luminance != 0
         {
-            Some(format!(
-                "G({:.4},{:.4})B({:.4},{:.4})R({:.4},{:.4})WP({:.4},{:.4})L({:.4},{:.4})",
-                (*props).mastering_display_primaries_x[1],
-                (*props).mastering_display_primaries_y[1],
-                (*props).mastering_display_primaries_x[2],
-                (*props).mastering_display_primaries_y[2],
-                (*props).mastering_display_primaries_x[0],
-                (*props).mastering_display_primaries_y[0],
-                (*props).mastering_display_white_point_x,
-                (*props).mastering_display_white_point_y,
+            let (px, py) =
+                ((*props).mastering_display_primaries_x, (*props).mastering_display_primaries_y);
+            Some(fmt_mastering_display(
+                [
+                    (px[1], py[1]),
+                    (px[2], py[2]),
+                    (px[0], py[0]),
+                    (
+                        (*props).mastering_display_white_point_x,
+                        (*props).mastering_display_white_point_y,
+                    ),
+                ],
                 (*props).mastering_display_max_luminance,
-                (*props).mastering_display_min_luminance
+                (*props).mastering_display_min_luminance,
             ))
         } else {
             None
@@ -304,20 +549,35 @@ pub fn get_vidinf(idx: &Arc<VidIdx>) -> Result<VidInf, Box<dyn std::error::Error
             None
         };
 
+        let frames = (*props).num_frames as usize;
+        let ts = get_timestamps(idx, frames);
+        let vfr = is_vfr(&ts);
+        let (fps_num, fps_den) = if vfr {
+            let avg = f64::from((*props).num_frames) * 1000.0 / (ts[frames] - ts[0]) * 1000.0;
+            (avg.round() as u32, 1000)
+        } else {
+            ((*props).fps_numerator as u32, (*props).fps_denominator as u32)
+        };
+
         let inf = VidInf {
             width,
             height,
-            fps_num: (*props).fps_numerator as u32,
-            fps_den: (*props).fps_denominator as u32,
-            frames: (*props).num_frames as usize,
+            fps_num,
+            fps_den,
+            frames,
             color_primaries: Some((*frame).color_primaries),
             transfer_characteristics: Some((*frame).transfer_characteristics),
             matrix_coefficients: Some(matrix_coeff),
             is_10bit,
+            out_10bit: true,
+            vfr,
+            gray: (*frame).data[1].is_null(),
             color_range,
             chroma_sample_position,
             mastering_display,
             content_light,
+            #[cfg(feature = "vship")]
+            tonemap: None,
         };
 
         FFMS_DestroyVideoSource(video);
@@ -326,25 +586,23 @@ pub fn get_vidinf(idx: &Arc<VidIdx>) -> Result<VidInf, Box<dyn std::error::Error
     }
 }
 
-pub fn thr_vid_src(
-    idx: &Arc<VidIdx>,
-    threads: i32,
-) -> Result<*mut libc::c_void, Box<dyn std::error::Error>> {
+pub fn thr_vid_src(idx: &Arc<VidIdx>, threads: i32) -> Result<*mut libc::c_void, XavError> {
     unsafe {
         let source = CString::new(idx.path.as_str())?;
-        let mut err = std::mem::zeroed::<FFMS_ErrorInfo>();
+        let mut msg = [0i8; 1024];
+        let mut err = FFMS_ErrorInfo::new(&mut msg);
 
         let video = FFMS_CreateVideoSource(
             source.as_ptr(),
             idx.track,
             idx.idx_handle,
             threads,
-            0,
+            idx.seek_mode.unwrap_or(0),
             std::ptr::addr_of_mut!(err),
         );
 
         if video.is_null() {
-            return Err("Failed to create vid src".into());
+            return Err(XavError::Decode(err.msg("Failed to create vid src")));
         }
 
         Ok(video)
@@ -360,13 +618,48 @@ pub const fn calc_packed_size(inf: &VidInf) -> usize {
     (tot_pixels * 5) / 4
 }
 
+/// Why a frame could not be extracted.
+#[derive(Debug)]
+pub enum FrameErr {
+    /// FFMS failed on this frame, the rest of the source may still decode.
+    Get(XavError),
+    /// The frame is not the size of the track, so nothing after it can be encoded.
+    Dims(XavError),
+}
+
+impl From<FrameErr> for XavError {
+    fn from(e: FrameErr) -> Self {
+        match e {
+            FrameErr::Get(e) | FrameErr::Dims(e) => e,
+        }
+    }
+}
+
+unsafe fn check_dims(
+    frame: *const FFMS_Frame,
+    frame_idx: usize,
+    inf: &VidInf,
+) -> Result<(), FrameErr> {
+    let (width, height) = unsafe { ((*frame).encoded_width, (*frame).encoded_height) };
+    if i64::from(width) == i64::from(inf.width) && i64::from(height) == i64::from(inf.height) {
+        return Ok(());
+    }
+    Err(FrameErr::Dims(XavError::Decode(format!(
+        "Resolution change at frame {frame_idx}: {width}x{height} instead of {}x{}. Sources that \
+         change resolution are not supported, cut or scale them first",
+        inf.width, inf.height
+    ))))
+}
+
 pub fn extr_8bit(
     vid_src: *mut libc::c_void,
     frame_idx: usize,
+    inf: &VidInf,
     output: &mut [u8],
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), FrameErr> {
     unsafe {
-        let mut err = std::mem::zeroed::<FFMS_ErrorInfo>();
+        let mut msg = [0i8; 1024];
+        let mut err = FFMS_ErrorInfo::new(&mut msg);
         let frame = FFMS_GetFrame(
             vid_src,
             i32::try_from(frame_idx).unwrap_or(0),
@@ -374,8 +667,9 @@ pub fn extr_8bit(
         );
 
         if frame.is_null() {
-            return Err("Failed to get frame".into());
+            return Err(FrameErr::Get(XavError::Decode(err.msg("Failed to get frame"))));
         }
+        check_dims(frame, frame_idx, inf)?;
 
         let width = (*frame).encoded_width as usize;
         let height = (*frame).encoded_height as usize;
@@ -391,6 +685,11 @@ pub fn extr_8bit(
         let uv_width = width / 2;
         let uv_height = height / 2;
         for plane in 1..=2 {
+            if (*frame).data[plane].is_null() {
+                output[pos..pos + uv_width * uv_height].fill(128);
+                pos += uv_width * uv_height;
+                continue;
+            }
             let linesize = (*frame).linesize[plane] as usize;
             for row in 0..uv_height {
                 let src =
@@ -417,6 +716,25 @@ pub fn conv_to_10bit(input: &[u8], output: &mut [u8]) {
     });
 }
 
+const BAYER_4X4: [[u16; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+pub fn conv_to_8bit(input: &[u8], output: &mut [u8], inf: &VidInf) {
+    let (w, h) = (inf.width as usize, inf.height as usize);
+    let mut pos = 0;
+    for (pw, ph) in [(w, h), (w / 2, h / 2), (w / 2, h / 2)] {
+        let plane = &mut output[pos..pos + pw * ph];
+        for (y, row) in plane.chunks_exact_mut(pw).enumerate() {
+            let src = &input[(pos + y * pw) * 2..(pos + (y + 1) * pw) * 2];
+            let bayer = &BAYER_4X4[y & 3];
+            for (x, (out, px)) in row.iter_mut().zip(src.chunks_exact(2)).enumerate() {
+                let v = u16::from_le_bytes([px[0], px[1]]);
+                *out = ((v * 4 + bayer[x & 3]) >> 4).min(255) as u8;
+            }
+        }
+        pos += pw * ph;
+    }
+}
+
 #[inline]
 pub fn pack_4_pix_10bit(input: [u8; 8], output: &mut [u8; 5]) {
     let p0 = u32::from(u16::from_le_bytes([input[0], input[1]]) & 0x3FF);
@@ -560,10 +878,12 @@ fn copy_plane_10to10(
 pub fn extr_10bit(
     vid_src: *mut libc::c_void,
     frame_idx: usize,
+    inf: &VidInf,
     output: &mut [u8],
-) -> Result<(), Box<dyn std::error::Error>> {
+) -> Result<(), FrameErr> {
     unsafe {
-        let mut err = std::mem::zeroed::<FFMS_ErrorInfo>();
+        let mut msg = [0i8; 1024];
+        let mut err = FFMS_ErrorInfo::new(&mut msg);
         let frame = FFMS_GetFrame(
             vid_src,
             i32::try_from(frame_idx).unwrap_or(0),
@@ -571,14 +891,15 @@ pub fn extr_10bit(
         );
 
         if frame.is_null() {
-            return Err("Failed to get frame".into());
+            return Err(FrameErr::Get(XavError::Decode(err.msg("Failed to get frame"))));
         }
+        check_dims(frame, frame_idx, inf)?;
 
         let width = (*frame).encoded_width as usize;
         let height = (*frame).encoded_height as usize;
 
         if width == 0 || height == 0 {
-            return Err("Invalid frame dimensions".into());
+            return Err(FrameErr::Get(XavError::Decode("Invalid frame dimensions".into())));
         }
 
         let y_linesize = (*frame).linesize[0] as usize;
@@ -587,7 +908,7 @@ pub fn extr_10bit(
 
         let y_ptr = (*frame).data[0];
         if y_ptr.is_null() {
-            return Err("Null Y plane pointer".into());
+            return Err(FrameErr::Get(XavError::Decode("Null Y plane pointer".into())));
         }
 
         if is_10bit {
@@ -599,25 +920,20 @@ pub fn extr_10bit(
         let uv_width = width / 2;
         let uv_height = height / 2;
 
-        let u_ptr = (*frame).data[1];
-        let u_linesize = (*frame).linesize[1] as usize;
-
-        if !u_ptr.is_null() {
-            if is_10bit {
-                copy_plane_10to10(u_ptr, u_linesize, uv_width, uv_height, output, &mut out_pos);
-            } else {
-                copy_plane_8to10(u_ptr, u_linesize, uv_width, uv_height, output, &mut out_pos);
-            }
-        }
-
-        let v_ptr = (*frame).data[2];
-        let v_linesize = (*frame).linesize[2] as usize;
+        for plane in 1..=2 {
+            let ptr = (*frame).data[plane];
+            let linesize = (*frame).linesize[plane] as usize;
 
-        if !v_ptr.is_null() {
-            if is_10bit {
-                copy_plane_10to10(v_ptr, v_linesize, uv_width, uv_height, output, &mut out_pos);
+            if ptr.is_null() {
+                let end = out_pos + uv_width * uv_height * 2;
+                for px in output[out_pos..end].chunks_exact_mut(2) {
+                    px.copy_from_slice(&512u16.to_le_bytes());
+                }
+                out_pos = end;
+            } else if is_10bit {
+                copy_plane_10to10(ptr, linesize, uv_width, uv_height, output, &mut out_pos);
             } else {
-                copy_plane_8to10(v_ptr, v_linesize, uv_width, uv_height, output, &mut out_pos);
+                copy_plane_8to10(ptr, linesize, uv_width, uv_height, output, &mut out_pos);
             }
         }
 
@@ -629,9 +945,10 @@ pub fn extr_10bit(
 pub fn get_frame(
     vid_src: *mut libc::c_void,
     frame_idx: usize,
-) -> Result<*const FFMS_Frame, Box<dyn std::error::Error>> {
+) -> Result<*const FFMS_Frame, XavError> {
     unsafe {
-        let mut err = std::mem::zeroed::<FFMS_ErrorInfo>();
+        let mut msg = [0i8; 1024];
+        let mut err = FFMS_ErrorInfo::new(&mut msg);
         let frame = FFMS_GetFrame(
             vid_src,
             i32::try_from(frame_idx).unwrap_or(0),
@@ -639,7 +956,7 @@ pub fn get_frame(
         );
 
         if frame.is_null() {
-            return Err("Failed to get frame".into());
+            return Err(XavError::Decode(err.msg("Failed to get frame")));
         }
 
         Ok(frame)
@@ -651,3 +968,23 @@ pub fn destroy_vid_src(vid_src: *mut libc::c_void) {
         FFMS_DestroyVideoSource(vid_src);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mastering_display_units() {
+        let hdr10 = [(13250.0, 34500.0), (7500.0, 3000.0), (34000.0, 16000.0), (15635.0, 16450.0)];
+        assert_eq!(
+            fmt_mastering_display(hdr10, 10_000_000.0, 50.0),
+            "G(0.2650,0.6900)B(0.1500,0.0600)R(0.6800,0.3200)WP(0.3127,0.3290)L(1000.0000,0.0050)"
+        );
+
+        let scaled = [(0.265, 0.69), (0.15, 0.06), (0.68, 0.32), (0.3127, 0.329)];
+        assert_eq!(
+            fmt_mastering_display(scaled, 4000.0, 0.005),
+            "G(0.2650,0.6900)B(0.1500,0.0600)R(0.6800,0.3200)WP(0.3127,0.3290)L(4000.0000,0.0050)"
+        );
+    }
+}
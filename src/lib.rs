@@ -0,0 +1,2285 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Write as _;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+mod chunk;
+mod error;
+mod ffms;
+#[cfg(feature = "vship")]
+mod interp;
+mod noise;
+mod progs;
+mod scd;
+mod svt;
+#[cfg(feature = "vship")]
+mod tq;
+#[cfg(feature = "vship")]
+mod vship;
+mod y4m;
+#[cfg(feature = "vship")]
+mod zimg;
+
+pub use chunk::{AudioCodec, SplitMethod};
+pub use error::XavError;
+pub use progs::{FULLSCREEN, Sink, StatTotals, term_width};
+pub use svt::Encoder;
+
+static TERMINATED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Makes a running [`encode`] stop handing out chunks and save resume state.
+///
+/// Only touches atomics, so it can be called from a signal handler. Returns `false` if a stop was
+/// already requested this way.
+pub fn request_stop() -> bool {
+    let first = !TERMINATED.swap(true, std::sync::atomic::Ordering::Relaxed);
+    svt::STOP.store(true, std::sync::atomic::Ordering::Relaxed);
+    first
+}
+
+fn stop_cause() -> &'static str {
+    if TERMINATED.load(std::sync::atomic::Ordering::Relaxed) {
+        "Terminated"
+    } else {
+        "Time limit reached"
+    }
+}
+
+const C: &str = "\x1b[1;96m";
+const W: &str = "\x1b[1;97m";
+const N: &str = "\x1b[0m";
+
+#[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Args {
+    pub worker: usize,
+    pub auto_worker: bool,
+    pub adaptive_workers: bool,
+    pub threads_per_worker: Option<usize>,
+    pub scene_file: PathBuf,
+    pub split_method: SplitMethod,
+    pub chunk_frames: Option<usize>,
+    pub extend_scenes: bool,
+    pub kf_align: bool,
+    #[cfg(feature = "vship")]
+    pub target_quality: Option<String>,
+    #[cfg(feature = "vship")]
+    pub qp_range: Option<String>,
+    #[cfg(feature = "vship")]
+    pub verify: bool,
+    #[cfg(feature = "vship")]
+    pub probe_keep: bool,
+    #[cfg(feature = "vship")]
+    pub tq_percentile: Option<f64>,
+    #[cfg(feature = "vship")]
+    pub crf_curve: Option<usize>,
+    #[cfg(feature = "vship")]
+    pub tonemap: bool,
+    pub params: String,
+    pub raw_params: bool,
+    pub resume: bool,
+    pub keep: bool,
+    pub partial: bool,
+    pub in_order: Option<PathBuf>,
+    pub max_workdir: Option<u64>,
+    pub audio_codec: Option<AudioCodec>,
+    pub audio_bitrate: Option<u32>,
+    pub quiet: bool,
+    pub noise: Option<u32>,
+    pub noise_chroma: Option<f32>,
+    pub grain_strength: Option<f32>,
+    pub no_denoise: bool,
+    pub noise_strict: bool,
+    pub out_10bit: Option<bool>,
+    pub chroma_loc: Option<i32>,
+    pub color_range: Option<i32>,
+    pub fps: Option<(u32, u32)>,
+    pub mastering_display: Option<String>,
+    pub content_light: Option<String>,
+    pub trim: Vec<(usize, usize, bool)>,
+    pub encoder: Encoder,
+    pub preset: Option<String>,
+    pub speed: Option<u8>,
+    pub tune: Option<String>,
+    pub keyint: Option<String>,
+    pub tile_cols: Option<u8>,
+    pub tile_rows: Option<u8>,
+    pub max_bitrate: Option<u32>,
+    pub dry_run: bool,
+    pub deterministic: bool,
+    pub list_chunks: bool,
+    pub concat_only: bool,
+    pub force: bool,
+    pub stdout: bool,
+    pub lossless: bool,
+    pub stats_report: bool,
+    pub fullscreen: bool,
+    pub progress_fifo: Option<PathBuf>,
+    pub on_chunk: Option<String>,
+    pub heartbeat: u64,
+    pub chunk_timeout: Option<Duration>,
+    pub refresh_ms: u64,
+    pub time_limit: Option<Duration>,
+    pub nice: Option<i32>,
+    pub benchmark: Option<usize>,
+    pub preview: Option<usize>,
+    pub frames: Option<usize>,
+    pub no_index_cache: bool,
+    pub temp: PathBuf,
+    pub seek_mode: Option<i32>,
+    pub track: Option<i32>,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// The command line these were parsed from, saved for `-r`.
+    pub argv: Vec<String>,
+    /// Where progress, status lines and warnings are written.
+    pub out: Sink,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            worker: 0,
+            auto_worker: false,
+            adaptive_workers: false,
+            threads_per_worker: None,
+            scene_file: PathBuf::new(),
+            split_method: SplitMethod::default(),
+            chunk_frames: None,
+            extend_scenes: false,
+            kf_align: false,
+            #[cfg(feature = "vship")]
+            target_quality: None,
+            #[cfg(feature = "vship")]
+            qp_range: None,
+            #[cfg(feature = "vship")]
+            verify: false,
+            #[cfg(feature = "vship")]
+            probe_keep: false,
+            #[cfg(feature = "vship")]
+            tq_percentile: None,
+            #[cfg(feature = "vship")]
+            crf_curve: None,
+            #[cfg(feature = "vship")]
+            tonemap: false,
+            params: String::new(),
+            raw_params: false,
+            resume: false,
+            keep: false,
+            partial: false,
+            in_order: None,
+            max_workdir: None,
+            audio_codec: None,
+            audio_bitrate: None,
+            quiet: false,
+            noise: None,
+            noise_chroma: None,
+            grain_strength: None,
+            no_denoise: false,
+            noise_strict: false,
+            out_10bit: None,
+            chroma_loc: None,
+            color_range: None,
+            fps: None,
+            mastering_display: None,
+            content_light: None,
+            trim: Vec::new(),
+            encoder: Encoder::default(),
+            preset: None,
+            speed: None,
+            tune: None,
+            keyint: None,
+            tile_cols: None,
+            tile_rows: None,
+            max_bitrate: None,
+            dry_run: false,
+            deterministic: false,
+            list_chunks: false,
+            concat_only: false,
+            force: false,
+            stdout: false,
+            lossless: false,
+            stats_report: false,
+            fullscreen: false,
+            progress_fifo: None,
+            on_chunk: None,
+            heartbeat: 30,
+            chunk_timeout: None,
+            refresh_ms: 0,
+            time_limit: None,
+            nice: None,
+            benchmark: None,
+            preview: None,
+            frames: None,
+            no_index_cache: false,
+            temp: PathBuf::new(),
+            seek_mode: None,
+            track: None,
+            input: PathBuf::new(),
+            output: PathBuf::new(),
+            argv: Vec::new(),
+            out: Sink::default(),
+        }
+    }
+}
+
+fn get_preset(name: &str) -> Option<(&'static str, Option<u32>)> {
+    match name {
+        "anime" => Some(("--preset 4 --crf 30 --tune 0 --enable-variance-boost 1", None)),
+        "film" => Some(("--preset 4 --crf 27 --tune 0 --enable-variance-boost 1", Some(400))),
+        "screencap" => Some(("--preset 6 --crf 32 --scm 1 --enable-tf 0", None)),
+        _ => None,
+    }
+}
+
+fn apply_preset(args: &mut Args) {
+    let Some((params, noise)) = args.preset.as_deref().and_then(get_preset) else { return };
+
+    let user_flags: Vec<&str> =
+        args.params.split_whitespace().filter(|a| a.starts_with('-')).collect();
+    let preset_params: Vec<&str> = params
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .chunks(2)
+        .filter(|pair| !user_flags.contains(&pair[0]))
+        .filter(|pair| args.speed.is_none() || pair[0] != "--preset")
+        .flatten()
+        .copied()
+        .collect();
+
+    args.params = format!("{} {}", preset_params.join(" "), args.params).trim().to_string();
+    if args.noise.is_none() {
+        args.noise = noise;
+    }
+}
+
+fn apply_defaults(args: &mut Args) {
+    let auto_worker = args.worker == 0;
+    args.auto_worker = auto_worker;
+    apply_preset(args);
+
+    if let Some(speed) = args.speed
+        && args.encoder.get_speed(&args.params).is_none()
+    {
+        args.params =
+            format!("{} {}", args.encoder.speed_param(speed), args.params).trim().to_string();
+    }
+
+    let tune = args
+        .tune
+        .as_deref()
+        .or_else(|| (args.encoder == Encoder::Svt && !args.raw_params).then_some("vq"));
+    if let Some(value) = tune.and_then(|t| args.encoder.tune_value(t))
+        && args.encoder.get_tune(&args.params).is_none()
+    {
+        args.params =
+            format!("{} {}", args.encoder.tune_param(value), args.params).trim().to_string();
+    }
+
+    if let Some(kbps) = args.max_bitrate {
+        let cap = match args.encoder {
+            Encoder::Svt => format!("--mbr {kbps}"),
+            Encoder::Aom => format!("--end-usage=cq --target-bitrate={kbps}"),
+            Encoder::Rav1e => String::new(),
+        };
+        args.params = format!("{} {cap}", args.params).trim().to_string();
+    }
+
+    let threads = std::thread::available_parallelism().map_or(8, std::num::NonZero::get);
+    if auto_worker {
+        args.worker = match threads {
+            32.. => 8,
+            24..32 => 6,
+            16..24 => 4,
+            12..16 => 3,
+            8..12 => 2,
+            _ => 1,
+        };
+    }
+
+    if args.encoder.get_threads(&args.params).is_some() {
+        if args.threads_per_worker.is_some() {
+            args.out
+                .warn(&format!("{} in -p overrides --threads-per-worker", args.encoder.lp_flag()));
+        }
+    } else if !auto_worker {
+        add_lp(args);
+    }
+
+    let stem = args.input.file_stem().map_or_else(|| "output".into(), |s| s.to_string_lossy());
+    if args.output == PathBuf::new() {
+        args.output = args.input.with_file_name(format!("{stem}_av1.mkv"));
+    }
+
+    if args.scene_file == PathBuf::new() {
+        let prefix = if args.split_method == SplitMethod::Keyframe { "kf" } else { "scd" };
+        args.scene_file = PathBuf::from(format!("{prefix}_{stem}.txt"));
+    }
+
+    #[cfg(feature = "vship")]
+    if (args.target_quality.is_some() || args.crf_curve.is_some()) && args.qp_range.is_none() {
+        args.qp_range = Some("10.0-40.0".to_string());
+    }
+}
+
+/// Splits the cores evenly across workers unless `-p` sets the encoder threads. `--worker auto`
+/// calls this after `scale_workers` so cores freed by fewer workers are not left idle.
+fn add_lp(args: &mut Args) {
+    if args.worker == 0 || args.encoder.get_threads(&args.params).is_some() {
+        return;
+    }
+    let threads = std::thread::available_parallelism().map_or(8, std::num::NonZero::get);
+    let lp = args.threads_per_worker.unwrap_or_else(|| (threads / args.worker).max(1));
+    args.params = format!("{} {}", args.encoder.lp_param(lp), args.params).trim().to_string();
+}
+
+/// Parses a full command line (including the program name) into resolved [`Args`].
+///
+/// # Errors
+///
+/// Returns an error for unknown or invalid options and missing required arguments.
+pub fn get_args(args: &[String]) -> Result<Args, XavError> {
+    parse_args(args, true)
+}
+
+fn parse_args(args: &[String], use_env: bool) -> Result<Args, XavError> {
+    if args.len() < 2 {
+        return Err(XavError::Args("Usage: xav [options] <input> <output>".into()));
+    }
+
+    let mut result = Args::default();
+    let mut pending = Pending::default();
+    let mut it = args[1..].iter();
+    while let Some(arg) = it.next() {
+        let flag = arg.as_str();
+        #[cfg(feature = "vship")]
+        if parse_tq_flag(&mut result, flag, &mut it)? {
+            continue;
+        }
+        if parse_chunk_flag(&mut result, &mut pending, flag, &mut it)?
+            || parse_source_flag(&mut result, flag, &mut it)?
+            || parse_encoder_flag(&mut result, &mut pending, flag, &mut it)?
+            || parse_picture_flag(&mut result, flag, &mut it)?
+            || parse_run_flag(&mut result, flag, &mut it)?
+        {
+            continue;
+        }
+        match flag {
+            "-" if result.input == PathBuf::new() => result.input = PathBuf::from("-"),
+            "-" => result.stdout = true,
+            arg if !arg.starts_with('-') => {
+                if result.input == PathBuf::new() {
+                    result.input = PathBuf::from(arg);
+                } else if result.output == PathBuf::new() {
+                    result.output = PathBuf::from(arg);
+                }
+            }
+            _ => return Err(XavError::Args(format!("Unknown argument: {arg}"))),
+        }
+    }
+
+    result.temp = pending
+        .temp
+        .take()
+        .or_else(|| std::env::var_os("TMPDIR").map(PathBuf::from))
+        .unwrap_or_default();
+    if result.temp != PathBuf::new() && !result.temp.is_dir() {
+        return Err(XavError::Args(format!(
+            "Temp directory {} does not exist",
+            result.temp.display()
+        )));
+    }
+
+    if result.resume {
+        return resume_args(result);
+    }
+
+    let (env_argv, env_params) = env_defaults(&mut result, &pending, use_env)?;
+    let cli_params = result.params.clone();
+
+    if let Some(path) = pending.params_file {
+        let content = fs::read_to_string(&path).map_err(|e| {
+            XavError::Args(format!("Failed to read params file {}: {e}", path.display()))
+        })?;
+        let from_file: Vec<&str> = content
+            .lines()
+            .filter(|l| !l.trim_start().starts_with('#'))
+            .flat_map(str::split_whitespace)
+            .collect();
+        result.params = format!("{} {}", from_file.join(" "), result.params).trim().to_string();
+    }
+
+    result.split_method = pending.split_method.unwrap_or_else(|| {
+        if result.chunk_frames.is_some() { SplitMethod::Fixed } else { SplitMethod::Scene }
+    });
+    if result.noise.is_some() && result.grain_strength.is_some() {
+        return Err(XavError::Args("-n and --grain-strength are mutually exclusive".into()));
+    }
+    result.noise = result.noise.or_else(|| result.grain_strength.map(|_| noise::STRENGTH_ISO));
+    result.argv = [args, &env_argv].concat();
+
+    validate(&result)?;
+
+    if result.stdout {
+        result.output = result.temp.join(format!(".{:016x}.stdout.mkv", hash_input(&result.input)));
+    }
+    if result.input == Path::new("-") {
+        result.worker = 1;
+    }
+    if result.deterministic {
+        result.threads_per_worker = Some(1);
+    }
+
+    let env_crf = if use_env { apply_env_crf(&mut result)? } else { None };
+    if let Some(p) =
+        env_crf.map(|crf| format!("{crf} {cli_params}").trim().to_string()).or(env_params)
+    {
+        result.argv.extend(["-p".to_string(), p]);
+    }
+    svt::warn_param_conflicts(&result);
+    apply_defaults(&mut result);
+
+    if result.preview.is_some() {
+        let stem = result.output.file_stem().unwrap_or_default().to_string_lossy();
+        result.output = result.output.with_file_name(format!("{stem}_preview.mkv"));
+    }
+
+    if result.worker == 0
+        || result.scene_file == PathBuf::new()
+        || result.input == PathBuf::new()
+        || result.output == PathBuf::new()
+    {
+        return Err(XavError::Args("Missing required arguments".into()));
+    }
+
+    Ok(result)
+}
+
+/// Fills what the command line left open from `XAV_WORKERS`, `XAV_PARAMS` and `XAV_NOISE`.
+/// Returns the flags that reproduce them and the `XAV_PARAMS` value, for the saved command line.
+fn env_defaults(
+    r: &mut Args,
+    p: &Pending,
+    use_env: bool,
+) -> Result<(Vec<String>, Option<String>), XavError> {
+    let env = |name| if use_env { env_default(name) } else { None };
+    let mut env_argv = Vec::new();
+    r.worker = match p.worker {
+        Some(w) => w,
+        None => match env("XAV_WORKERS") {
+            None => 0,
+            Some(w) => {
+                let n = if w == "auto" {
+                    0
+                } else {
+                    w.parse().map_err(|e| XavError::Args(format!("XAV_WORKERS: {e}")))?
+                };
+                env_argv.extend(["-w".to_string(), w]);
+                n
+            }
+        },
+    };
+    let env_params =
+        if r.params.is_empty() && p.params_file.is_none() { env("XAV_PARAMS") } else { None };
+    if let Some(ref env) = env_params {
+        r.params.clone_from(env);
+    }
+    if r.noise.is_none()
+        && r.grain_strength.is_none()
+        && let Some(iso) = env("XAV_NOISE")
+    {
+        r.noise = Some(parse_noise(&iso).map_err(|e| XavError::Args(format!("XAV_NOISE: {e}")))?);
+        env_argv.extend(["-n".to_string(), iso]);
+    }
+    Ok((env_argv, env_params))
+}
+
+/// Options that only turn into [`Args`] fields once the whole command line is read.
+#[derive(Default)]
+struct Pending {
+    worker: Option<usize>,
+    split_method: Option<SplitMethod>,
+    params_file: Option<PathBuf>,
+    temp: Option<PathBuf>,
+}
+
+type ArgIter<'a> = std::slice::Iter<'a, String>;
+
+/// Loads the saved args for `-r`, refusing a quality target that differs from the saved one.
+fn resume_args(given: Args) -> Result<Args, XavError> {
+    let mut saved_args = get_saved_args(&given.temp, &given.input)?;
+    #[cfg(feature = "vship")]
+    for (flag, given, saved) in [
+        ("-t", &given.target_quality, &saved_args.target_quality),
+        ("-c", &given.qp_range, &saved_args.qp_range),
+    ] {
+        if let Some(given) = given
+            && Some(given) != saved.as_ref()
+        {
+            return Err(XavError::Args(format!(
+                "The saved encode used {flag} {}, resuming with {flag} {given} would mix quality \
+                 targets. Resume without {flag} or start a new encode",
+                saved.as_deref().unwrap_or("none")
+            )));
+        }
+    }
+    saved_args.resume = true;
+    saved_args.temp = given.temp;
+    Ok(saved_args)
+}
+
+#[cfg(feature = "vship")]
+fn parse_tq_flag(r: &mut Args, flag: &str, it: &mut ArgIter) -> Result<bool, XavError> {
+    match flag {
+        "-t" | "--tq" => {
+            if let Some(v) = it.next() {
+                r.target_quality = Some(parse_tq(v)?);
+            }
+        }
+        "-c" | "--qp" => {
+            if let Some(v) = it.next() {
+                r.qp_range = Some(v.clone());
+            }
+        }
+        "--verify" => r.verify = true,
+        "--probe-keep" => r.probe_keep = true,
+        "--tonemap" => r.tonemap = true,
+        "--tq-percentile" => {
+            if let Some(v) = it.next() {
+                let val = v.parse::<f64>()?;
+                if !(val > 0.0 && val <= 100.0) {
+                    return Err(XavError::Args("--tq-percentile must be in (0, 100]".into()));
+                }
+                r.tq_percentile = Some(val);
+            }
+        }
+        "--crf-curve" => {
+            if let Some(v) = it.next() {
+                r.crf_curve = Some(v.parse::<usize>()?.max(1));
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn parse_chunk_flag(
+    r: &mut Args,
+    p: &mut Pending,
+    flag: &str,
+    it: &mut ArgIter,
+) -> Result<bool, XavError> {
+    match flag {
+        "-w" | "--worker" => {
+            if let Some(v) = it.next() {
+                p.worker = Some(if v == "auto" { 0 } else { v.parse()? });
+            }
+        }
+        "--threads-per-worker" => {
+            if let Some(v) = it.next() {
+                r.threads_per_worker = Some(v.parse::<usize>()?.max(1));
+            }
+        }
+        "--adaptive-workers" => r.adaptive_workers = true,
+        "-s" | "--sc" => {
+            if let Some(v) = it.next() {
+                r.scene_file = PathBuf::from(v);
+            }
+        }
+        "--split-method" => {
+            if let Some(v) = it.next() {
+                p.split_method = Some(SplitMethod::parse(v).ok_or_else(|| {
+                    XavError::Args("Split method must be scene, keyframe or fixed".into())
+                })?);
+            }
+        }
+        "--extend-sc" => r.extend_scenes = true,
+        "--kf-align" => r.kf_align = true,
+        "--chunk-frames" => {
+            if let Some(v) = it.next() {
+                r.chunk_frames = Some(v.parse::<usize>()?);
+            }
+        }
+        "-r" | "--resume" => r.resume = true,
+        "-k" | "--keep" | "--log-chunks" => r.keep = true,
+        "--partial" => r.partial = true,
+        "--in-order" => {
+            if let Some(v) = it.next() {
+                r.in_order = Some(PathBuf::from(v));
+            }
+        }
+        "--max-workdir-size" => {
+            if let Some(v) = it.next() {
+                r.max_workdir = Some(parse_size(v)?);
+            }
+        }
+        "--temp" => {
+            if let Some(v) = it.next() {
+                p.temp = Some(PathBuf::from(v));
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn parse_source_flag(r: &mut Args, flag: &str, it: &mut ArgIter) -> Result<bool, XavError> {
+    match flag {
+        "--no-index-cache" => r.no_index_cache = true,
+        "--seek-mode" => {
+            if let Some(v) = it.next() {
+                r.seek_mode = Some(
+                    ffms::parse_seek_mode(v)
+                        .ok_or_else(|| XavError::Args(format!("Unknown seek mode: {v}")))?,
+                );
+            }
+        }
+        "--track" => {
+            if let Some(v) = it.next() {
+                r.track = Some(i32::from(v.parse::<u16>()?));
+            }
+        }
+        "--frames" => {
+            if let Some(v) = it.next() {
+                r.frames = Some(v.parse::<usize>()?);
+            }
+        }
+        "--benchmark" => {
+            if let Some(v) = it.next() {
+                r.benchmark = Some(v.parse::<usize>()?.max(1));
+            }
+        }
+        "--preview" => {
+            if let Some(v) = it.next() {
+                r.preview = Some(v.parse::<usize>()?.max(1));
+            }
+        }
+        "--trim" => {
+            if let Some(v) = it.next() {
+                for seg in v.split(',') {
+                    let (drop, range) = seg.strip_prefix('!').map_or((false, seg), |r| (true, r));
+                    let (s, e) = range
+                        .split_once(':')
+                        .ok_or_else(|| XavError::Args("Trim must be start:end".into()))?;
+                    let (s, e): (usize, usize) = (s.parse()?, e.parse()?);
+                    if s >= e {
+                        return Err(XavError::Args("Trim start must be before its end".into()));
+                    }
+                    r.trim.push((s, e, drop));
+                }
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn parse_encoder_flag(
+    r: &mut Args,
+    p: &mut Pending,
+    flag: &str,
+    it: &mut ArgIter,
+) -> Result<bool, XavError> {
+    match flag {
+        "-p" | "--param" => {
+            if let Some(v) = it.next() {
+                r.params.clone_from(v);
+            }
+        }
+        "--params-file" => {
+            if let Some(v) = it.next() {
+                p.params_file = Some(PathBuf::from(v));
+            }
+        }
+        "--raw-params" => r.raw_params = true,
+        "-e" | "--encoder" => {
+            if let Some(v) = it.next() {
+                r.encoder = Encoder::parse(v)
+                    .ok_or_else(|| XavError::Args("Encoder must be svt, rav1e or aom".into()))?;
+            }
+        }
+        "--preset" => {
+            if let Some(v) = it.next() {
+                if get_preset(v).is_none() {
+                    return Err(XavError::Args("Preset must be anime, film or screencap".into()));
+                }
+                r.preset = Some(v.clone());
+            }
+        }
+        "--speed" => {
+            if let Some(v) = it.next() {
+                let val: u8 = v.parse()?;
+                if val > 13 {
+                    return Err(XavError::Args("Speed must be between 0-13".into()));
+                }
+                r.speed = Some(val);
+            }
+        }
+        "--tune" => {
+            if let Some(v) = it.next() {
+                r.tune = Some(v.clone());
+            }
+        }
+        "--keyint" => {
+            if let Some(v) = it.next() {
+                let valid = v.strip_suffix('s').map_or_else(
+                    || v.parse::<usize>().is_ok(),
+                    |secs| secs.parse::<f64>().is_ok_and(|s| s > 0.0),
+                );
+                if !valid {
+                    return Err(XavError::Args(
+                        "Keyint must be a frame count or seconds like `5s`".into(),
+                    ));
+                }
+                r.keyint = Some(v.clone());
+            }
+        }
+        "--tile-columns" | "--tile-rows" => {
+            if let Some(v) = it.next() {
+                let val: u8 = v.parse()?;
+                if val > 6 {
+                    return Err(XavError::Args("Tile columns/rows must be between 0-6".into()));
+                }
+                if flag == "--tile-rows" {
+                    r.tile_rows = Some(val);
+                } else {
+                    r.tile_cols = Some(val);
+                }
+            }
+        }
+        "--max-bitrate" => {
+            if let Some(v) = it.next() {
+                r.max_bitrate = Some(v.parse()?);
+            }
+        }
+        "--lossless" => r.lossless = true,
+        "--deterministic" => r.deterministic = true,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Grain synthesis and the color and timing metadata written into the output.
+fn parse_picture_flag(r: &mut Args, flag: &str, it: &mut ArgIter) -> Result<bool, XavError> {
+    match flag {
+        "-n" | "--noise" => {
+            if let Some(v) = it.next() {
+                r.noise = Some(parse_noise(v)?);
+            }
+        }
+        "--noise-chroma" => {
+            if let Some(v) = it.next() {
+                let val: f32 = v.parse()?;
+                if !(0.0..=4.0).contains(&val) {
+                    return Err(XavError::Args("Noise chroma strength must be between 0-4".into()));
+                }
+                r.noise_chroma = Some(val);
+            }
+        }
+        "--grain-strength" => {
+            if let Some(v) = it.next() {
+                let val: f32 = v.parse()?;
+                if !(0.0..=1.0).contains(&val) {
+                    return Err(XavError::Args("Grain strength must be between 0-1".into()));
+                }
+                r.grain_strength = Some(val);
+            }
+        }
+        "--no-denoise" => r.no_denoise = true,
+        "--noise-strict" => r.noise_strict = true,
+        "--chroma-location" => {
+            if let Some(v) = it.next() {
+                r.chroma_loc = Some(match v.as_str() {
+                    "left" => 1,
+                    "topleft" => 2,
+                    _ => {
+                        return Err(XavError::Args(
+                            "Chroma location must be left or topleft".into(),
+                        ));
+                    }
+                });
+            }
+        }
+        "--color-range" => {
+            if let Some(v) = it.next() {
+                r.color_range = Some(match v.as_str() {
+                    "limited" | "tv" => 0,
+                    "full" | "pc" => 1,
+                    _ => return Err(XavError::Args("Color range must be limited or full".into())),
+                });
+            }
+        }
+        "--fps" => {
+            if let Some(v) = it.next() {
+                r.fps = Some(parse_fps(v)?);
+            }
+        }
+        "--mastering-display" => {
+            if let Some(v) = it.next() {
+                r.mastering_display = Some(parse_mastering_display(v)?);
+            }
+        }
+        "--content-light" => {
+            if let Some(v) = it.next() {
+                r.content_light = Some(parse_content_light(v)?);
+            }
+        }
+        "--pix-fmt" => {
+            if let Some(v) = it.next() {
+                r.out_10bit = Some(match v.as_str() {
+                    "yuv420p8" => false,
+                    "yuv420p10" => true,
+                    _ => {
+                        return Err(XavError::Args(
+                            "Pixel format must be yuv420p8 or yuv420p10".into(),
+                        ));
+                    }
+                });
+            }
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn parse_run_flag(r: &mut Args, flag: &str, it: &mut ArgIter) -> Result<bool, XavError> {
+    match flag {
+        "-q" | "--quiet" => r.quiet = true,
+        "--audio-codec" => {
+            if let Some(v) = it.next() {
+                r.audio_codec = Some(AudioCodec::parse(v).ok_or_else(|| {
+                    XavError::Args("Audio codec must be copy, opus or aac".into())
+                })?);
+            }
+        }
+        "--audio-bitrate" => {
+            if let Some(v) = it.next() {
+                r.audio_bitrate = Some(v.parse::<u32>()?);
+            }
+        }
+        "--progress-fifo" => {
+            if let Some(v) = it.next() {
+                r.progress_fifo = Some(PathBuf::from(v));
+            }
+        }
+        "--on-chunk" => {
+            if let Some(v) = it.next() {
+                r.on_chunk = Some(v.clone());
+            }
+        }
+        "--nice" => {
+            if let Some(v) = it.next() {
+                let val = v.parse::<i32>()?;
+                if !(-20..=19).contains(&val) {
+                    return Err(XavError::Args("--nice must be in [-20, 19]".into()));
+                }
+                r.nice = Some(val);
+            }
+        }
+        "--time-limit" => {
+            if let Some(v) = it.next() {
+                r.time_limit = Some(parse_duration(v)?);
+            }
+        }
+        "--refresh-ms" => {
+            if let Some(v) = it.next() {
+                r.refresh_ms = v.parse::<u64>()?;
+            }
+        }
+        "--heartbeat" => {
+            if let Some(v) = it.next() {
+                r.heartbeat = v.parse::<u64>()?;
+            }
+        }
+        "--chunk-timeout" => {
+            if let Some(v) = it.next() {
+                let secs = v.parse::<u64>()?;
+                if secs == 0 {
+                    return Err(XavError::Args("--chunk-timeout must be at least 1".into()));
+                }
+                r.chunk_timeout = Some(Duration::from_secs(secs));
+            }
+        }
+        "--fullscreen" => r.fullscreen = true,
+        "--dry-run" => r.dry_run = true,
+        "--list-chunks" => r.list_chunks = true,
+        "--concat-only" => r.concat_only = true,
+        "--force" => r.force = true,
+        "--stdout" => r.stdout = true,
+        "--stats-report" => r.stats_report = true,
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Rejects option combinations that cannot work together.
+fn validate(args: &Args) -> Result<(), XavError> {
+    if args.stats_report {
+        if args.encoder != Encoder::Svt {
+            return Err(XavError::Args("--stats-report is only available for svt".into()));
+        }
+        if args.quiet || args.input == Path::new("-") {
+            return Err(XavError::Args(
+                "--stats-report reads the encoder progress, it does not work with -q or stdin"
+                    .into(),
+            ));
+        }
+        #[cfg(feature = "vship")]
+        if args.target_quality.is_some() {
+            return Err(XavError::Args("--stats-report cannot be used with -t".into()));
+        }
+    }
+
+    if args.preset.is_some() && args.encoder != Encoder::Svt {
+        return Err(XavError::Args("Presets are only available for svt".into()));
+    }
+
+    if let Some(ref fifo) = args.progress_fifo
+        && !fs::metadata(fifo)
+            .is_ok_and(|m| std::os::unix::fs::FileTypeExt::is_fifo(&m.file_type()))
+    {
+        return Err(XavError::Args(format!("{} is not a named pipe", fifo.display())));
+    }
+
+    if (args.on_chunk.is_some() || args.in_order.is_some()) && args.max_workdir.is_some() {
+        return Err(XavError::Args(
+            "--on-chunk and --in-order cannot be used with --max-workdir-size, merged chunks are \
+             deleted"
+                .into(),
+        ));
+    }
+
+    if args.max_bitrate.is_some() && args.encoder == Encoder::Rav1e {
+        return Err(XavError::Args("--max-bitrate is not supported with rav1e".into()));
+    }
+
+    #[cfg(feature = "vship")]
+    if args.tq_percentile.is_some() && args.target_quality.is_none() && args.crf_curve.is_none() {
+        return Err(XavError::Args("--tq-percentile requires -t or --crf-curve".into()));
+    }
+
+    #[cfg(feature = "vship")]
+    if args.crf_curve.is_some()
+        && (args.target_quality.is_some()
+            || args.benchmark.is_some()
+            || args.lossless
+            || args.stdout
+            || args.input == Path::new("-"))
+    {
+        return Err(XavError::Args(
+            "--crf-curve cannot be combined with -t, --benchmark, --lossless, --stdout or stdin"
+                .into(),
+        ));
+    }
+
+    #[cfg(feature = "vship")]
+    if args.adaptive_workers && args.target_quality.is_some() {
+        args.out.warn("--adaptive-workers is ignored with target quality");
+    }
+
+    if args.chunk_frames == Some(0) {
+        return Err(XavError::Args("--chunk-frames must be at least 1".into()));
+    }
+
+    if (args.split_method == SplitMethod::Fixed) != args.chunk_frames.is_some() {
+        return Err(XavError::Args("--split-method fixed and --chunk-frames go together".into()));
+    }
+
+    if args.chunk_frames.is_some() && args.scene_file != PathBuf::new() {
+        return Err(XavError::Args("--chunk-frames and -s are mutually exclusive".into()));
+    }
+
+    if args.audio_bitrate.is_some() && matches!(args.audio_codec, None | Some(AudioCodec::Copy)) {
+        return Err(XavError::Args("--audio-bitrate needs --audio-codec opus or aac".into()));
+    }
+
+    if args.audio_codec.is_some()
+        && (args.preview.is_some() || !args.trim.is_empty() || args.frames.is_some())
+    {
+        return Err(XavError::Args(
+            "--audio-codec cannot be used with --preview, --trim or --frames, the audio would not \
+             match the video"
+                .into(),
+        ));
+    }
+
+    validate_io(args)?;
+    validate_encoding(args)
+}
+
+fn validate_io(args: &Args) -> Result<(), XavError> {
+    if args.stdout {
+        if args.output != PathBuf::new() {
+            return Err(XavError::Args(
+                "--stdout and an output path are mutually exclusive".into(),
+            ));
+        }
+        if args.benchmark.is_some() {
+            return Err(XavError::Args("--benchmark writes no output for --stdout".into()));
+        }
+    }
+
+    if args.input == Path::new("-") {
+        if args.output == PathBuf::new() && !args.stdout {
+            return Err(XavError::Args("Reading from stdin needs an output path".into()));
+        }
+        #[cfg(feature = "vship")]
+        if args.target_quality.is_some() || args.verify || args.tonemap {
+            return Err(XavError::Args(
+                "-t, --verify and --tonemap are not supported with stdin".into(),
+            ));
+        }
+        if args.split_method == SplitMethod::Keyframe || args.kf_align {
+            return Err(XavError::Args(
+                "--split-method keyframe and --kf-align are not supported with stdin".into(),
+            ));
+        }
+        if args.audio_codec.is_some() {
+            return Err(XavError::Args("--audio-codec is not supported with stdin".into()));
+        }
+        if args.benchmark.is_some() || args.preview.is_some() || !args.trim.is_empty() {
+            return Err(XavError::Args(
+                "--benchmark, --preview and --trim are not supported with stdin".into(),
+            ));
+        }
+    }
+
+    if args.concat_only
+        && (args.input == Path::new("-") || args.benchmark.is_some() || args.list_chunks)
+    {
+        return Err(XavError::Args(
+            "--concat-only cannot be used with stdin, --benchmark or --list-chunks".into(),
+        ));
+    }
+
+    if args.preview.is_some() && args.benchmark.is_some() {
+        return Err(XavError::Args("--preview and --benchmark are mutually exclusive".into()));
+    }
+
+    if args.input.is_dir() {
+        return Err(XavError::Args(format!(
+            "{} is a directory, pass a video file as <INPUT>",
+            args.input.display()
+        )));
+    }
+    Ok(())
+}
+
+fn validate_encoding(args: &Args) -> Result<(), XavError> {
+    #[cfg(feature = "vship")]
+    if args.tonemap && (args.target_quality.is_some() || args.verify) {
+        return Err(XavError::Args(
+            "--tonemap cannot be combined with -t or --verify, CVVDP would compare SDR to HDR"
+                .into(),
+        ));
+    }
+
+    if args.lossless {
+        #[cfg(feature = "vship")]
+        if args.target_quality.is_some() {
+            return Err(XavError::Args("--lossless and -t are mutually exclusive".into()));
+        }
+        if let Some(flag) = args.encoder.get_rate_flag(&args.params) {
+            return Err(XavError::Args(format!(
+                "--lossless and {flag} in -p are mutually exclusive"
+            )));
+        }
+        if args.preset.is_some() || args.max_bitrate.is_some() {
+            return Err(XavError::Args(
+                "--lossless cannot be combined with --preset or --max-bitrate".into(),
+            ));
+        }
+    }
+
+    if let Some(ref tune) = args.tune
+        && args.encoder.tune_value(tune).is_none()
+    {
+        return Err(XavError::Args(format!(
+            "--tune {tune} is not available for {}",
+            args.encoder.binary()
+        )));
+    }
+
+    if args.deterministic
+        && (args.encoder.get_threads(&args.params).is_some()
+            || args.threads_per_worker.is_some_and(|t| t != 1))
+    {
+        return Err(XavError::Args(format!(
+            "--deterministic pins {} to 1, drop it from -p and --threads-per-worker",
+            args.encoder.lp_flag()
+        )));
+    }
+
+    if args.noise_chroma.is_some() && args.noise.is_none() {
+        return Err(XavError::Args("--noise-chroma requires --noise or --grain-strength".into()));
+    }
+
+    if args.no_denoise && args.noise.is_none() {
+        return Err(XavError::Args("--no-denoise requires --noise or --grain-strength".into()));
+    }
+
+    if args.noise_strict && args.noise.is_none() {
+        return Err(XavError::Args("--noise-strict requires --noise or --grain-strength".into()));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "vship")]
+fn parse_tq(s: &str) -> Result<String, XavError> {
+    let bad = || XavError::Args(format!("Invalid TQ target {s}, use `9.45-9.55` or `9.5~0.05`"));
+    if let Some((target, tol)) = s.split_once('~') {
+        let (target, tol) = (target.trim().parse::<f64>()?, tol.trim().parse::<f64>()?);
+        if tol < 0.0 {
+            return Err(bad());
+        }
+        return Ok(format!("{}-{}", target - tol, target + tol));
+    }
+
+    let (min, max) = s.split_once('-').ok_or_else(bad)?;
+    let (min, max) = (min.trim().parse::<f64>()?, max.trim().parse::<f64>()?);
+    if min > max {
+        return Err(bad());
+    }
+    Ok(format!("{min}-{max}"))
+}
+
+fn parse_mastering_display(s: &str) -> Result<String, XavError> {
+    let bad = || {
+        XavError::Args(format!(
+            "Invalid mastering display {s}, use `G(x,y)B(x,y)R(x,y)WP(x,y)L(max,min)`"
+        ))
+    };
+    let mut rest = s;
+    let mut pairs = [(0.0, 0.0); 5];
+    for (tag, pair) in ["G", "B", "R", "WP", "L"].into_iter().zip(&mut pairs) {
+        let inner = rest.strip_prefix(tag).and_then(|r| r.strip_prefix('(')).ok_or_else(bad)?;
+        let (nums, tail) = inner.split_once(')').ok_or_else(bad)?;
+        let (a, b) = nums.split_once(',').ok_or_else(bad)?;
+        *pair = (a.parse().map_err(|_| bad())?, b.parse().map_err(|_| bad())?);
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        return Err(bad());
+    }
+    let [g, b, r, wp, (max_lum, min_lum)] = pairs;
+    Ok(ffms::fmt_mastering_display([g, b, r, wp], max_lum, min_lum))
+}
+
+fn parse_content_light(s: &str) -> Result<String, XavError> {
+    let (max_cll, max_fall) = s.split_once(',').ok_or_else(|| {
+        XavError::Args(format!("Invalid content light {s}, use `max_cll,max_fall`"))
+    })?;
+    Ok(format!("{},{}", max_cll.parse::<u16>()?, max_fall.parse::<u16>()?))
+}
+
+/// Reads an `XAV_*` default, which sits between xav's own defaults and the command line.
+fn env_default(name: &str) -> Option<String> {
+    std::env::var(name).ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+/// Adds `XAV_CRF` as the encoder's rate flag unless the command line already picks the rate.
+/// Returns the added flag.
+fn apply_env_crf(args: &mut Args) -> Result<Option<String>, XavError> {
+    #[cfg(feature = "vship")]
+    if args.target_quality.is_some() || args.crf_curve.is_some() {
+        return Ok(None);
+    }
+    if args.lossless || args.preset.is_some() || args.encoder.get_rate_flag(&args.params).is_some()
+    {
+        return Ok(None);
+    }
+    let Some(crf) = env_default("XAV_CRF") else { return Ok(None) };
+    let crf: f32 = crf.parse().map_err(|e| XavError::Args(format!("XAV_CRF: {e}")))?;
+
+    let flag = args.encoder.crf_param(crf);
+    args.params = format!("{flag} {}", args.params).trim().to_string();
+    Ok(Some(flag))
+}
+
+fn parse_noise(s: &str) -> Result<u32, XavError> {
+    let val: f32 = s.parse()?;
+    if !(1.0..=64.0).contains(&val) {
+        return Err(XavError::Args("Noise ISO must be between 1-64".into()));
+    }
+    Ok((val * 100.0).round() as u32)
+}
+
+fn parse_fps(s: &str) -> Result<(u32, u32), XavError> {
+    let bad = || XavError::Args(format!("Invalid frame rate {s}, use `N`, `N.NNN` or `NUM:DEN`"));
+    let (num, den) = if let Some((num, den)) = s.split_once(':') {
+        (num.parse().map_err(|_| bad())?, den.parse().map_err(|_| bad())?)
+    } else if let Ok(num) = s.parse::<u32>() {
+        (num, 1)
+    } else {
+        let fps: f64 = s.parse().map_err(|_| bad())?;
+        ((fps * 1000.0).round() as u32, 1000)
+    };
+    if num == 0 || den == 0 {
+        return Err(XavError::Args("Frame rate must be positive".into()));
+    }
+    Ok((num, den))
+}
+
+fn parse_duration(s: &str) -> Result<Duration, XavError> {
+    let (num, mult) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1),
+        Some((i, 'm')) => (&s[..i], 60),
+        Some((i, 'h')) => (&s[..i], 3600),
+        _ => (s, 1),
+    };
+    let secs = num.parse::<u64>()?.saturating_mul(mult);
+    if secs == 0 {
+        return Err(XavError::Args("--time-limit must be at least 1s".into()));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+fn parse_size(s: &str) -> Result<u64, XavError> {
+    let (num, unit) = s.find(|c: char| !c.is_ascii_digit()).map_or((s, ""), |i| s.split_at(i));
+    let mult: u64 = match unit.to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => {
+            return Err(XavError::Args(format!(
+                "Invalid size {s}, use a number with K, M, G or T"
+            )));
+        }
+    };
+    num.parse::<u64>()?
+        .checked_mul(mult)
+        .ok_or_else(|| XavError::Args(format!("Size {s} is too large")))
+}
+
+/// FNV-1a. Work dir names outlive the binary, so the hash must not change between Rust releases
+/// the way `DefaultHasher` may.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Identifies the input by size and mtime only, so a moved or renamed source keeps its work dir.
+fn hash_input(path: &Path) -> u64 {
+    let mut key = Vec::new();
+    if let Ok(meta) = fs::metadata(path) {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        key.extend(meta.len().to_le_bytes());
+        key.extend(mtime.as_secs().to_le_bytes());
+        key.extend(mtime.subsec_nanos().to_le_bytes());
+    } else {
+        key.extend(path.as_os_str().as_encoded_bytes());
+    }
+    fnv1a(&key)
+}
+
+/// The input a work dir was saved for, the second line of its `cmd.txt`.
+fn saved_input(work_dir: &Path) -> Option<PathBuf> {
+    let content = fs::read_to_string(work_dir.join("cmd.txt")).ok()?;
+    content.lines().nth(1).filter(|l| !l.is_empty()).map(PathBuf::from)
+}
+
+/// `.HASH` in `temp`, or `.HASH-N` when another file that still exists with the same size and
+/// mtime already owns it.
+fn work_dir(temp: &Path, input: &Path) -> PathBuf {
+    let hash = hash_input(input);
+    let input = fs::canonicalize(input).unwrap_or_else(|_| input.to_path_buf());
+    (0..)
+        .map(|n| match n {
+            0 => temp.join(format!(".{hash:016x}")),
+            n => temp.join(format!(".{hash:016x}-{n}")),
+        })
+        .find(|dir| saved_input(dir).is_none_or(|owner| owner == input || !owner.exists()))
+        .unwrap_or_default()
+}
+
+/// The `.xxxxxxx` name older versions used, kept on `DefaultHasher` over the path to match them.
+fn legacy_work_dir(temp: &Path, input: &Path) -> PathBuf {
+    let mut hasher = DefaultHasher::new();
+    input.hash(&mut hasher);
+    let hash = format!("{:x}", hasher.finish());
+    temp.join(format!(".{}", hash.get(..7).unwrap_or(&hash)))
+}
+
+fn index_path(args: &Args) -> PathBuf {
+    if args.temp == PathBuf::new() {
+        ffms::idx_path(&args.input)
+    } else {
+        work_dir(&args.temp, &args.input).with_extension("ffidx")
+    }
+}
+
+fn save_args(work_dir: &Path, args: &Args) -> Result<(), XavError> {
+    let quoted_cmd: Vec<String> = args.argv.iter().map(|arg| quote_arg(arg)).collect();
+    let input = fs::canonicalize(&args.input).unwrap_or_else(|_| args.input.clone());
+    fs::write(work_dir.join("cmd.txt"), format!("{}\n{}", quoted_cmd.join(" "), input.display()))?;
+    Ok(())
+}
+
+fn find_work_dir(temp: &Path) -> Result<PathBuf, XavError> {
+    let dir = if temp == Path::new("") { Path::new(".") } else { temp };
+    let mut found: Vec<(PathBuf, String)> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|e| e.path())
+        .filter(|p| p.file_name().is_some_and(|n| n.to_string_lossy().starts_with('.')))
+        .filter_map(|p| {
+            let content = fs::read_to_string(p.join("cmd.txt")).ok()?;
+            let input = content.lines().nth(1).unwrap_or_default().to_string();
+            Some((p, input))
+        })
+        .collect();
+
+    match found.len() {
+        0 => Err(XavError::Args(format!("No saved encoding found in {}", dir.display()))),
+        1 => Ok(found.remove(0).0),
+        _ => {
+            let list: Vec<String> =
+                found.iter().map(|(p, input)| format!("{}  {input}", p.display())).collect();
+            Err(XavError::Args(format!(
+                "Multiple saved encodings found, pass the input to choose one:\n{}",
+                list.join("\n")
+            )))
+        }
+    }
+}
+
+fn get_saved_args(temp: &Path, input: &Path) -> Result<Args, XavError> {
+    let work_dir = if input == Path::new("") {
+        find_work_dir(temp)?
+    } else if !work_dir(temp, input).exists() && legacy_work_dir(temp, input).exists() {
+        legacy_work_dir(temp, input)
+    } else {
+        work_dir(temp, input)
+    };
+    let cmd_path = work_dir.join("cmd.txt");
+
+    if cmd_path.exists() {
+        let content = fs::read_to_string(&cmd_path)?;
+        let mut lines = content.lines();
+        let cmd_line = lines.next().unwrap_or_default();
+        let saved_args = parse_quoted_args(cmd_line);
+        let mut args = parse_args(&saved_args, false)?;
+        if input != Path::new("") {
+            args.input = input.to_path_buf();
+            let moved = fs::canonicalize(input).unwrap_or_else(|_| input.to_path_buf());
+            if saved_input(&work_dir).as_ref() != Some(&moved) {
+                fs::write(&cmd_path, format!("{cmd_line}\n{}", moved.display()))?;
+            }
+        } else if let Some(orig) = lines.next() {
+            args.input = PathBuf::from(orig);
+        }
+
+        let current = self::work_dir(temp, &args.input);
+        if work_dir == legacy_work_dir(temp, &args.input) && !current.exists() {
+            fs::rename(&work_dir, &current)?;
+        }
+        Ok(args)
+    } else {
+        Err(XavError::Args("No saved encoding found for this input file".into()))
+    }
+}
+
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty()
+        && !arg.contains(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '\\'))
+    {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn parse_quoted_args(cmd_line: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut current_arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = cmd_line.chars();
+
+    while let Some(ch) = chars.next() {
+        match (quote, ch) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), _) => current_arg.push(ch),
+            (_, '\\') => {
+                current_arg.extend(chars.next());
+                in_arg = true;
+            }
+            (None, '"' | '\'') => {
+                quote = Some(ch);
+                in_arg = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut current_arg));
+                    in_arg = false;
+                }
+            }
+            _ => {
+                current_arg.push(ch);
+                in_arg = true;
+            }
+        }
+    }
+
+    if in_arg {
+        args.push(current_arg);
+    }
+
+    args
+}
+
+fn get_total_ram() -> u64 {
+    unsafe {
+        let mut info = std::mem::zeroed::<libc::sysinfo>();
+        if libc::sysinfo(&raw mut info) != 0 {
+            return 0;
+        }
+        info.totalram * u64::from(info.mem_unit)
+    }
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn scale_workers(args: &Args, inf: &ffms::VidInf) -> Args {
+    let mut scaled = args.clone();
+    let total_ram = get_total_ram();
+    if total_ram == 0 {
+        add_lp(&mut scaled);
+        return scaled;
+    }
+
+    let frame_size = ffms::calc_10bit_size(inf) as u64;
+    let chunk_size = args.chunk_frames.unwrap_or_else(|| svt::get_max_chunk_size(inf));
+    let per_worker = frame_size * chunk_size as u64 + frame_size * 64;
+    let budget = total_ram / 4 * 3;
+    let fits = (budget / per_worker.max(1)).max(1) as usize;
+
+    if fits < args.worker {
+        scaled.worker = fits;
+    }
+
+    if !args.quiet {
+        args.out.put(&format!(
+            "{C}Workers: {W}{}{C} (~{:.2} GB per worker for {}x{}, {:.2} GB of {:.2} GB RAM \
+             budgeted, {} cores allow {}){N}\n",
+            scaled.worker,
+            per_worker as f64 / 1e9,
+            inf.width,
+            inf.height,
+            budget as f64 / 1e9,
+            total_ram as f64 / 1e9,
+            std::thread::available_parallelism().map_or(8, std::num::NonZero::get),
+            args.worker
+        ));
+    }
+
+    add_lp(&mut scaled);
+    scaled
+}
+
+fn ensure_scene_file(
+    args: &Args,
+    idx: &std::sync::Arc<ffms::VidIdx>,
+    inf: &ffms::VidInf,
+) -> Result<(), XavError> {
+    if args.scene_file.exists() {
+        let covered = fs::read_to_string(&args.scene_file)
+            .ok()
+            .and_then(|content| chunk::covered_frames(&content, inf))
+            .filter(|&f| f > 0 && f < inf.frames);
+        if let Some(start) = covered
+            && args.extend_scenes
+            && args.split_method == SplitMethod::Scene
+        {
+            if !args.quiet {
+                args.out.put(&format!(
+                    "{C}Extending {W}{}{C} from frame {W}{start}{N}\n",
+                    args.scene_file.display()
+                ));
+            }
+            return scd::fd_scenes(idx, inf, &args.scene_file, start, progs_out(args))
+                .map_err(|e| XavError::Scenes(e.to_string()));
+        }
+        return Ok(());
+    }
+    match args.split_method {
+        SplitMethod::Scene => scd::fd_scenes(idx, inf, &args.scene_file, 0, progs_out(args)),
+        SplitMethod::Keyframe => scd::kf_scenes(idx, inf, &args.scene_file),
+        SplitMethod::Fixed => Ok(()),
+    }
+    .map_err(|e| XavError::Scenes(e.to_string()))
+}
+
+/// Stats of a finished encode, or of the sampled chunks with `--benchmark`.
+pub struct Summary {
+    pub width: u32,
+    pub height: u32,
+    pub fps_num: u32,
+    pub fps_den: u32,
+    pub chunks: usize,
+    pub frames: usize,
+    pub tot_frames: usize,
+    pub duration: f64,
+    pub enc_time: Duration,
+    pub input_size: u64,
+    pub output_size: u64,
+    pub cvvdp: Option<(f64, usize)>,
+    pub stat_report: Option<progs::StatTotals>,
+}
+
+/// Writes the grain table for `-n`/`--grain-strength`. A failure only drops the grain unless
+/// `--noise-strict` is set.
+fn grain_table(
+    args: &Args,
+    inf: &ffms::VidInf,
+    work_dir: &Path,
+) -> Result<Option<PathBuf>, XavError> {
+    let Some(iso) = args.noise else { return Ok(None) };
+    let table_path = work_dir.join("grain.tbl");
+    match noise::gen_table(iso, args.grain_strength, args.noise_chroma, inf, &table_path) {
+        Ok(()) => Ok(Some(table_path)),
+        Err(e) if args.noise_strict => Err(e),
+        Err(e) => {
+            args.out.warn(&format!("Grain table generation failed, encoding without grain: {e}"));
+            Ok(None)
+        }
+    }
+}
+
+fn run_benchmark(
+    args: &Args,
+    inf: &ffms::VidInf,
+    idx: &std::sync::Arc<ffms::VidIdx>,
+    chunks: &[chunk::Chunk],
+    n: usize,
+    work_dir: &Path,
+    grain_table: Option<&PathBuf>,
+) -> Result<Summary, XavError> {
+    let sample = sample_chunks(chunks, n);
+    let n = sample.len();
+
+    let enc_start = std::time::Instant::now();
+    svt::encode_all(&sample, inf, args, idx, work_dir, grain_table)?;
+    let enc_time = enc_start.elapsed();
+
+    if svt::STOP.load(std::sync::atomic::Ordering::Relaxed) {
+        return Err(XavError::Stopped(format!("{} before the benchmark finished", stop_cause())));
+    }
+
+    let frames: usize = sample.iter().map(|c| c.end - c.start).sum();
+    fs::remove_dir_all(work_dir)?;
+
+    Ok(Summary {
+        width: inf.width,
+        height: inf.height,
+        fps_num: inf.fps_num,
+        fps_den: inf.fps_den,
+        chunks: n,
+        frames,
+        tot_frames: chunks.iter().map(|c| c.end - c.start).sum(),
+        duration: frames_secs(frames, inf),
+        enc_time,
+        input_size: fs::metadata(&args.input)?.len(),
+        output_size: 0,
+        cvvdp: None,
+        stat_report: None,
+    })
+}
+
+fn sample_chunks(chunks: &[chunk::Chunk], n: usize) -> Vec<chunk::Chunk> {
+    let n = n.min(chunks.len());
+    (0..n)
+        .map(|i| &chunks[i * chunks.len() / n])
+        .enumerate()
+        .map(|(i, c)| chunk::Chunk { idx: i, start: c.start, end: c.end, params: c.params.clone() })
+        .collect()
+}
+
+/// Summary for the report-only modes that stop before encoding the source.
+fn report_summary(
+    args: &Args,
+    inf: &ffms::VidInf,
+    chunks: &[chunk::Chunk],
+) -> Result<Summary, XavError> {
+    Ok(Summary {
+        width: inf.width,
+        height: inf.height,
+        fps_num: inf.fps_num,
+        fps_den: inf.fps_den,
+        chunks: chunks.len(),
+        frames: 0,
+        tot_frames: chunks.iter().map(|c| c.end - c.start).sum(),
+        duration: 0.0,
+        enc_time: Duration::ZERO,
+        input_size: fs::metadata(&args.input)?.len(),
+        output_size: 0,
+        cvvdp: None,
+        stat_report: None,
+    })
+}
+
+#[cfg(feature = "vship")]
+fn curve_crfs(qp_range: &str) -> Vec<f64> {
+    let (min, max) = qp_range
+        .split_once('-')
+        .and_then(|(a, b)| Some((a.parse::<f64>().ok()?, b.parse::<f64>().ok()?)))
+        .unwrap_or((10.0, 40.0));
+    (0..5).map(|i| (f64::from(i).mul_add((max - min) / 4.0, min) * 4.0).round() / 4.0).collect()
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn frames_secs(frames: usize, inf: &ffms::VidInf) -> f64 {
+    frames as f64 * f64::from(inf.fps_den) / f64::from(inf.fps_num)
+}
+
+#[cfg(feature = "vship")]
+#[allow(clippy::cast_precision_loss)]
+fn print_curve(
+    out: &Sink,
+    inf: &ffms::VidInf,
+    chunks: &[chunk::Chunk],
+    results: &[(usize, Vec<tq::CurvePoint>)],
+) {
+    let sampled: usize = results.iter().map(|r| r.0).sum();
+    let Some((_, first)) = results.first() else { return };
+    let tot_frames: usize = chunks.iter().map(|c| c.end - c.start).sum();
+    let secs = frames_secs(sampled, inf);
+
+    let mut table = String::new();
+    writeln!(table, "{C}Sampled {W}{}{C} chunks, {W}{sampled}{C} frames{N}", results.len())
+        .unwrap();
+    writeln!(table, "{:>6} {:>8} {:>12} {:>9}", "CRF", "CVVDP", "Est. size", "kb/s").unwrap();
+    for (i, point) in first.iter().enumerate() {
+        let score = results.iter().map(|(f, p)| *f as f64 * p[i].score).sum::<f64>()
+            / sampled.max(1) as f64;
+        let bytes: u64 = results.iter().map(|(_, p)| p[i].size).sum();
+        let est = bytes as f64 * tot_frames as f64 / sampled.max(1) as f64;
+        writeln!(
+            table,
+            "{:>6.2} {score:>8.4} {:>9.1} MB {:>9.0}",
+            point.crf,
+            est / 1_000_000.0,
+            bytes as f64 * 8.0 / secs.max(0.001) / 1000.0
+        )
+        .unwrap();
+    }
+    out.put(&table);
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn list_chunks(out: &Sink, chunks: &[chunk::Chunk]) {
+    let mut table = String::new();
+    writeln!(table, "{:>6} {:>8} {:>8} {:>7}", "Chunk", "Start", "End", "Frames").unwrap();
+    for c in chunks {
+        writeln!(table, "{:>6} {:>8} {:>8} {:>7}", c.idx, c.start, c.end, c.end - c.start).unwrap();
+    }
+
+    let lens = chunks.iter().map(|c| c.end - c.start);
+    if let (Some(min), Some(max)) = (lens.clone().min(), lens.clone().max()) {
+        writeln!(
+            table,
+            "{C}{W}{}{C} chunks, {W}{min}{C}-{W}{max}{C} frames, {W}{:.1}{C} on average{N}",
+            chunks.len(),
+            lens.sum::<usize>() as f64 / chunks.len() as f64
+        )
+        .unwrap();
+    }
+    out.put(&table);
+}
+
+/// Where indexing, scene detection and merge progress go, `None` with `--quiet`.
+fn progs_out(args: &Args) -> Option<&Sink> {
+    (!args.quiet).then_some(&args.out)
+}
+
+fn override_inf(args: &Args, inf: &mut ffms::VidInf) {
+    if let Some(out_10bit) = args.out_10bit {
+        inf.out_10bit = out_10bit;
+    }
+    if args.chroma_loc.is_some() {
+        inf.chroma_sample_position = args.chroma_loc;
+    }
+    if args.color_range.is_some() {
+        inf.color_range = args.color_range;
+    }
+    if let Some((num, den)) = args.fps {
+        (inf.fps_num, inf.fps_den, inf.vfr) = (num, den, false);
+    }
+    if args.mastering_display.is_some() {
+        inf.mastering_display.clone_from(&args.mastering_display);
+    }
+    if args.content_light.is_some() {
+        inf.content_light.clone_from(&args.content_light);
+    }
+}
+
+/// Rejects a forced 8-bit encode of a 10-bit source that cannot survive the dither.
+fn check_pix_fmt(args: &Args, inf: &ffms::VidInf) -> Result<(), XavError> {
+    if args.out_10bit != Some(false) || !inf.is_10bit {
+        return Ok(());
+    }
+    if args.lossless {
+        return Err(XavError::Args(
+            "--pix-fmt yuv420p8 dithers the 10-bit source, which --lossless cannot keep".into(),
+        ));
+    }
+    if matches!(inf.transfer_characteristics, Some(16 | 18)) {
+        return Err(XavError::Args(
+            "HDR sources need 10-bit output, drop --pix-fmt yuv420p8 or add --tonemap".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "vship")]
+fn tonemap_inf(inf: &mut ffms::VidInf) -> Result<(), XavError> {
+    let transfer = inf
+        .transfer_characteristics
+        .filter(|t| inf.is_10bit && matches!(t, 16 | 18))
+        .ok_or_else(|| XavError::Args("--tonemap needs a 10-bit PQ or HLG source".into()))?;
+
+    let max_cll = inf.content_light.as_deref().and_then(|cl| cl.split(',').next()?.parse().ok());
+    let max_lum = inf
+        .mastering_display
+        .as_deref()
+        .and_then(|md| md.split_once("L(")?.1.split(',').next()?.parse().ok());
+    let peak = max_cll.filter(|&n: &f64| n > 0.0).or(max_lum).unwrap_or(1000.0);
+
+    let tm = ffms::Tonemap {
+        matrix: inf.matrix_coefficients.unwrap_or(9),
+        transfer,
+        primaries: inf.color_primaries.unwrap_or(9),
+        full_range: inf.color_range == Some(1),
+        peak,
+    };
+    zimg::Tonemapper::new(inf.width, inf.height, &tm)
+        .map_err(|e| XavError::Decode(e.to_string()))?;
+
+    inf.tonemap = Some(tm);
+    inf.color_primaries = Some(1);
+    inf.transfer_characteristics = Some(1);
+    inf.matrix_coefficients = Some(1);
+    inf.color_range = Some(0);
+    inf.mastering_display = None;
+    inf.content_light = None;
+    Ok(())
+}
+
+fn check_output_frames(args: &Args, expected: usize) -> Result<(), XavError> {
+    let output = &args.output;
+    match ffms::count_frames(output) {
+        Some(n) if n != expected => Err(XavError::Merge(format!(
+            "{} has {n} frames but {expected} were encoded. The work directory was kept",
+            output.display()
+        ))),
+        Some(_) => Ok(()),
+        None => {
+            args.out.warn(&format!("Could not count the frames of {}", output.display()));
+            Ok(())
+        }
+    }
+}
+
+fn provenance(args: &Args) -> String {
+    let days = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() / 86400);
+    let (y, m, d) = civil_date(days);
+
+    let mut tag = format!("xav {} {}", env!("CARGO_PKG_VERSION"), args.encoder.binary());
+    if !args.params.is_empty() {
+        tag = format!("{tag} {}", args.params);
+    }
+    #[cfg(feature = "vship")]
+    if let (Some(tq), Some(qp)) = (&args.target_quality, &args.qp_range) {
+        tag = format!("{tag}; tq {tq} qp {qp}");
+    }
+    if args.deterministic {
+        return tag;
+    }
+    format!("{tag}; {y:04}-{m:02}-{d:02}")
+}
+
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + u64::from(m <= 2), m, d)
+}
+
+fn encode_stream(args: &Args) -> Result<Summary, XavError> {
+    let mut reader = y4m::Y4mReader::new(std::io::stdin().lock())?;
+    override_inf(args, &mut reader.inf);
+    check_pix_fmt(args, &reader.inf)?;
+
+    let work_dir = work_dir(&args.temp, &args.input);
+    if work_dir.exists() {
+        fs::remove_dir_all(&work_dir)?;
+    }
+    fs::create_dir_all(work_dir.join("split"))?;
+    fs::create_dir_all(work_dir.join("encode"))?;
+    if args.keep {
+        fs::create_dir_all(work_dir.join("logs"))?;
+    }
+
+    let day = (reader.inf.fps_num / reader.inf.fps_den.max(1)) as usize * 86_400;
+    let grain_table =
+        grain_table(args, &ffms::VidInf { frames: day, ..reader.inf.clone() }, &work_dir)?;
+
+    let cuts = if args.chunk_frames.is_none() && args.scene_file.exists() {
+        chunk::read_cuts(&fs::read_to_string(&args.scene_file)?)
+    } else {
+        Vec::new()
+    };
+    let max_len = args.chunk_frames.unwrap_or_else(|| svt::get_max_chunk_size(&reader.inf));
+
+    let enc_start = std::time::Instant::now();
+    let chunks =
+        svt::encode_stream(&mut reader, &cuts, max_len, args, &work_dir, grain_table.as_ref())?;
+    let enc_time = enc_start.elapsed();
+
+    let (inf, input_size) = (reader.inf.clone(), reader.bytes);
+    drop(reader);
+
+    if svt::STOP.load(std::sync::atomic::Ordering::Relaxed) {
+        return Err(XavError::Stopped(format!("{} before the end of stdin", stop_cause())));
+    }
+
+    chunk::merge_out(
+        &work_dir.join("encode"),
+        &args.output,
+        &inf,
+        &chunks,
+        None,
+        &chunk::MuxOpts {
+            provenance: provenance(args),
+            audio: None,
+            deterministic: args.deterministic,
+            progs: progs_out(args),
+        },
+    )?;
+    check_output_frames(args, inf.frames)?;
+
+    let summary = Summary {
+        width: inf.width,
+        height: inf.height,
+        fps_num: inf.fps_num,
+        fps_den: inf.fps_den,
+        chunks: chunks.len(),
+        frames: inf.frames,
+        tot_frames: inf.frames,
+        duration: frames_secs(inf.frames, &inf),
+        enc_time,
+        input_size,
+        output_size: fs::metadata(&args.output)?.len(),
+        cvvdp: None,
+        stat_report: None,
+    };
+
+    if !args.keep {
+        fs::remove_dir_all(&work_dir)?;
+    }
+
+    Ok(summary)
+}
+
+/// Runs the whole pipeline: indexing, scene detection, chunked encoding and muxing.
+///
+/// With `args.benchmark` set only the sampled chunks are encoded and no output is written.
+/// Progress and status lines go to `args.out`. The caller owns the process, so priorities
+/// (`args.nice`) and `--stdout` handling are left to it.
+///
+/// # Errors
+///
+/// Returns an error if the source cannot be indexed, a chunk fails to produce output or
+/// muxing fails.
+pub fn encode(args: &Args) -> Result<Summary, XavError> {
+    TERMINATED.store(false, std::sync::atomic::Ordering::Relaxed);
+    svt::STOP.store(false, std::sync::atomic::Ordering::Relaxed);
+    let _cancel_limit = args.time_limit.map(|limit| {
+        let (cancel, cancelled) = crossbeam_channel::bounded::<()>(0);
+        std::thread::spawn(move || {
+            if cancelled.recv_timeout(limit) == Err(crossbeam_channel::RecvTimeoutError::Timeout) {
+                svt::STOP.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        });
+        cancel
+    });
+    run(args)
+}
+
+/// Fails before any work starts if a tool or the GPU the run needs is missing.
+fn check_tools(args: &Args) -> Result<(), XavError> {
+    let transcode = matches!(args.audio_codec, Some(AudioCodec::Opus | AudioCodec::Aac));
+    let encoder = (!args.concat_only).then(|| args.encoder.binary());
+    for tool in encoder.into_iter().chain(["mkvmerge"]).chain(transcode.then_some("ffmpeg")) {
+        let found = std::process::Command::new(tool)
+            .arg("--version")
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .is_ok();
+        if !found {
+            return Err(XavError::Missing(format!("{tool} not found in PATH")));
+        }
+    }
+
+    #[cfg(feature = "vship")]
+    if args.target_quality.is_some() || args.verify || args.crf_curve.is_some() {
+        vship::check_device().map_err(|e| {
+            XavError::Missing(format!(
+                "{e}. Target quality and --verify need a GPU supported by VSHIP, drop -t and \
+                 --verify to encode at a fixed CRF"
+            ))
+        })?;
+    }
+    Ok(())
+}
+
+/// Indexes the input and reads its properties, with the overrides from `args` applied.
+fn open_source(args: &Args) -> Result<(std::sync::Arc<ffms::VidIdx>, ffms::VidInf), XavError> {
+    let idx = ffms::VidIdx::new(
+        &args.input,
+        progs_out(args),
+        &ffms::IdxOpts {
+            cache: (!args.no_index_cache).then(|| index_path(args)),
+            seek_mode: args.seek_mode,
+            track: args.track,
+        },
+    )?;
+    let mut inf = ffms::get_vidinf(&idx, progs_out(args).filter(|_| args.chroma_loc.is_none()))?;
+    override_inf(args, &mut inf);
+    #[cfg(feature = "vship")]
+    if args.tonemap {
+        tonemap_inf(&mut inf)?;
+    }
+    check_pix_fmt(args, &inf)?;
+    if let Some(frames) = args.frames {
+        inf.frames = frames;
+    }
+    if inf.vfr {
+        args.out.warn(&format!(
+            "Variable frame rate source. Encoding at an average of {:.3} fps and muxing with the \
+             source timestamps",
+            f64::from(inf.fps_num) / f64::from(inf.fps_den)
+        ));
+    }
+
+    if inf.gray {
+        #[cfg(feature = "vship")]
+        if args.verify {
+            return Err(XavError::Args("--verify is not supported for grayscale sources".into()));
+        }
+        if !args.quiet {
+            if args.encoder == Encoder::Aom {
+                args.out.put(&format!("{C}Grayscale source, encoding as monochrome{N}\n"));
+            } else {
+                args.out.put(&format!("{C}Grayscale source, encoding with neutral chroma{N}\n"));
+            }
+        }
+    }
+    Ok((idx, inf))
+}
+
+fn run(args: &Args) -> Result<Summary, XavError> {
+    check_tools(args)?;
+
+    if args.input == Path::new("-") {
+        return encode_stream(args);
+    }
+
+    if !args.force && ffms::get_codec(&args.input, args.track).as_deref() == Some("av1") {
+        return Err(XavError::Args(format!(
+            "{} is already AV1, re-encoding it loses quality. Use --force to encode it anyway",
+            args.input.display()
+        )));
+    }
+
+    let (idx, inf) = open_source(args)?;
+
+    if args.concat_only {
+        let mut work_dir = work_dir(&args.temp, &args.input);
+        if args.preview.is_some() {
+            work_dir.set_extension("preview");
+        }
+        let chunks = plan_chunks(args, &idx, &inf)?;
+        check_done(&work_dir, &chunks)?;
+        let start = std::time::Instant::now();
+        return finish(args, &idx, &inf, &chunks, &work_dir, start);
+    }
+
+    let scaled;
+    let args = if args.auto_worker {
+        scaled = scale_workers(args, &inf);
+        &scaled
+    } else {
+        args
+    };
+
+    ensure_scene_file(args, &idx, &inf)?;
+
+    if !args.quiet {
+        args.out.put("\n");
+    }
+
+    let chunks = plan_chunks(args, &idx, &inf)?;
+
+    if args.list_chunks {
+        list_chunks(&args.out, &chunks);
+        return report_summary(args, &inf, &chunks);
+    }
+
+    let mut work_dir = work_dir(&args.temp, &args.input);
+    if args.benchmark.is_some() {
+        work_dir.set_extension("bench");
+    } else if args.preview.is_some() {
+        work_dir.set_extension("preview");
+    }
+    #[cfg(feature = "vship")]
+    if args.crf_curve.is_some() {
+        work_dir.set_extension("curve");
+    }
+
+    if !args.resume && work_dir.exists() {
+        fs::remove_dir_all(&work_dir)?;
+    }
+
+    fs::create_dir_all(work_dir.join("split"))?;
+    fs::create_dir_all(work_dir.join("encode"))?;
+    if args.keep {
+        fs::create_dir_all(work_dir.join("logs"))?;
+    }
+
+    if !args.resume {
+        save_args(&work_dir, args)?;
+    }
+
+    let grain_table = grain_table(args, &inf, &work_dir)?;
+
+    if let Some(n) = args.benchmark {
+        return run_benchmark(args, &inf, &idx, &chunks, n, &work_dir, grain_table.as_ref());
+    }
+
+    #[cfg(feature = "vship")]
+    if let Some(n) = args.crf_curve {
+        let sample = sample_chunks(&chunks, n);
+        let crfs = curve_crfs(args.qp_range.as_deref().unwrap_or_default());
+        let results =
+            svt::crf_curve(&sample, &inf, args, &idx, &work_dir, grain_table.as_ref(), &crfs)?;
+        print_curve(&args.out, &inf, &chunks, &results);
+        fs::remove_dir_all(&work_dir)?;
+        return report_summary(args, &inf, &chunks);
+    }
+
+    let enc_start = std::time::Instant::now();
+    svt::encode_all(&chunks, &inf, args, &idx, &work_dir, grain_table.as_ref())?;
+
+    if svt::STOP.load(std::sync::atomic::Ordering::Relaxed) {
+        return Err(XavError::Stopped(format!(
+            "{}. Finished chunks are saved, resume with -r to encode the rest",
+            stop_cause()
+        )));
+    }
+
+    finish(args, &idx, &inf, &chunks, &work_dir, enc_start)
+}
+
+/// Turns the scene file or `--chunk-frames` into the chunk list, as aligned, trimmed and sampled
+/// by the other options.
+fn plan_chunks(
+    args: &Args,
+    idx: &std::sync::Arc<ffms::VidIdx>,
+    inf: &ffms::VidInf,
+) -> Result<Vec<chunk::Chunk>, XavError> {
+    let mut scenes = if let Some(n) = args.chunk_frames {
+        chunk::fixed_scenes(inf.frames, n)
+    } else {
+        chunk::load_scenes(&args.scene_file, inf, &args.out)?
+    };
+    if args.kf_align && args.split_method != SplitMethod::Keyframe {
+        let (min_dist, _) = scd::cut_dists(inf);
+        let (aligned, missed) =
+            chunk::align_scenes(&scenes, &ffms::get_keyframes(idx, inf.frames), min_dist);
+        if missed > 0 {
+            args.out.warn(&format!(
+                "{missed} chunk starts have no source keyframe within {min_dist} frames and stay \
+                 where they are"
+            ));
+        }
+        scenes = aligned;
+    }
+    if !args.trim.is_empty() {
+        scenes = chunk::trim_scenes(&scenes, &args.trim, inf.frames);
+    }
+
+    let mut chunks = chunk::chunkify(&scenes);
+
+    if let Some(n) = args.preview {
+        let tot = chunks.len();
+        chunks = chunks
+            .into_iter()
+            .step_by(n)
+            .enumerate()
+            .map(|(i, c)| chunk::Chunk { idx: i, ..c })
+            .collect();
+        if !args.quiet {
+            args.out.put(&format!(
+                "{C}Preview: {W}{}{C} of {W}{tot}{C} chunks, every {W}{n}{C}th{N}\n",
+                chunks.len()
+            ));
+        }
+    }
+    Ok(chunks)
+}
+
+/// Checks that `done.txt` of a kept work directory lists every chunk.
+fn check_done(work_dir: &Path, chunks: &[chunk::Chunk]) -> Result<(), XavError> {
+    let done: std::collections::HashSet<usize> = chunk::get_resume(work_dir)
+        .map(|r| r.chnks_done.iter().map(|c| c.idx).collect())
+        .unwrap_or_default();
+    if done.is_empty() {
+        return Err(XavError::Missing(format!(
+            "No finished chunks in {}, --concat-only needs a work directory kept with -k",
+            work_dir.display()
+        )));
+    }
+    if let Some(gap) = chunks.iter().find(|c| !done.contains(&c.idx)) {
+        return Err(XavError::Args(format!(
+            "Chunk {} is not finished in {}, resume with -r to encode it",
+            gap.idx,
+            work_dir.display()
+        )));
+    }
+    Ok(())
+}
+
+fn finish(
+    args: &Args,
+    idx: &std::sync::Arc<ffms::VidIdx>,
+    inf: &ffms::VidInf,
+    chunks: &[chunk::Chunk],
+    work_dir: &Path,
+    enc_start: std::time::Instant,
+) -> Result<Summary, XavError> {
+    let enc_time = enc_start.elapsed();
+    let frames: usize = chunks.iter().map(|c| c.end - c.start).sum();
+    let encoded: std::collections::HashMap<usize, usize> = chunk::get_resume(work_dir)
+        .map(|r| r.chnks_done.iter().map(|c| (c.idx, c.frames)).collect())
+        .unwrap_or_default();
+    let dropped = chunks.iter().any(|c| encoded.get(&c.idx).is_some_and(|&n| n != c.end - c.start));
+    let (timestamps, duration) = if inf.vfr || dropped {
+        let ts_path = work_dir.join("timestamps.txt");
+        let ts = ffms::get_timestamps(idx, inf.frames);
+        let lost = chunk::get_dropped(work_dir);
+        let duration = chunk::write_timestamps(chunks, &ts, &encoded, &lost, &ts_path)?;
+        (Some(ts_path), duration)
+    } else {
+        (None, frames_secs(frames, inf))
+    };
+
+    chunk::merge_out(
+        &work_dir.join("encode"),
+        &args.output,
+        inf,
+        chunks,
+        timestamps.as_deref(),
+        &chunk::MuxOpts {
+            provenance: provenance(args),
+            audio: args.audio_codec.map(|codec| chunk::Audio {
+                source: &args.input,
+                codec,
+                bitrate: args.audio_bitrate,
+            }),
+            deterministic: args.deterministic,
+            progs: progs_out(args),
+        },
+    )?;
+    check_output_frames(
+        args,
+        chunks.iter().map(|c| encoded.get(&c.idx).copied().unwrap_or(c.end - c.start)).sum(),
+    )?;
+    if args.partial {
+        let _ = fs::remove_file(chunk::partial_path(&args.output));
+    }
+
+    #[cfg(feature = "vship")]
+    let cvvdp = if args.verify { tq::verify(idx, inf, chunks, &args.output)? } else { None };
+    #[cfg(not(feature = "vship"))]
+    let cvvdp = None;
+
+    let summary = Summary {
+        width: inf.width,
+        height: inf.height,
+        fps_num: inf.fps_num,
+        fps_den: inf.fps_den,
+        chunks: chunks.len(),
+        frames,
+        tot_frames: frames,
+        duration,
+        enc_time,
+        input_size: fs::metadata(&args.input)?.len(),
+        output_size: fs::metadata(&args.output)?.len(),
+        cvvdp,
+        stat_report: args.stats_report.then(|| progs::stat_totals(work_dir, chunks)).flatten(),
+    };
+
+    if !args.keep && !args.concat_only {
+        fs::remove_dir_all(work_dir)?;
+    }
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRICKY: [&str; 8] =
+        ["plain", "", " ", "two words", "say \"hi\"", "it's", "C:\\dir\\", "\t\"'\\ mix"];
+
+    #[test]
+    fn quoted_args_round_trip() {
+        let quoted: Vec<String> = TRICKY.iter().map(|arg| quote_arg(arg)).collect();
+        assert_eq!(parse_quoted_args(&quoted.join(" ")), TRICKY);
+    }
+
+    #[test]
+    fn work_dir_hash_is_fixed() {
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn saved_args_parse_the_same() {
+        let argv: Vec<String> = [
+            "xav",
+            "my \"clip\" 'v2'.mkv",
+            "out dir\\out.mkv",
+            "-p",
+            "--film-grain 8 --fgs-table \"a b\\c\"",
+            "-q",
+        ]
+        .map(String::from)
+        .to_vec();
+        let parsed = parse_args(&argv, false).unwrap();
+        let quoted: Vec<String> = parsed.argv.iter().map(|arg| quote_arg(arg)).collect();
+        let saved = parse_args(&parse_quoted_args(&quoted.join(" ")), false).unwrap();
+
+        assert_eq!(saved.argv, parsed.argv);
+        assert_eq!(saved.input, parsed.input);
+        assert_eq!(saved.output, parsed.output);
+        assert_eq!(saved.params, parsed.params);
+    }
+}
@@ -1,23 +1,6 @@
-use std::collections::hash_map::DefaultHasher;
-use std::fs;
-use std::hash::{Hash, Hasher};
 use std::io::Write;
-use std::path::{Path, PathBuf};
-
-mod chunk;
-mod ffms;
-#[cfg(feature = "vship")]
-mod interp;
-mod noise;
-mod progs;
-mod scd;
-mod svt;
-#[cfg(feature = "vship")]
-mod tq;
-#[cfg(feature = "vship")]
-mod vship;
-#[cfg(feature = "vship")]
-mod zimg;
+
+use xav::{Args, Summary, XavError};
 
 const G: &str = "\x1b[1;92m";
 const R: &str = "\x1b[1;91m";
@@ -28,57 +11,120 @@ const C: &str = "\x1b[1;96m";
 const W: &str = "\x1b[1;97m";
 const N: &str = "\x1b[0m";
 
-#[derive(Clone)]
-pub struct Args {
-    pub worker: usize,
-    pub scene_file: PathBuf,
-    #[cfg(feature = "vship")]
-    pub target_quality: Option<String>,
-    #[cfg(feature = "vship")]
-    pub qp_range: Option<String>,
-    pub params: String,
-    pub resume: bool,
-    pub quiet: bool,
-    pub noise: Option<u32>,
-    pub input: PathBuf,
-    pub output: PathBuf,
-}
-
 extern "C" fn restore() {
-    print!("\x1b[?25h\x1b[?1049l");
-    let _ = std::io::stdout().flush();
+    if xav::FULLSCREEN.load(std::sync::atomic::Ordering::Relaxed) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = std::io::stdout().flush();
+    }
 }
 extern "C" fn exit_restore(_: i32) {
     restore();
     std::process::exit(130);
 }
+extern "C" fn term_stop(sig: i32) {
+    if !xav::request_stop() {
+        exit_restore(sig);
+    }
+}
 
 #[rustfmt::skip]
 fn print_help() {
     println!("Format: xav [options] <INPUT> [<OUTPUT>]");
     println!();
-    println!("<INPUT>        Input path");
+    println!("<INPUT>        Input path. `-` reads a 4:2:0 Y4M stream from stdin and encodes it with one worker");
     println!("<OUTPUT>       Output path. Adds `_av1` to the input name if not specified");
     println!();
     println!("Options:");
-    println!("-p|--param     SVT AV1 parameters inside quotes");
-    println!("-w|--worker    Number of `svt-av1` instances to run");
+    println!("--preset       Content preset: `anime`, `film` or `screencap`. Explicit flags override it");
+    println!("--tune         `vq`, `psnr` or `ssim` (aom has no vq, rav1e no ssim). Defaults to vq on SVT");
+    println!("--speed        Encoder speed [0-13]. Maps to `--preset` on SVT. The encoder's own default if not specified");
+    println!("--keyint       Max keyframe interval inside chunks in frames or seconds (`5s`). Chunks still start on scene cuts");
+    println!("--tile-columns Log2 of tile columns [0-6]. Picked by resolution if not specified");
+    println!("--tile-rows    Log2 of tile rows [0-6]. Picked by resolution if not specified");
+    println!("-e|--encoder   Encoder backend: `svt`, `rav1e` or `aom`. Defaults to `svt`");
+    println!("-p|--param     Encoder parameters inside quotes. These win over xav's own flags except input/output ones");
+    println!("--raw-params   Drop xav's optional encoder flags (keyint, scd, tiles, color, rc...) and the default tune, leaving only -p.");
+    println!("               Always set: input/output, size, fps, depth and progress flags, the rate flag with -t or --lossless, the grain table");
+    println!("--params-file  Read encoder parameters from a file, `#` lines are comments. -p is appended after it and wins");
+    println!("-w|--worker    Number of encoder instances to run or `auto`. Auto scales by cores and RAM");
+    println!("--threads-per-worker  Encoder threads per worker (`--lp` on SVT). Defaults to cores divided by workers");
+    println!("--adaptive-workers  Experimental. Start with half of the workers and add or park them by measured FPS");
+    println!("--max-bitrate  Cap each chunk's bitrate in kbps. Switches rate control to capped CRF (`--mbr` on SVT, CQ on aomenc)");
+    println!("--trim         Frame ranges to keep, `!` prefix drops. Example: `0:1000,!200:300,5000:9000`");
+    println!("--fps          Override a wrong source frame rate: `24`, `23.976` or `24000:1001`. Source timestamps are ignored");
+    println!("--color-range  Override a mistagged source range: `limited` (tv, 16-235) or `full` (pc, 0-255). Tags the output the same way");
+    println!("--chroma-location  Override the chroma sample position: `left` or `topleft`");
+    println!("--mastering-display  Override the HDR mastering display: `G(x,y)B(x,y)R(x,y)WP(x,y)L(max,min)` in cd/m², x265 fixed point units also work");
+    println!("--content-light  Override the HDR content light level: `max_cll,max_fall`");
+    println!("--pix-fmt      Encoder input format: `yuv420p8` (ordered dither from 10-bit) or `yuv420p10`. Defaults to 10-bit encoder input, also for 8-bit sources. 8-bit is refused for 10-bit HDR and --lossless");
+    println!("--temp         Base directory for the work dir and index cache. Falls back to TMPDIR, then the current directory. Pass it again with -r");
+    println!("--no-index-cache  Index the source in memory without reading or writing `<INPUT>.ffidx` (or the --temp copy)");
+    println!("--track        FFMS track number of the video stream to encode. Defaults to the first video track");
+    println!("--seek-mode    FFMS seek mode: `linear-norewind`, `linear`, `normal`, `unsafe` or `aggressive`. Try `linear` for sources that decode wrong frames");
+    println!("--frames       Override the source frame count when the container reports a wrong one");
     println!();
     #[cfg(feature = "vship")]
     {
         println!("TQ:");
-        println!("-t|--tq        Allowed CVVDP Range for Target Quality. Example: `9.45-9.55`");
+        println!("-t|--tq        Allowed CVVDP Range for Target Quality. Example: `9.45-9.55` or `9.5~0.05`");
         println!("-c|--qp        Allowed CRF/QP search range for Target Quality. Example: `12.25-44.75`");
+        println!("--verify       Measure CVVDP of the final output, averaged over short runs of frames spread across it");
+        println!("--probe-keep   Keep every probe as `<OUTPUT>.probes/CHUNK_CRF_SCORE.ivf` with a CRF,score CSV per chunk");
+        println!("--tonemap      Tone-map a PQ/HLG source to SDR BT.709. Cannot be used with -t or --verify");
+        println!("--crf-curve    Probe N sampled chunks at 5 CRFs across -c and print CVVDP and estimated size, then exit");
+        println!("--tq-percentile  Target the Nth percentile of per-frame CVVDP instead of the chunk score. Each frame is scored on its own with no temporal pooling, so pick -t on that scale, not the chunk one. Example: `5`");
         println!();
     }
     println!("Misc:");
-    println!("-n|--noise     Apply photon noise [1-64]: 1=ISO100, 64=ISO6400");
-    println!("-s|--sc        SCD file to use. Runs SCD and creates the file if not specified");
+    println!("-n|--noise     Apply photon noise [1-64]: 1=ISO100, 64=ISO6400. Fractions like `3.5` are allowed");
+    println!("--grain-strength  Raw grain strength [0.0-1.0] as a fraction of the full AV1 scaling range, instead of -n");
+    println!("--noise-strict Abort when the grain table cannot be made. By default xav warns and encodes without grain");
+    println!("--no-denoise   Apply the grain table without letting the encoder denoise first");
+    println!("--noise-chroma Chroma grain strength relative to luma [0-4]. Follows luma if not specified");
+    println!("-s|--sc        SCD file to use. Runs SCD and creates the file if not specified. Params after a frame apply to that scene");
+    println!("--extend-sc    Run SCD only on the frames past the end of an existing -s file and append the new cuts");
+    println!("--kf-align     Move chunk starts to the nearest source keyframe within a second so each chunk decodes from a clean frame");
+    println!("--split-method `scene` (SCD, default), `keyframe` (source keyframes) or `fixed` (needs --chunk-frames)");
+    println!("--chunk-frames Split into fixed N-frame chunks instead of running SCD. Cannot be used with -s");
     println!("-r|--resume    Resume the encoding. Example below");
     println!("-q|--quiet     Do not run any code related to any progress");
+    println!("--fullscreen   Draw the progress in the alternate screen instead of inline");
+    println!("--nice         Run xav, its threads and the encoders at this niceness [-20-19], with the matching IO priority");
+    println!("--time-limit   Stop handing out chunks after this long, let running ones finish and exit for -r. Example: `90s`, `30m`, `2h`");
+    println!("--refresh-ms   Redraw the progress at most every N ms. 0 (default) redraws on every encoder line");
+    println!("--chunk-timeout  Kill an encoder that takes longer than this many seconds on one chunk and move on. -r encodes it later");
+    println!("--heartbeat    Seconds without encoder output before a chunk shows `waiting on chunk`. 0 disables, default 30");
+    println!("--on-chunk     Shell command run after each chunk with index, frames, size and path as $1-$4 and XAV_CHUNK/FRAMES/SIZE/OUTPUT");
+    println!("--progress-fifo  Write the percentage and `# N FPS` lines to an existing named pipe (zenity/dialog)");
+    println!("-k|--keep      Keep the work directory after a successful encode, with each chunk's encoder log in `logs/`");
+    println!("--log-chunks   Save each chunk's full encoder output to `logs/NNNN.log` in the work directory. Implies -k");
+    println!("--audio-codec  Mux the source audio as `copy`, `opus` or `aac` (needs ffmpeg). Video only if not specified");
+    println!("--audio-bitrate  Audio bitrate in kb/s for --audio-codec opus or aac. Example: `128`");
+    println!("--in-order     Also write the finished chunks as one IVF stream in source order to this path, which may be a named pipe");
+    println!("--partial      Keep a playable `<OUTPUT>.partial.ivf` of the finished prefix while encoding");
+    println!("--max-workdir-size  Merge the finished prefix and delete its chunks once `encode/` grows past this. Example: `20G`");
+    println!("--preview      Encode every Nth chunk into a short `<OUTPUT>_preview.mkv` montage");
+    println!("--benchmark    Encode N sampled chunks and report the speed without creating an output");
+    println!("--force        Encode sources that are already AV1");
+    println!("--stats-report Have svt report PSNR/SSIM per chunk and show the frame-weighted totals in the summary");
+    println!("--lossless     Mathematically lossless encode. Cannot be used with -t, --crf, --preset or --max-bitrate");
+    println!("--stdout       Write the muxed output to stdout, same as `-` for <OUTPUT>. Progress goes to stderr");
+    println!("--deterministic  Byte-identical output across runs: one encoder thread per chunk, mkvmerge --deterministic, no date in the tag");
+    println!("--dry-run      Print the resolved parameters and exit");
+    println!("--list-chunks  Print each chunk's start frame, end frame and length, then exit without encoding");
+    println!("--concat-only  Merge and mux the finished chunks of a work directory kept with -k without encoding. Example: `xav --concat-only --audio-codec opus i.mkv o.mkv`");
+    println!();
+    println!("Exit codes: 2 invalid arguments, 3 missing encoder or mkvmerge, 4 index/decode failure, 5 encode failure, 6 merge failure, 7 stopped by --time-limit or SIGTERM");
+    println!("SIGTERM stops like --time-limit and can be resumed with -r, a second SIGTERM exits at once");
+    println!();
+    println!("Environment: XAV_PARAMS, XAV_WORKERS, XAV_CRF and XAV_NOISE set defaults for -p, -w, the CRF and -n.");
+    println!("               Command line flags win over them and they win over xav's own defaults. XAV_PARAMS is skipped with --params-file,");
+    println!("               XAV_CRF when -p, --preset, -t or --lossless already pick the rate, XAV_NOISE with --grain-strength.");
+    println!("               The values used are saved with the encode, so -r resumes with them and ignores the environment");
     println!();
     println!("Examples:");
     println!("xav -r i.mkv");
+    println!("xav -r  # Resumes the only saved encoding in the current directory");
     println!("xav -w 8 -s sc.txt -p \"--lp 3 --tune 0\" i.mkv o.mkv");
     println!(
         "xav -q -w 8 -s sc.txt -t 9.4-9.6 -c 1-63 -p \"--lp 3 --tune 0\" i.mkv o.mkv"
@@ -88,272 +134,76 @@ fn print_help() {
 
 fn parse_args() -> Args {
     let args: Vec<String> = std::env::args().collect();
-    get_args(&args).unwrap_or_else(|_| {
+    xav::get_args(&args).unwrap_or_else(|e| {
         print_help();
-        std::process::exit(1);
+        eprintln!("\n{e}");
+        std::process::exit(e.exit_code());
     })
 }
 
-fn apply_defaults(args: &mut Args) {
-    if args.worker == 0 {
-        let threads = std::thread::available_parallelism().map_or(8, std::num::NonZero::get);
-        args.worker = match threads {
-            32.. => 8,
-            24..32 => 6,
-            16..24 => 4,
-            12..16 => 3,
-            8..12 => 2,
-            _ => 1,
-        };
-        args.params = format!("--lp 3 {}", args.params).trim().to_string();
-    }
-
-    if args.output == PathBuf::new() {
-        let stem = args.input.file_stem().unwrap().to_string_lossy();
-        args.output = args.input.with_file_name(format!("{stem}_av1.mkv"));
-    }
-
-    if args.scene_file == PathBuf::new() {
-        let stem = args.input.file_stem().unwrap().to_string_lossy();
-        args.scene_file = PathBuf::from(format!("scd_{stem}.txt"));
-    }
-
-    #[cfg(feature = "vship")]
-    if args.target_quality.is_some() && args.qp_range.is_none() {
-        args.qp_range = Some("10.0-40.0".to_string());
-    }
-}
-
-fn get_args(args: &[String]) -> Result<Args, Box<dyn std::error::Error>> {
-    if args.len() < 2 {
-        return Err("Usage: xav [options] <input> <output>".into());
-    }
-
-    let mut worker = 0;
-    let mut scene_file = PathBuf::new();
-    #[cfg(feature = "vship")]
-    let mut target_quality = None;
-    #[cfg(feature = "vship")]
-    let mut qp_range = None;
-    let mut params = String::new();
-    let mut resume = false;
-    let mut quiet = false;
-    let mut noise = None;
-    let mut input = PathBuf::new();
-    let mut output = PathBuf::new();
-
-    let mut i = 1;
-    while i < args.len() {
-        match args[i].as_str() {
-            "-w" | "--worker" => {
-                i += 1;
-                if i < args.len() {
-                    worker = args[i].parse()?;
-                }
-            }
-            "-s" | "--sc" => {
-                i += 1;
-                if i < args.len() {
-                    scene_file = PathBuf::from(&args[i]);
-                }
-            }
-            #[cfg(feature = "vship")]
-            "-t" | "--tq" => {
-                i += 1;
-                if i < args.len() {
-                    target_quality = Some(args[i].clone());
-                }
-            }
-            #[cfg(feature = "vship")]
-            "-c" | "--qp" => {
-                i += 1;
-                if i < args.len() {
-                    qp_range = Some(args[i].clone());
-                }
-            }
-            "-p" | "--param" => {
-                i += 1;
-                if i < args.len() {
-                    params.clone_from(&args[i]);
-                }
-            }
-            "-r" | "--resume" => {
-                resume = true;
-            }
-            "-q" | "--quiet" => {
-                quiet = true;
-            }
-            "-n" | "--noise" => {
-                i += 1;
-                if i < args.len() {
-                    let val: u32 = args[i].parse()?;
-                    if !(1..=64).contains(&val) {
-                        return Err("Noise ISO must be between 1-64".into());
-                    }
-                    noise = Some(val * 100);
-                }
-            }
-            arg if !arg.starts_with('-') => {
-                if input == PathBuf::new() {
-                    input = PathBuf::from(arg);
-                } else if output == PathBuf::new() {
-                    output = PathBuf::from(arg);
-                }
-            }
-            _ => return Err(format!("Unknown argument: {}", args[i]).into()),
-        }
-        i += 1;
-    }
-
-    if resume {
-        let mut saved_args = get_saved_args(&input)?;
-        saved_args.resume = true;
-        return Ok(saved_args);
-    }
-
-    let mut result = Args {
-        worker,
-        scene_file,
-        #[cfg(feature = "vship")]
-        target_quality,
-        #[cfg(feature = "vship")]
-        qp_range,
-        params,
-        resume,
-        quiet,
-        noise,
-        input,
-        output,
-    };
-
-    apply_defaults(&mut result);
-
-    if result.worker == 0
-        || result.scene_file == PathBuf::new()
-        || result.input == PathBuf::new()
-        || result.output == PathBuf::new()
-    {
-        return Err("Missing required arguments".into());
-    }
-
-    Ok(result)
-}
-
-fn hash_input(path: &Path) -> String {
-    let mut hasher = DefaultHasher::new();
-    path.hash(&mut hasher);
-    format!("{:x}", hasher.finish())
-}
+const SUMMARY_WIDTH: usize = 79;
 
-fn save_args(work_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    let cmd: Vec<String> = std::env::args().collect();
-    let quoted_cmd: Vec<String> = cmd
-        .iter()
-        .map(|arg| if arg.contains(' ') { format!("\"{arg}\"") } else { arg.clone() })
-        .collect();
-    fs::write(work_dir.join("cmd.txt"), quoted_cmd.join(" "))?;
-    Ok(())
+fn box_row(label: &str, content: &str) -> String {
+    format!(
+        "{P}┣━━━━━━━━━━━╋━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┫\n{P}┃ \
+         {Y}{label:<9} {P}┃ {W}{content:<63} {P}┃\n"
+    )
 }
 
-fn get_saved_args(input: &Path) -> Result<Args, Box<dyn std::error::Error>> {
-    let hash = hash_input(input);
-    let work_dir = PathBuf::from(format!(".{}", &hash[..7]));
-    let cmd_path = work_dir.join("cmd.txt");
-
-    if cmd_path.exists() {
-        let cmd_line = fs::read_to_string(cmd_path)?;
-        let saved_args = parse_quoted_args(&cmd_line);
-        get_args(&saved_args)
-    } else {
-        Err("No saved encoding found for this input file".into())
-    }
-}
+#[allow(clippy::cast_precision_loss)]
+fn print_benchmark(args: &Args, s: &Summary) {
+    let fps = s.frames as f64 / s.enc_time.as_secs_f64().max(0.001);
+    let proj = (s.tot_frames as f64 / fps.max(0.001)) as u64;
 
-fn parse_quoted_args(cmd_line: &str) -> Vec<String> {
-    let mut args = Vec::new();
-    let mut current_arg = String::new();
-    let mut in_quotes = false;
-
-    for ch in cmd_line.chars() {
-        match ch {
-            '"' => in_quotes = !in_quotes,
-            ' ' if !in_quotes => {
-                if !current_arg.is_empty() {
-                    args.push(current_arg.clone());
-                    current_arg.clear();
-                }
-            }
-            _ => current_arg.push(ch),
-        }
-    }
-
-    if !current_arg.is_empty() {
-        args.push(current_arg);
-    }
-
-    args
+    println!(
+        "{P}Benchmark: {W}{}{P} chunks, {W}{}{P} frames, {W}{}{P} workers{N}",
+        s.chunks, s.frames, args.worker
+    );
+    println!(
+        "{Y}Speed:     {W}{fps:.2} fps {C}({:.2} fps per worker){N}",
+        fps / args.worker as f64
+    );
+    println!(
+        "{Y}Projected: {W}{:02}{C}:{W}{:02}{C}:{W}{:02} {C}for {} frames{N}",
+        proj / 3600,
+        (proj % 3600) / 60,
+        proj % 60,
+        s.tot_frames
+    );
 }
 
-fn ensure_scene_file(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
-    if !args.scene_file.exists() {
-        scd::fd_scenes(&args.input, &args.scene_file, args.quiet)?;
+fn elide(name: &str, max: usize) -> String {
+    if name.chars().count() <= max {
+        return name.to_string();
     }
-    Ok(())
+    let head: String = name.chars().take(max.saturating_sub(1)).collect();
+    format!("{head}…")
 }
 
-fn main_with_args(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
-    if !args.quiet {
-        print!("\x1b[?1049h\x1b[H\x1b[?25l");
-        std::io::stdout().flush().unwrap();
-    }
-
-    ensure_scene_file(args)?;
-
-    if !args.quiet {
-        println!();
-    }
-
-    let hash = hash_input(&args.input);
-    let work_dir = PathBuf::from(format!(".{}", &hash[..7]));
-
-    if !args.resume && work_dir.exists() {
-        fs::remove_dir_all(&work_dir)?;
-    }
-
-    fs::create_dir_all(work_dir.join("split"))?;
-    fs::create_dir_all(work_dir.join("encode"))?;
-
-    if !args.resume {
-        save_args(&work_dir)?;
-    }
-
-    let idx = ffms::VidIdx::new(&args.input, args.quiet)?;
-    let inf = ffms::get_vidinf(&idx)?;
-
-    let grain_table = if let Some(iso) = args.noise {
-        let table_path = work_dir.join("grain.tbl");
-        noise::gen_table(iso, &inf, &table_path)?;
-        Some(table_path)
-    } else {
-        None
-    };
-
-    let scenes = chunk::load_scenes(&args.scene_file, inf.frames)?;
-
-    let chunks = chunk::chunkify(&scenes);
-
-    let enc_start = std::time::Instant::now();
-    svt::encode_all(&chunks, &inf, args, &idx, &work_dir, grain_table.as_ref());
-    let enc_time = enc_start.elapsed();
-
-    chunk::merge_out(&work_dir.join("encode"), &args.output, &inf)?;
-
-    print!("\x1b[?25h\x1b[?1049l");
-    std::io::stdout().flush().unwrap();
-
-    let input_size = fs::metadata(&args.input)?.len();
-    let output_size = fs::metadata(&args.output)?.len();
-    let duration = inf.frames as f64 * f64::from(inf.fps_den) / f64::from(inf.fps_num);
+fn print_summary(args: &Args, s: &Summary) {
+    let extra: Vec<(&str, String)> = [
+        args.lossless.then(|| ("Mode", "Lossless".to_string())),
+        args.encoder
+            .get_speed(&args.params)
+            .map(|speed| ("Speed", format!("{} {speed}", args.encoder.speed_flag()))),
+        args.encoder.get_tune(&args.params).map(|tune| ("Tune", format!("--tune {tune}"))),
+        s.cvvdp.map(|(score, sampled)| ("CVVDP", format!("{score:.4} ({sampled} frames sampled)"))),
+        s.stat_report.as_ref().map(|r| {
+            let (worst, worst_psnr) = r.worst;
+            (
+                "Stats",
+                format!(
+                    "PSNR {:.2} dB, SSIM {:.4}, lowest chunk {worst} {worst_psnr:.2} dB",
+                    r.psnr, r.ssim
+                ),
+            )
+        }),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+
+    let (input_size, output_size, duration) = (s.input_size, s.output_size, s.duration);
     let input_br = (input_size as f64 * 8.0) / duration / 1000.0;
     let output_br = (output_size as f64 * 8.0) / duration / 1000.0;
     let change = ((output_size as f64 / input_size as f64) - 1.0) * 100.0;
@@ -369,15 +219,50 @@ fn main_with_args(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
     let arrow = if change < 0.0 { "󰛀" } else { "󰛃" };
     let change_color = if change < 0.0 { G } else { R };
 
-    let fps_rate = f64::from(inf.fps_num) / f64::from(inf.fps_den);
-    let enc_speed = inf.frames as f64 / enc_time.as_secs_f64();
+    let fps_rate = f64::from(s.fps_num) / f64::from(s.fps_den);
+    let enc_speed = s.frames as f64 / s.enc_time.as_secs_f64();
 
-    let enc_secs = enc_time.as_secs();
+    let enc_secs = s.enc_time.as_secs();
     let (eh, em, es) = (enc_secs / 3600, (enc_secs % 3600) / 60, enc_secs % 60);
 
     let dur_secs = duration as u64;
     let (dh, dm, ds) = (dur_secs / 3600, (dur_secs % 3600) / 60, dur_secs % 60);
 
+    let input_name = args.input.file_name().unwrap_or_default().to_string_lossy();
+    let output_name = args.output.file_name().unwrap_or_default().to_string_lossy();
+    let size_str = format!(
+        "{} {C}({:.0} kb/s) {G}󰛂 {G}{} {C}({:.0} kb/s) {}{} {:.2}%",
+        fmt_size(input_size),
+        input_br,
+        fmt_size(output_size),
+        output_br,
+        change_color,
+        arrow,
+        change.abs()
+    );
+
+    if let Some(width) = xav::term_width().filter(|&w| w < SUMMARY_WIDTH) {
+        let name_max = width.saturating_sub(8) / 2;
+        eprintln!(
+            "\n{G}✅ {Y}DONE {R}{} {G}󰛂 {}{N}",
+            elide(&input_name, name_max),
+            elide(&output_name, name_max)
+        );
+        eprintln!("{Y}Size  {R}{size_str}{N}");
+        eprintln!(
+            "{Y}Video {W}{}x{} {B}{fps_rate:.3} fps {W}{dh:02}{C}:{W}{dm:02}{C}:{W}{ds:02}{N}",
+            s.width, s.height
+        );
+        eprintln!("{Y}Time  {W}{eh:02}{C}:{W}{em:02}{C}:{W}{es:02} {B}@ {enc_speed:.2} fps{N}");
+        for (label, content) in &extra {
+            eprintln!("{Y}{label:<6}{W}{}{N}", elide(content, width.saturating_sub(6)));
+        }
+        print_hint(args, change);
+        return;
+    }
+
+    let rows: String = extra.iter().map(|(label, content)| box_row(label, content)).collect();
+
     eprintln!(
     "\n{P}┏━━━━━━━━━━━┳━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┓\n\
 {P}┃ {G}✅ {Y}DONE   {P}┃ {R}{:<30.30} {G}󰛂 {G}{:<30.30} {P}┃\n\
@@ -387,27 +272,101 @@ fn main_with_args(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
 {P}┃ {Y}Video     {P}┃ {W}{}x{:<4} {P}┃ {B}{:.3} fps {P}┃ {W}{:02}{C}:{W}{:02}{C}:{W}{:02}{:<30} {P}┃\n\
 {P}┣━━━━━━━━━━━╋━━━━━━━━━━━┻━━━━━━━━━━━━┻━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┫\n\
 {P}┃ {Y}Time      {P}┃ {W}{:02}{C}:{W}{:02}{C}:{W}{:02} {B}@ {:>6.2} fps{:<42} {P}┃\n\
-{P}┗━━━━━━━━━━━┻━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┛{N}",
-    args.input.file_name().unwrap().to_string_lossy(),
-    args.output.file_name().unwrap().to_string_lossy(),
-    format!("{} {C}({:.0} kb/s) {G}󰛂 {G}{} {C}({:.0} kb/s) {}{} {:.2}%", 
-        fmt_size(input_size), input_br, fmt_size(output_size), output_br, change_color, arrow, change.abs()),
-    inf.width, inf.height, fps_rate, dh, dm, ds, "",
-    eh, em, es, enc_speed, ""
+{}{P}┗━━━━━━━━━━━┻━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━━┛{N}",
+    input_name,
+    output_name,
+    size_str,
+    s.width, s.height, fps_rate, dh, dm, ds, "",
+    eh, em, es, enc_speed, "", rows
 );
 
-    fs::remove_dir_all(&work_dir)?;
+    print_hint(args, change);
+}
 
+fn print_hint(args: &Args, change: f64) {
+    if change > 0.0 && !args.lossless {
+        eprintln!(
+            "{Y}Hint: The output is larger than the source. Raise the CRF (or lower the -t \
+             target), or check that the source is not already heavily compressed or AV1{N}"
+        );
+    }
+}
+
+fn set_priority(nice: i32) {
+    const IOPRIO_CLASS_BE: i32 = 2;
+    const IOPRIO_WHO_PROCESS: i32 = 1;
+
+    unsafe {
+        if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+            eprintln!("Warning: Failed to set nice {nice}: {}", std::io::Error::last_os_error());
+        }
+        let ioprio = (IOPRIO_CLASS_BE << 13) | ((nice + 20) / 5);
+        libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio);
+    }
+}
+
+fn redirect_stdout() -> Result<std::fs::File, XavError> {
+    use std::os::fd::FromRawFd;
+
+    std::io::stdout().flush()?;
+    unsafe {
+        let fd = libc::dup(1);
+        if fd < 0 || libc::dup2(2, 1) < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(std::fs::File::from_raw_fd(fd))
+    }
+}
+
+fn restore_stdout(saved: &std::fs::File) -> Result<(), XavError> {
+    use std::os::fd::AsRawFd;
+
+    std::io::stdout().flush()?;
+    if unsafe { libc::dup2(saved.as_raw_fd(), 1) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
     Ok(())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// With `--stdout` everything xav or the encoders would print to stdout goes to stderr while
+/// the encode runs, then the muxed file is copied to stdout.
+fn encode(args: &Args) -> Result<Summary, XavError> {
+    if !args.stdout || args.list_chunks {
+        return xav::encode(args);
+    }
+
+    let mut stdout = redirect_stdout()?;
+    let summary = xav::encode(args).inspect_err(|_| {
+        let _ = std::fs::remove_file(&args.output);
+    });
+    restore_stdout(&stdout)?;
+    let summary = summary?;
+    std::io::copy(&mut std::fs::File::open(&args.output)?, &mut stdout)?;
+    std::fs::remove_file(&args.output)?;
+    Ok(summary)
+}
+
+fn main() {
     let args = parse_args();
     let output = args.output.clone();
 
+    if args.dry_run {
+        println!("Params: {}", args.params);
+        if let Some(strength) = args.grain_strength {
+            println!("Grain strength: {strength}");
+        } else if let Some(iso) = args.noise {
+            println!("Noise: ISO{iso}");
+        }
+        if let Some(chroma) = args.noise_chroma {
+            println!("Noise chroma: {chroma}");
+        }
+        return;
+    }
+
+    xav::FULLSCREEN.store(args.fullscreen && !args.quiet, std::sync::atomic::Ordering::Relaxed);
+
     std::panic::set_hook(Box::new(move |panic_info| {
-        print!("\x1b[?25h\x1b[?1049l");
-        let _ = std::io::stdout().flush();
+        restore();
         eprintln!("{panic_info}");
         eprintln!("{}, FAIL", output.display());
     }));
@@ -415,15 +374,32 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     unsafe {
         libc::atexit(restore);
         libc::signal(libc::SIGINT, exit_restore as usize);
+        libc::signal(libc::SIGTERM, term_stop as usize);
         libc::signal(libc::SIGSEGV, exit_restore as usize);
     }
 
-    if let Err(e) = main_with_args(&args) {
-        print!("\x1b[?1049l");
+    if let Some(nice) = args.nice {
+        set_priority(nice);
+    }
+
+    if xav::FULLSCREEN.load(std::sync::atomic::Ordering::Relaxed) {
+        print!("\x1b[?1049h\x1b[H\x1b[?25l");
         std::io::stdout().flush().unwrap();
-        eprintln!("{}, FAIL", args.output.display());
-        return Err(e);
     }
 
-    Ok(())
+    let summary = encode(&args);
+    restore();
+
+    match summary {
+        Ok(_) if args.list_chunks => {}
+        #[cfg(feature = "vship")]
+        Ok(_) if args.crf_curve.is_some() => {}
+        Ok(s) if args.benchmark.is_some() => print_benchmark(&args, &s),
+        Ok(s) => print_summary(&args, &s),
+        Err(e) => {
+            eprintln!("{e}");
+            eprintln!("{}, FAIL", args.output.display());
+            std::process::exit(e.exit_code());
+        }
+    }
 }
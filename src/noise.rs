@@ -2,9 +2,21 @@ use std::path::Path;
 
 use av1_grain::{NoiseGenArgs, TransferFunction, generate_photon_noise_params, write_grain_table};
 
+use crate::error::XavError;
 use crate::ffms::VidInf;
 
-pub fn gen_table(iso: u32, inf: &VidInf, output: &Path) -> Result<(), Box<dyn std::error::Error>> {
+/// ISO that `--grain-strength` tables are generated at before their scaling is replaced.
+pub const STRENGTH_ISO: u32 = 6400;
+
+/// Writes a photon noise table for `iso`. A `strength` in 0-1 overrides the luma scaling so its
+/// peak is that fraction of the full AV1 scaling range, keeping the shape of the ISO curve.
+pub fn gen_table(
+    iso: u32,
+    strength: Option<f32>,
+    chroma: Option<f32>,
+    inf: &VidInf,
+    output: &Path,
+) -> Result<(), XavError> {
     let transfer = if inf.transfer_characteristics == Some(16) {
         TransferFunction::SMPTE2084
     } else {
@@ -16,13 +28,84 @@ pub fn gen_table(iso: u32, inf: &VidInf, output: &Path) -> Result<(), Box<dyn st
         width: inf.width,
         height: inf.height,
         transfer_function: transfer,
-        chroma_grain: true,
+        chroma_grain: chroma.is_none(),
         random_seed: None,
     };
 
     let duration = inf.frames as u64 * u64::from(inf.fps_den) * 10_000_000 / u64::from(inf.fps_num);
-    let segment = generate_photon_noise_params(0, duration, args);
+    let mut segment = generate_photon_noise_params(0, duration, args);
+
+    if let Some(strength) = strength {
+        let peak = segment.scaling_points_y.iter().map(|&[_, y]| y).max().unwrap_or(0).max(1);
+        let scale = strength * 255.0 / f32::from(peak);
+        for point in &mut segment.scaling_points_y {
+            point[1] = (f32::from(point[1]) * scale).round().min(255.0) as u8;
+        }
+    }
+
+    if let Some(strength) = chroma.filter(|&s| s > 0.0) {
+        let points = &segment.scaling_points_y;
+        let last = points.len().saturating_sub(1);
+        for i in 0..points.len().min(10) {
+            let [x, y] = points[i * last / 9];
+            let y = (f32::from(y) * strength).round().min(255.0) as u8;
+            segment.scaling_points_cb.push([x, y]);
+            segment.scaling_points_cr.push([x, y]);
+        }
+        segment.cb_mult = 128;
+        segment.cb_luma_mult = 192;
+        segment.cb_offset = 256;
+        segment.cr_mult = 128;
+        segment.cr_luma_mult = 192;
+        segment.cr_offset = 256;
+    }
+
+    write_grain_table(output, &[segment]).map_err(|e| XavError::Grain(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inf(is_10bit: bool, out_10bit: bool) -> VidInf {
+        VidInf {
+            width: 1920,
+            height: 1080,
+            fps_num: 24000,
+            fps_den: 1001,
+            frames: 240,
+            color_primaries: Some(1),
+            transfer_characteristics: Some(1),
+            matrix_coefficients: Some(1),
+            is_10bit,
+            out_10bit,
+            vfr: false,
+            gray: false,
+            color_range: Some(0),
+            chroma_sample_position: None,
+            mastering_display: None,
+            content_light: None,
+            #[cfg(feature = "vship")]
+            tonemap: None,
+        }
+    }
+
+    #[test]
+    fn table_does_not_depend_on_bit_depth() {
+        let dir = std::env::temp_dir().join(format!("xav-noise-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tables: Vec<Vec<u8>> = [(false, false), (false, true), (true, false), (true, true)]
+            .into_iter()
+            .enumerate()
+            .map(|(i, (is_10bit, out_10bit))| {
+                let path = dir.join(format!("{i}.tbl"));
+                gen_table(800, None, Some(0.5), &inf(is_10bit, out_10bit), &path).unwrap();
+                std::fs::read(&path).unwrap()
+            })
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
 
-    write_grain_table(output, &[segment])?;
-    Ok(())
+        assert!(tables[0].starts_with(b"filmgrn1"));
+        assert!(tables.windows(2).all(|w| w[0] == w[1]));
+    }
 }
@@ -1,13 +1,23 @@
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 static DISPLAY_MUTEX: Mutex<()> = Mutex::new(());
+pub static FULLSCREEN: AtomicBool = AtomicBool::new(false);
 
 const BAR_WIDTH: usize = 32;
+const DRIFT_DIV: usize = 100;
+/// Below this many finished chunks their spread says little, so the size estimate is always a
+/// range and assumes chunks differ by at least `EST_PRIOR_CV` of the mean bitrate.
+const EST_MIN_CHUNKS: usize = 8;
+const EST_PRIOR_CV: f32 = 0.5;
 
 const G: &str = "\x1b[1;92m";
 const R: &str = "\x1b[1;91m";
@@ -21,23 +31,129 @@ const N: &str = "\x1b[0m";
 const G_HASH: &str = "\x1b[1;92m#";
 const R_DASH: &str = "\x1b[1;91m-";
 
+/// Where a run draws its progress bars, status lines and warnings. Defaults to stdout.
+#[derive(Clone)]
+pub struct Sink(Arc<Mutex<dyn Write + Send>>);
+
+impl Sink {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self(Arc::new(Mutex::new(out)))
+    }
+
+    /// Writes and flushes `text`. Write errors only cost the display, so they are dropped.
+    pub fn put(&self, text: &str) {
+        let mut out = self.0.lock().unwrap();
+        let _ = out.write_all(text.as_bytes());
+        let _ = out.flush();
+    }
+
+    /// Writes `msg` as a `Warning:` line.
+    pub fn warn(&self, msg: &str) {
+        self.put(&format!("Warning: {msg}\n"));
+    }
+}
+
+impl Default for Sink {
+    fn default() -> Self {
+        Self::new(std::io::stdout())
+    }
+}
+
+/// PSNR/SSIM from the SUMMARY block of one `SvtAv1EncApp` chunk encode.
+#[derive(Clone, Copy)]
+pub struct StatReport {
+    pub idx: usize,
+    pub frames: usize,
+    pub psnr: f64,
+    pub ssim: f64,
+}
+
+/// Frame-weighted PSNR/SSIM over the reported chunks and the chunk with the lowest PSNR.
+pub struct StatTotals {
+    pub frames: usize,
+    pub psnr: f64,
+    pub ssim: f64,
+    pub worst: (usize, f64),
+}
+
+fn parse_stat_report(text: &str, idx: usize) -> Option<StatReport> {
+    let mut lines = text.lines().map(str::trim);
+    lines.find(|l| l.starts_with("Total Frames"))?;
+    let frames = lines.next()?.split_whitespace().next()?.parse().ok()?;
+    let overall = lines.find(|l| l.starts_with("Overall"))?;
+    let nums: Vec<f64> = overall.split_whitespace().filter_map(|t| t.parse().ok()).collect();
+    Some(StatReport { idx, frames, psnr: *nums.get(1)?, ssim: *nums.get(7)? })
+}
+
+/// Appends `report` to the `stats.txt` of a work directory, next to `done.txt`.
+fn save_stat_report(path: &Path, report: &StatReport) {
+    let line = format!("{} {} {} {}\n", report.idx, report.frames, report.psnr, report.ssim);
+    let _ = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()));
+}
+
+/// Reads the reports saved in `work_dir` for `chunks`. A chunk encoded again after a resume
+/// keeps its last report.
+fn load_stat_reports(work_dir: &Path, chunks: &[crate::chunk::Chunk]) -> Vec<StatReport> {
+    let content = std::fs::read_to_string(work_dir.join("stats.txt")).unwrap_or_default();
+    let mut reports: HashMap<usize, StatReport> = HashMap::new();
+    for line in content.lines() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() == 4
+            && let (Ok(idx), Ok(frames), Ok(psnr), Ok(ssim)) =
+                (parts[0].parse(), parts[1].parse(), parts[2].parse(), parts[3].parse())
+        {
+            reports.insert(idx, StatReport { idx, frames, psnr, ssim });
+        }
+    }
+    chunks.iter().filter_map(|c| reports.remove(&c.idx)).collect()
+}
+
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+pub fn stat_totals(work_dir: &Path, chunks: &[crate::chunk::Chunk]) -> Option<StatTotals> {
+    let reports = load_stat_reports(work_dir, chunks);
+    let frames: usize = reports.iter().map(|r| r.frames).sum();
+    if frames == 0 {
+        return None;
+    }
+    let mean = |f: fn(&StatReport) -> f64| {
+        reports.iter().map(|r| f(r) * r.frames as f64).sum::<f64>() / frames as f64
+    };
+    let worst = reports.iter().min_by(|a, b| a.psnr.total_cmp(&b.psnr))?;
+    Some(StatTotals {
+        frames,
+        psnr: mean(|r| r.psnr),
+        ssim: mean(|r| r.ssim),
+        worst: (worst.idx, worst.psnr),
+    })
+}
+
 pub struct ProgsBar {
     s_time: Instant,
     last_up: Instant,
     last_val: usize,
     tot: usize,
-    quiet: bool,
+    out: Option<Sink>,
 }
 
 impl ProgsBar {
-    pub fn new(quiet: bool) -> Self {
-        Self { s_time: Instant::now(), last_up: Instant::now(), last_val: 0, tot: 0, quiet }
+    /// Draws to `out`, or nothing when it is `None`.
+    pub fn new(out: Option<&Sink>) -> Self {
+        Self {
+            s_time: Instant::now(),
+            last_up: Instant::now(),
+            last_val: 0,
+            tot: 0,
+            out: out.cloned(),
+        }
     }
 
     pub fn up_idx(&mut self, current: usize, tot: usize) {
-        if self.quiet {
-            return;
-        }
+        let Some(ref out) = self.out else { return };
         self.tot = tot;
         let now = Instant::now();
         let elapsed = now.duration_since(self.s_time);
@@ -60,20 +176,17 @@ impl ProgsBar {
 
         let perc = (current * 100 / tot.max(1)).min(100);
 
-        print!(
+        out.put(&format!(
             "\r\x1b[2K{W}IDX: {C}[{bar}{C}] {W}{perc}%{C}, {Y}{mbps} MBs{C}, {W}{eta_str}{C}, \
              {G}{current_mb}{C}/{R}{tot_mb}{N}"
-        );
-        std::io::stdout().flush().unwrap();
+        ));
 
         self.last_up = now;
         self.last_val = current;
     }
 
     pub fn up_scenes(&mut self, current: usize, tot: usize) {
-        if self.quiet {
-            return;
-        }
+        let Some(ref out) = self.out else { return };
         self.tot = tot;
         let now = Instant::now();
         let elapsed = now.duration_since(self.s_time);
@@ -90,32 +203,64 @@ impl ProgsBar {
         let eta_str = fmt_dur_colored(eta);
         let perc = (current * 100 / tot.max(1)).min(100);
 
-        print!(
+        out.put(&format!(
             "\r\x1b[2K{W}SCD: {C}[{bar}{C}] {W}{perc}%{C}, {Y}{fps} FPS{C}, {W}{eta_str}{C}, \
              {G}{current}{C}/{R}{tot}{N}"
-        );
-        std::io::stdout().flush().unwrap();
+        ));
 
         self.last_up = now;
         self.last_val = current;
     }
 
+    pub fn up_merge(&mut self, perc: usize) {
+        let Some(ref out) = self.out else { return };
+        let elapsed_secs = self.s_time.elapsed().as_secs() as usize;
+        let eta_secs = (100 - perc.min(100)) * elapsed_secs / perc.max(1);
+        let eta = Duration::from_secs(eta_secs as u64);
+
+        let filled = (BAR_WIDTH * perc / 100).min(BAR_WIDTH);
+        let bar = format!("{}{}", G_HASH.repeat(filled), R_DASH.repeat(BAR_WIDTH - filled));
+        let eta_str = fmt_dur_colored(eta);
+
+        out.put(&format!("\r\x1b[2K{W}MRG: {C}[{bar}{C}] {W}{perc}%{C}, {W}{eta_str}{N}"));
+        self.last_val = perc;
+    }
+
     pub fn finish(&self) {
-        if self.quiet {
-            return;
-        }
+        let Some(ref out) = self.out else { return };
 
-        print!("\r\x1b[2K");
-        std::io::stdout().flush().unwrap();
+        out.put("\r\x1b[2K");
     }
 
     pub fn finish_scenes(&self) {
-        if self.quiet {
+        let Some(ref out) = self.out else { return };
+
+        out.put("\r\x1b[2K");
+    }
+}
+
+struct ProgsFifo {
+    path: PathBuf,
+    file: Option<File>,
+    last_perc: Option<u8>,
+}
+
+impl ProgsFifo {
+    fn send(&mut self, perc: u8, fps: f32) {
+        if self.last_perc == Some(perc) {
             return;
         }
+        if self.file.is_none() {
+            self.file =
+                OpenOptions::new().write(true).custom_flags(libc::O_NONBLOCK).open(&self.path).ok();
+        }
+        let Some(file) = self.file.as_mut() else { return };
 
-        print!("\r\x1b[2K");
-        std::io::stdout().flush().unwrap();
+        match file.write_all(format!("{perc}\n# {fps:.2} FPS\n").as_bytes()) {
+            Ok(()) => self.last_perc = Some(perc),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(_) => self.file = None,
+        }
     }
 }
 
@@ -127,8 +272,33 @@ struct ProgsState {
     worker_cnt: usize,
     completed: Arc<AtomicUsize>,
     completions: Arc<Mutex<crate::chunk::ResumeInf>>,
+    written: Arc<AtomicUsize>,
+    lens: HashMap<usize, usize>,
     fps_num: usize,
     fps_den: usize,
+    drawn: AtomicUsize,
+    fifo: Option<Mutex<ProgsFifo>>,
+    seen: Mutex<HashMap<usize, Instant>>,
+    heartbeat: Option<Duration>,
+    refresh_ms: u64,
+    last_draw_ms: AtomicU64,
+    out: Sink,
+    stats: Option<PathBuf>,
+}
+
+impl ProgsState {
+    fn redraw_due(&self) -> bool {
+        if self.refresh_ms == 0 {
+            return true;
+        }
+        let now = self.start.elapsed().as_millis() as u64;
+        let last = self.last_draw_ms.load(Ordering::Relaxed);
+        now.saturating_sub(last) >= self.refresh_ms
+            && self
+                .last_draw_ms
+                .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+                .is_ok()
+    }
 }
 
 pub struct ProgsTrack {
@@ -142,12 +312,16 @@ impl ProgsTrack {
         chunks: &[crate::chunk::Chunk],
         inf: &crate::ffms::VidInf,
         worker_cnt: usize,
-        init_frames: usize,
         completed: Arc<AtomicUsize>,
         completions: Arc<Mutex<crate::chunk::ResumeInf>>,
+        written: Arc<AtomicUsize>,
+        out: &Sink,
     ) -> Self {
-        print!("\x1b[s");
-        std::io::stdout().flush().unwrap();
+        if FULLSCREEN.load(Ordering::Relaxed) {
+            out.put("\x1b[s");
+        }
+
+        let init_frames = written.load(Ordering::Relaxed);
 
         Self {
             lines: Arc::new(Mutex::new(HashMap::new())),
@@ -155,17 +329,83 @@ impl ProgsTrack {
             state: Arc::new(ProgsState {
                 start: Instant::now(),
                 tot_chunks: chunks.len(),
-                tot_frames: inf.frames,
+                tot_frames: chunks.iter().map(|c| c.end - c.start).sum(),
                 init_frames,
                 worker_cnt,
                 completed,
                 completions,
+                written,
+                lens: chunks.iter().map(|c| (c.idx, c.end - c.start)).collect(),
                 fps_num: inf.fps_num as usize,
                 fps_den: inf.fps_den as usize,
+                drawn: AtomicUsize::new(0),
+                fifo: None,
+                seen: Mutex::new(HashMap::new()),
+                heartbeat: None,
+                refresh_ms: 0,
+                last_draw_ms: AtomicU64::new(0),
+                out: out.clone(),
+                stats: None,
             }),
         }
     }
 
+    /// Also writes the percentage and FPS to the named pipe at `path`.
+    pub fn with_fifo(mut self, path: Option<&Path>) -> Self {
+        if let Some(state) = Arc::get_mut(&mut self.state) {
+            state.fifo = path.map(|path| {
+                Mutex::new(ProgsFifo { path: path.to_path_buf(), file: None, last_perc: None })
+            });
+        }
+        self
+    }
+
+    /// Saves the PSNR/SSIM summary of each chunk to `stats.txt` in `work_dir`.
+    pub fn with_stats(mut self, work_dir: Option<&Path>) -> Self {
+        if let Some(state) = Arc::get_mut(&mut self.state) {
+            state.stats = work_dir.map(|dir| dir.join("stats.txt"));
+        }
+        self
+    }
+
+    /// Redraws at most every `ms` milliseconds on encoder output. 0 redraws on every line.
+    pub fn with_refresh(mut self, ms: u64) -> Self {
+        if let Some(state) = Arc::get_mut(&mut self.state) {
+            state.refresh_ms = ms;
+        }
+        self
+    }
+
+    /// Redraws with a "waiting on chunk" note when an encoder prints nothing for `secs`.
+    pub fn with_heartbeat(mut self, secs: u64) -> Self {
+        if secs == 0 {
+            return self;
+        }
+        let timeout = Duration::from_secs(secs);
+        if let Some(state) = Arc::get_mut(&mut self.state) {
+            state.heartbeat = Some(timeout);
+        }
+
+        let lines = Arc::downgrade(&self.lines);
+        let processed = Arc::downgrade(&self.processed);
+        let state = Arc::downgrade(&self.state);
+        thread::spawn(move || {
+            loop {
+                thread::sleep(Duration::from_secs(1));
+                let (Some(lines), Some(processed), Some(state)) =
+                    (lines.upgrade(), processed.upgrade(), state.upgrade())
+                else {
+                    break;
+                };
+                let stalled = state.seen.lock().unwrap().values().any(|t| t.elapsed() >= timeout);
+                if stalled {
+                    Self::show_progs(&lines, &processed, &state);
+                }
+            }
+        });
+        self
+    }
+
     pub fn watch_enc(
         &self,
         stderr: impl std::io::Read + Send + 'static,
@@ -180,6 +420,7 @@ impl ProgsTrack {
         thread::spawn(move || {
             let mut reader = BufReader::new(stderr);
             let mut buffer = Vec::new();
+            state.seen.lock().unwrap().insert(chunk_idx, Instant::now());
 
             loop {
                 buffer.clear();
@@ -193,26 +434,53 @@ impl ProgsTrack {
                     Err(_) => continue,
                 };
 
-                if line.contains("error") {
-                    print!("\x1b[?1049l");
-                    std::io::stdout().flush().unwrap();
-                    eprintln!("{line}");
+                if track_frames
+                    && line.contains("SUMMARY")
+                    && let Some(ref path) = state.stats
+                    && let Some(report) = parse_stat_report(line, chunk_idx)
+                {
+                    save_stat_report(path, &report);
                 }
 
-                if !line.contains("Encoding:") || line.contains("SUMMARY") {
-                    continue;
+                if line.contains("error") {
+                    if FULLSCREEN.load(Ordering::Relaxed) {
+                        state.out.put("\x1b[?1049l");
+                    }
+                    state.out.put(&format!("{line}\n"));
                 }
 
-                Self::up_line(&lines, &processed, chunk_idx, line, track_frames, crf_score);
+                let Some(line) = Self::norm_line(line) else { continue };
 
-                Self::show_progs(&lines, &processed, &state);
+                Self::up_line(&lines, &processed, chunk_idx, &line, track_frames, crf_score);
+                state.seen.lock().unwrap().insert(chunk_idx, Instant::now());
+
+                if state.redraw_due() {
+                    Self::show_progs(&lines, &processed, &state);
+                }
             }
 
+            state.seen.lock().unwrap().remove(&chunk_idx);
             let mut map = lines.lock().unwrap();
             map.remove(&chunk_idx);
         });
     }
 
+    fn norm_line(line: &str) -> Option<String> {
+        if line.contains("Encoding:") && !line.contains("SUMMARY") {
+            return Some(line.to_string());
+        }
+
+        let line = line.trim();
+        if let Some(aom) = line.strip_prefix("Pass 1/1 frame") {
+            let aom = aom.trim_start();
+            let (frames, rest) = aom.split_once(' ').unwrap_or((aom, ""));
+            return Some(format!("Encoding: {frames} Frames {}", rest.trim_start()));
+        }
+
+        let rav1e = line.strip_prefix("encoded ")?;
+        Some(format!("Encoding: {}", rav1e.replacen(" frames", " Frames", 1)))
+    }
+
     fn get_frame_cnt(line: &str) -> Option<usize> {
         let frames_pos = line.find(" Frames")?;
         let bytes = line.as_bytes();
@@ -276,8 +544,18 @@ impl ProgsTrack {
 
         let data = state.completions.lock().unwrap();
         let completed_frames: usize = data.chnks_done.iter().map(|c| c.frames).sum();
+        let expected: usize = data.chnks_done.iter().filter_map(|c| state.lens.get(&c.idx)).sum();
+        let written = state.written.load(Ordering::Relaxed);
         drop(data);
 
+        let drift = written.abs_diff(expected);
+        let drift_str = if drift > expected / DRIFT_DIV {
+            let sign = if written > expected { '+' } else { '-' };
+            format!(" {R}[{written} written, drift {sign}{drift}]")
+        } else {
+            String::new()
+        };
+
         let frames_done =
             if completed_frames > processed_frames { completed_frames } else { processed_frames };
 
@@ -293,15 +571,45 @@ impl ProgsTrack {
         let chunks_done = state.completed.load(Ordering::Relaxed);
         let (bitrate_str, est_str) = get_bitrate_estimates(state);
 
-        print!("\x1b[u");
+        let mut frame = String::new();
+        let drawn = state.drawn.load(Ordering::Relaxed);
+        if FULLSCREEN.load(Ordering::Relaxed) {
+            frame.push_str("\x1b[u");
+        } else if drawn > 0 {
+            write!(frame, "\x1b[{drawn}F").unwrap();
+        }
 
+        let stalled: HashMap<usize, u64> = state
+            .heartbeat
+            .map(|timeout| {
+                let seen = state.seen.lock().unwrap();
+                seen.iter()
+                    .filter(|(_, t)| t.elapsed() >= timeout)
+                    .map(|(&idx, t)| (idx, t.elapsed().as_secs()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let width = term_width();
         let map = lines.lock().unwrap();
-        for line in map.values() {
-            print!("\r\x1b[2K{line}\n");
+        for (idx, line) in map.iter() {
+            let line = stalled.get(idx).map_or_else(
+                || line.clone(),
+                |secs| format!("{line} {Y}waiting on chunk {idx}, no output for {secs}s{N}"),
+            );
+            writeln!(frame, "\r\x1b[2K{}", fit(&line, width)).unwrap();
+        }
+        let mut rows = map.len();
+        for (idx, secs) in stalled.iter().filter(|(idx, _)| !map.contains_key(idx)) {
+            let line =
+                format!("{C}[{idx:04}{C}] {Y}waiting on chunk {idx}, no output for {secs}s{N}");
+            writeln!(frame, "\r\x1b[2K{}", fit(&line, width)).unwrap();
+            rows += 1;
         }
-        for _ in map.len()..=state.worker_cnt {
-            print!("\r\x1b[2K\n");
+        for _ in rows..=state.worker_cnt {
+            frame.push_str("\r\x1b[2K\n");
         }
+        state.drawn.store(rows.max(state.worker_cnt + 1) + 1, Ordering::Relaxed);
         drop(map);
 
         let (h, m, s) = (elapsed_secs / 3600, (elapsed_secs % 3600) / 60, elapsed_secs % 60);
@@ -312,15 +620,20 @@ impl ProgsTrack {
 
         let bar = format!("{}{}", G_HASH.repeat(progs), R_DASH.repeat(BAR_WIDTH - progs));
 
-        println!(
+        if let Some(ref fifo) = state.fifo {
+            fifo.lock().unwrap().send(perc, fps);
+        }
+
+        let status = format!(
             "{W}{h:02}{P}:{W}{m:02}{P}:{W}{s:02} {C}[{G}{chunks_done}{C}/{R}{}{C}] [{bar}{C}] \
-             {W}{perc}% {G}{frames_done}{C}/{R}{} {C}({Y}{fps:.2} FPS{C}, \
+             {W}{perc}% {G}{frames_done}{C}/{R}{}{drift_str} {C}({Y}{fps:.2} FPS{C}, \
              {W}{eta_h:02}{P}:{W}{eta_m:02}{P}:{W}{eta_s:02}{C}, {bitrate_str}{C}, \
              {R}{est_str}{C}){N}",
             state.tot_chunks, state.tot_frames
         );
+        writeln!(frame, "{}", fit(&status, width)).unwrap();
 
-        std::io::stdout().flush().unwrap();
+        state.out.put(&frame);
     }
 
     #[cfg(feature = "vship")]
@@ -354,7 +667,9 @@ impl ProgsTrack {
         );
         drop(map);
 
-        Self::show_progs(&self.lines, &self.processed, &self.state);
+        if self.state.redraw_due() {
+            Self::show_progs(&self.lines, &self.processed, &self.state);
+        }
     }
 
     pub fn final_update(&self) {
@@ -362,27 +677,80 @@ impl ProgsTrack {
     }
 }
 
+#[allow(clippy::cast_precision_loss)]
 fn get_bitrate_estimates(state: &ProgsState) -> (String, String) {
     let data = state.completions.lock().unwrap();
     let tot_size: u64 = data.chnks_done.iter().map(|c| c.size).sum();
     let tot_chunk_frames: usize = data.chnks_done.iter().map(|c| c.frames).sum();
+    let done_cnt = data.chnks_done.len();
+    let done = done_cnt.max(1) as f32;
+    let mean = tot_size as f32 / tot_chunk_frames.max(1) as f32;
+    let var = data
+        .chnks_done
+        .iter()
+        .map(|c| c.frames as f32 * (c.size as f32 / c.frames.max(1) as f32 - mean).powi(2))
+        .sum::<f32>()
+        / tot_chunk_frames.max(1) as f32;
     drop(data);
 
     let dur_secs = tot_chunk_frames as f32 * state.fps_den as f32 / state.fps_num as f32;
     let bitrate_kbps = tot_size as f32 * 8.0 / dur_secs / 1000.0;
 
-    let tot_dur = state.tot_frames as f32 * state.fps_den as f32 / state.fps_num as f32;
-    let est_size = bitrate_kbps * tot_dur * 1000.0 / 8.0;
+    let remaining = state.tot_frames.saturating_sub(tot_chunk_frames) as f32;
+    let est_size = remaining.mul_add(mean, tot_size as f32);
+    let early = done_cnt < EST_MIN_CHUNKS && remaining > 0.0;
+    let sd = if early { var.sqrt().max(mean * EST_PRIOR_CV) } else { var.sqrt() };
+    let spread = remaining * sd / done.sqrt();
 
-    let est_str = if est_size > 1_000_000_000.0 {
-        format!("{:.1} GB", est_size / 1_000_000_000.0)
+    let (div, unit) = if est_size + spread > 1_000_000_000.0 {
+        (1_000_000_000.0, "GB")
     } else {
-        format!("{:.1} MB", est_size / 1_000_000.0)
+        (1_000_000.0, "MB")
+    };
+    let est_str = if early || spread / est_size.max(1.0) > 0.05 {
+        format!(
+            "{:.1}-{:.1} {unit}",
+            (est_size - spread).max(tot_size as f32) / div,
+            (est_size + spread) / div
+        )
+    } else {
+        format!("{:.1} {unit}", est_size / div)
     };
 
     (format!("{B}{bitrate_kbps:.0} kb{C}/{B}s"), format!("{R}{est_str}"))
 }
 
+/// Columns of the terminal on stdout or stderr, `None` when neither is a terminal.
+#[must_use]
+pub fn term_width() -> Option<usize> {
+    [libc::STDOUT_FILENO, libc::STDERR_FILENO].into_iter().find_map(|fd| {
+        let mut ws: libc::winsize = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &raw mut ws) } == 0;
+        (ok && ws.ws_col > 0).then_some(usize::from(ws.ws_col))
+    })
+}
+
+/// Cuts `line` to `width` visible columns, leaving escape sequences intact.
+fn fit(line: &str, width: Option<usize>) -> String {
+    let Some(width) = width else { return line.to_string() };
+    let mut out = String::with_capacity(line.len());
+    let (mut cols, mut in_esc) = (0, false);
+    for ch in line.chars() {
+        if in_esc {
+            in_esc = !ch.is_ascii_alphabetic();
+        } else if ch == '\x1b' {
+            in_esc = true;
+        } else if cols + 1 >= width {
+            out.push_str(N);
+            return out;
+        } else {
+            cols += 1;
+        }
+        out.push(ch);
+    }
+    out
+}
+
 fn fmt_dur_colored(d: Duration) -> String {
     let tot_secs = d.as_secs();
     let hours = tot_secs / 3600;
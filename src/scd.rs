@@ -1,58 +1,258 @@
+use std::cell::Cell;
 use std::fmt::Write;
 use std::fs;
+use std::io::Read;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use av_scenechange::{DetectionOptions, SceneDetectionSpeed, av_decoders, detect_scene_changes};
 
-use crate::ffms;
-use crate::progs::ProgsBar;
+use crate::chunk;
+use crate::error::XavError;
+use crate::ffms::{self, VidIdx, VidInf};
+use crate::progs::{ProgsBar, Sink};
 
-pub fn fd_scenes(
-    vid_path: &Path,
+const SEG_LEN: usize = 3000;
+
+struct FrameReader {
+    source: *mut std::ffi::c_void,
+    inf: VidInf,
+    next: usize,
+    end: usize,
+    frame: Vec<u8>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl FrameReader {
+    fn new(
+        idx: &Arc<VidIdx>,
+        inf: &VidInf,
+        start: usize,
+        end: usize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let source = ffms::thr_vid_src(idx, 4)?;
+        let (csp, frame_size) = if inf.is_10bit {
+            ("C420p10 XYSCSS=420P10", ffms::calc_10bit_size(inf))
+        } else {
+            ("C420jpeg XYSCSS=420JPEG", ffms::calc_8bit_size(inf))
+        };
+        let header = format!(
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 {csp}\n",
+            inf.width, inf.height, inf.fps_num, inf.fps_den
+        );
+
+        Ok(Self {
+            source,
+            inf: inf.clone(),
+            next: start,
+            end,
+            frame: vec![0u8; frame_size],
+            buf: header.into_bytes(),
+            pos: 0,
+        })
+    }
+}
+
+impl Read for FrameReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.buf.len() {
+            if self.next >= self.end {
+                return Ok(0);
+            }
+
+            let res = if self.inf.is_10bit {
+                ffms::extr_10bit(self.source, self.next, &self.inf, &mut self.frame)
+            } else {
+                ffms::extr_8bit(self.source, self.next, &self.inf, &mut self.frame)
+            };
+            res.map_err(|e| std::io::Error::other(XavError::from(e).to_string()))?;
+
+            self.buf.clear();
+            self.buf.extend_from_slice(b"FRAME\n");
+            self.buf.extend_from_slice(&self.frame);
+            self.pos = 0;
+            self.next += 1;
+        }
+
+        let n = (self.buf.len() - self.pos).min(out.len());
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Drop for FrameReader {
+    fn drop(&mut self) {
+        ffms::destroy_vid_src(self.source);
+    }
+}
+
+struct SegCtx<'a> {
+    idx: &'a Arc<VidIdx>,
+    inf: &'a VidInf,
+    opts: DetectionOptions,
+    overlap: usize,
+    done: &'a AtomicUsize,
+    progs: Option<&'a Mutex<ProgsBar>>,
+}
+
+fn scan_seg(
+    ctx: &SegCtx,
+    start: usize,
+    end: usize,
+) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let from = start.saturating_sub(ctx.overlap);
+    let reader = FrameReader::new(ctx.idx, ctx.inf, from, end)?;
+    let y4m = av_decoders::Y4mDecoder::new(Box::new(reader) as Box<dyn Read>)?;
+    let mut decoder = av_decoders::Decoder::from_decoder_impl(av_decoders::DecoderImpl::Y4m(y4m))?;
+
+    let last = Cell::new(0);
+    let callback = |current: usize, _keyframes: usize| {
+        let seen = (from + current).saturating_sub(start);
+        let diff = seen.saturating_sub(last.replace(seen));
+        let total = ctx.done.fetch_add(diff, Ordering::Relaxed) + diff;
+        if let Some(p) = ctx.progs
+            && let Ok(mut pb) = p.lock()
+        {
+            pb.up_scenes(total, ctx.inf.frames);
+        }
+    };
+    let callback: Option<&dyn Fn(usize, usize)> =
+        if ctx.progs.is_some() { Some(&callback) } else { None };
+
+    let results = if ctx.inf.is_10bit {
+        detect_scene_changes::<u16>(&mut decoder, ctx.opts, None, callback)?
+    } else {
+        detect_scene_changes::<u8>(&mut decoder, ctx.opts, None, callback)?
+    };
+
+    Ok(results.scene_changes.iter().map(|&f| f + from).filter(|&f| f >= start && f < end).collect())
+}
+
+fn merge_cuts(mut cuts: Vec<usize>, frames: usize, min_dist: usize, max_dist: usize) -> Vec<usize> {
+    cuts.sort_unstable();
+    cuts.dedup();
+
+    let mut merged = vec![0];
+    for cut in cuts.into_iter().chain(std::iter::once(frames)) {
+        let last = *merged.last().unwrap();
+        if cut < last + min_dist && cut != frames {
+            continue;
+        }
+        let gap = cut - last;
+        let pieces = gap.div_ceil(max_dist);
+        merged.extend((1..pieces).map(|i| last + gap * i / pieces));
+        merged.push(cut);
+    }
+    merged.pop();
+
+    merged
+}
+
+pub fn cut_dists(inf: &VidInf) -> (usize, usize) {
+    let min_dist = ((inf.fps_num + inf.fps_den / 2) / inf.fps_den) as usize;
+    let max_dist = ((inf.fps_num * 10 + inf.fps_den / 2) / inf.fps_den).min(300) as usize;
+    (min_dist, max_dist)
+}
+
+fn scene_header(inf: &VidInf) -> String {
+    format!("# {}x{} {}/{} {}\n", inf.width, inf.height, inf.fps_num, inf.fps_den, inf.frames)
+}
+
+fn write_scenes(inf: &VidInf, cuts: Vec<usize>, scene_file: &Path) -> std::io::Result<()> {
+    let (min_dist, max_dist) = cut_dists(inf);
+    let mut content = scene_header(inf);
+    for scene_frame in merge_cuts(cuts, inf.frames, min_dist, max_dist) {
+        writeln!(content, "{scene_frame}").unwrap();
+    }
+
+    fs::write(scene_file, content)
+}
+
+/// Keeps every line of the scene file, including per-scene params, and appends the cuts found
+/// from `start` on.
+fn append_scenes(
+    inf: &VidInf,
+    cuts: Vec<usize>,
+    start: usize,
     scene_file: &Path,
-    quiet: bool,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let idx = ffms::VidIdx::new(vid_path, quiet)?;
-    let inf = ffms::get_vidinf(&idx)?;
+) -> std::io::Result<()> {
+    let (min_dist, max_dist) = cut_dists(inf);
+    let old = fs::read_to_string(scene_file)?;
+    let kept: Vec<usize> = chunk::read_cuts(&old).into_iter().map(|(f, _)| f).collect();
 
-    let min_dist = (inf.fps_num + inf.fps_den / 2) / inf.fps_den;
-    let max_dist = ((inf.fps_num * 10 + inf.fps_den / 2) / inf.fps_den).min(300);
-    let tot_frames = inf.frames;
-    drop(idx);
+    let mut content = scene_header(inf);
+    for line in old.lines().filter(|l| !l.trim_start().starts_with('#')) {
+        writeln!(content, "{line}").unwrap();
+    }
+    let all = kept.iter().copied().chain(cuts).collect();
+    for scene_frame in merge_cuts(all, inf.frames, min_dist, max_dist) {
+        if scene_frame >= start && !kept.contains(&scene_frame) {
+            writeln!(content, "{scene_frame}").unwrap();
+        }
+    }
+
+    fs::write(scene_file, content)
+}
+
+pub fn kf_scenes(
+    idx: &Arc<VidIdx>,
+    inf: &VidInf,
+    scene_file: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    write_scenes(inf, ffms::get_keyframes(idx, inf.frames), scene_file)?;
+    Ok(())
+}
 
-    let mut decoder = av_decoders::Decoder::from_file(vid_path)?;
+/// Runs SCD from `start` on. A non-zero `start` extends the scene file that covers the frames
+/// before it.
+pub fn fd_scenes(
+    idx: &Arc<VidIdx>,
+    inf: &VidInf,
+    scene_file: &Path,
+    start: usize,
+    out: Option<&Sink>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (min_dist, max_dist) = cut_dists(inf);
 
     let opts = DetectionOptions {
         analysis_speed: SceneDetectionSpeed::Standard,
         detect_flashes: false,
-        min_scenecut_distance: Some(min_dist as usize),
-        max_scenecut_distance: Some(max_dist as usize),
+        min_scenecut_distance: Some(min_dist),
+        max_scenecut_distance: None,
         lookahead_distance: 1,
     };
 
-    let progs = if quiet { None } else { Some(Arc::new(Mutex::new(ProgsBar::new(false)))) };
+    let segs: Vec<(usize, usize)> =
+        (start..inf.frames).step_by(SEG_LEN).map(|s| (s, (s + SEG_LEN).min(inf.frames))).collect();
+    let threads = std::thread::available_parallelism().map_or(8, std::num::NonZero::get);
+    let workers = (threads / 4).clamp(1, segs.len().max(1));
 
-    let results = if let Some(p) = &progs {
-        let progs_callback = {
-            let progs_clone = Arc::clone(p);
-            move |current: usize, _keyframes: usize| {
-                if let Ok(mut pb) = progs_clone.lock() {
-                    pb.up_scenes(current, tot_frames);
-                }
-            }
-        };
+    let progs = out.map(|out| Mutex::new(ProgsBar::new(Some(out))));
+    let done = AtomicUsize::new(start);
+    let next = AtomicUsize::new(0);
+    let cuts = Mutex::new(Vec::new());
+    let failed = Mutex::new(None);
 
-        if inf.is_10bit {
-            detect_scene_changes::<u16>(&mut decoder, opts, None, Some(&progs_callback))?
-        } else {
-            detect_scene_changes::<u8>(&mut decoder, opts, None, Some(&progs_callback))?
+    let ctx = SegCtx { idx, inf, opts, overlap: max_dist, done: &done, progs: progs.as_ref() };
+
+    std::thread::scope(|s| {
+        for _ in 0..workers {
+            s.spawn(|| {
+                while let Some(&(start, end)) = segs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    match scan_seg(&ctx, start, end) {
+                        Ok(found) => cuts.lock().unwrap().extend(found),
+                        Err(e) => {
+                            *failed.lock().unwrap() = Some(e.to_string());
+                            break;
+                        }
+                    }
+                }
+            });
         }
-    } else if inf.is_10bit {
-        detect_scene_changes::<u16>(&mut decoder, opts, None, None)?
-    } else {
-        detect_scene_changes::<u8>(&mut decoder, opts, None, None)?
-    };
+    });
 
     if let Some(p) = progs
         && let Ok(pb) = p.lock()
@@ -60,11 +260,26 @@ pub fn fd_scenes(
         pb.finish_scenes();
     }
 
-    let mut content = String::new();
-    for &scene_frame in &results.scene_changes {
-        writeln!(content, "{scene_frame}").unwrap();
+    if let Some(e) = failed.into_inner().unwrap() {
+        return Err(e.into());
     }
 
-    fs::write(scene_file, content)?;
+    if start == 0 {
+        write_scenes(inf, cuts.into_inner().unwrap(), scene_file)?;
+    } else {
+        append_scenes(inf, cuts.into_inner().unwrap(), start, scene_file)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn forced_cuts_are_spaced_evenly() {
+        assert_eq!(merge_cuts(vec![305, 400], 1000, 24, 300), [0, 152, 305, 400, 700]);
+        assert_eq!(merge_cuts(vec![10, 500], 600, 24, 300), [0, 250, 500]);
+        assert_eq!(merge_cuts(vec![], 900, 24, 300), [0, 300, 600]);
+    }
+}
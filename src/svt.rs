@@ -1,49 +1,367 @@
 use std::collections::HashSet;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Stdio};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::{fs, thread};
 
-use crossbeam_channel::{Receiver, Sender, bounded};
+use crossbeam_channel::{Receiver, RecvTimeoutError, SendTimeoutError, Sender, bounded};
 
-use crate::chunk::{Chunk, ChunkComp, ResumeInf, get_resume, save_resume};
+use crate::chunk::{
+    Chunk, ChunkComp, InOrderOut, PartialOut, ResumeInf, dir_size, get_resume, partial_path,
+    prefix_path, save_resume,
+};
+use crate::error::XavError;
 use crate::ffms::{
-    VidIdx, VidInf, calc_8bit_size, calc_10bit_size, calc_packed_size, conv_to_10bit,
-    destroy_vid_src, extr_8bit, extr_10bit, pack_10bit, thr_vid_src, unpack_10bit,
+    FrameErr, VidIdx, VidInf, calc_8bit_size, calc_10bit_size, calc_packed_size, conv_to_8bit,
+    conv_to_10bit, destroy_vid_src, extr_8bit, extr_10bit, pack_10bit, thr_vid_src, unpack_10bit,
 };
-use crate::progs::ProgsTrack;
+use crate::progs::{ProgsTrack, Sink};
 
-fn get_tile_params(width: u32, height: u32) -> (&'static str, &'static str) {
+fn get_tile_params(width: u32, height: u32) -> (u8, u8) {
     let is_vertical = height > width;
     let max_dim = width.max(height);
 
     match max_dim {
-        0..=1080 => ("0", "0"),
+        0..=1080 => (0, 0),
         1081..=2160 => {
             if is_vertical {
-                ("0", "1")
+                (0, 1)
             } else {
-                ("1", "0")
+                (1, 0)
             }
         }
         _ => {
             if is_vertical {
-                ("0", "2")
+                (0, 2)
             } else {
-                ("2", "0")
+                (2, 0)
             }
         }
     }
 }
 
+fn get_tiles(cfg: &EncConfig) -> (u8, u8) {
+    let (cols, rows) = get_tile_params(cfg.inf.width, cfg.inf.height);
+    (cfg.args.tile_cols.unwrap_or(cols), cfg.args.tile_rows.unwrap_or(rows))
+}
+
+fn get_flag(params: &str, flag: &str) -> Option<String> {
+    let mut tokens = params.split_whitespace();
+    let mut val = None;
+    while let Some(token) = tokens.next() {
+        if token == flag {
+            val = tokens.next().map(str::to_string);
+        } else if let Some(v) = token.strip_prefix(flag).and_then(|t| t.strip_prefix('=')) {
+            val = Some(v.to_string());
+        }
+    }
+    val
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoder {
+    #[default]
+    Svt,
+    Rav1e,
+    Aom,
+}
+
+impl Encoder {
+    #[must_use]
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "svt" | "svt-av1" => Some(Self::Svt),
+            "rav1e" => Some(Self::Rav1e),
+            "aom" | "aomenc" => Some(Self::Aom),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub const fn binary(self) -> &'static str {
+        match self {
+            Self::Svt => "SvtAv1EncApp",
+            Self::Rav1e => "rav1e",
+            Self::Aom => "aomenc",
+        }
+    }
+
+    #[must_use]
+    pub const fn lp_flag(self) -> &'static str {
+        match self {
+            Self::Svt => "--lp",
+            Self::Rav1e | Self::Aom => "--threads",
+        }
+    }
+
+    #[must_use]
+    pub fn lp_param(self, threads: usize) -> String {
+        match self {
+            Self::Svt => format!("--lp {threads}"),
+            Self::Rav1e => format!("--threads {threads}"),
+            Self::Aom => format!("--threads={threads}"),
+        }
+    }
+
+    #[must_use]
+    pub const fn speed_flag(self) -> &'static str {
+        match self {
+            Self::Svt => "--preset",
+            Self::Rav1e => "--speed",
+            Self::Aom => "--cpu-used",
+        }
+    }
+
+    #[must_use]
+    pub fn speed_param(self, speed: u8) -> String {
+        match self {
+            Self::Svt => format!("--preset {speed}"),
+            Self::Rav1e => format!("--speed {}", speed.min(10)),
+            Self::Aom => format!("--cpu-used={}", speed.min(9)),
+        }
+    }
+
+    #[must_use]
+    pub fn get_speed(self, params: &str) -> Option<String> {
+        get_flag(params, self.speed_flag())
+    }
+
+    #[must_use]
+    pub fn tune_value(self, name: &str) -> Option<&'static str> {
+        match (self, name) {
+            (Self::Svt, "vq") => Some("0"),
+            (Self::Svt, "psnr") => Some("1"),
+            (Self::Svt, "ssim") => Some("2"),
+            (Self::Rav1e, "vq") => Some("Psychovisual"),
+            (Self::Rav1e, "psnr") => Some("Psnr"),
+            (Self::Aom, "psnr") => Some("psnr"),
+            (Self::Aom, "ssim") => Some("ssim"),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn tune_param(self, value: &str) -> String {
+        match self {
+            Self::Svt | Self::Rav1e => format!("--tune {value}"),
+            Self::Aom => format!("--tune={value}"),
+        }
+    }
+
+    #[must_use]
+    pub fn crf_param(self, crf: f32) -> String {
+        match self {
+            Self::Svt => format!("--crf {crf}"),
+            Self::Rav1e => {
+                format!("--quantizer {}", (crf * 255.0 / 63.0).round().clamp(0.0, 255.0) as u8)
+            }
+            Self::Aom => format!("--end-usage=q --cq-level={}", crf.round().clamp(0.0, 63.0) as u8),
+        }
+    }
+
+    #[must_use]
+    pub fn get_tune(self, params: &str) -> Option<String> {
+        get_flag(params, "--tune")
+    }
+
+    #[must_use]
+    pub fn get_threads(self, params: &str) -> Option<String> {
+        get_flag(params, self.lp_flag())
+    }
+
+    #[must_use]
+    pub fn get_rate_flag(self, params: &str) -> Option<&'static str> {
+        self.rate_flags().iter().copied().find(|flag| get_flag(params, flag).is_some())
+    }
+
+    const fn lossless_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Svt => &["--lossless", "1"],
+            Self::Rav1e => &["--quantizer", "0"],
+            Self::Aom => &["--lossless=1"],
+        }
+    }
+
+    const fn no_denoise_args(self) -> &'static [&'static str] {
+        match self {
+            Self::Svt => &["--film-grain-denoise", "0"],
+            Self::Rav1e => &[],
+            Self::Aom => &["--enable-dnl-denoising=0"],
+        }
+    }
+
+    const fn y4m(self) -> bool {
+        matches!(self, Self::Rav1e | Self::Aom)
+    }
+
+    const fn locked_flags(self) -> &'static [&'static str] {
+        match self {
+            Self::Svt => &[
+                "-i",
+                "-b",
+                "--input-depth",
+                "-w",
+                "--width",
+                "-h",
+                "--height",
+                "--forced-max-frame-width",
+                "--forced-max-frame-height",
+                "--fps-num",
+                "--fps-denom",
+                "--progress",
+                "--no-progress",
+            ],
+            Self::Rav1e => &["-o", "--output", "--quiet"],
+            Self::Aom => &["-o", "--output", "--ivf", "--passes", "--quiet"],
+        }
+    }
+
+    const fn rate_flags(self) -> &'static [&'static str] {
+        match self {
+            Self::Svt => &["--crf", "--rc"],
+            Self::Rav1e => &["--quantizer"],
+            Self::Aom => &["--end-usage", "--cq-level"],
+        }
+    }
+
+    const fn owned_flags(self) -> &'static [&'static str] {
+        match self {
+            Self::Svt => &[
+                "--keyint",
+                "--scd",
+                "--scm",
+                "--tile-columns",
+                "--tile-rows",
+                "--fgs-table",
+                "--color-primaries",
+                "--transfer-characteristics",
+                "--matrix-coefficients",
+                "--color-range",
+                "--chroma-sample-position",
+                "--mastering-display",
+                "--content-light",
+            ],
+            Self::Rav1e => &[
+                "--keyint",
+                "--no-scene-detection",
+                "--tile-cols",
+                "--tile-rows",
+                "--film-grain-table",
+                "--primaries",
+                "--transfer",
+                "--matrix",
+                "--range",
+                "--mastering-display",
+                "--content-light",
+            ],
+            Self::Aom => &[
+                "--enable-fwd-kf",
+                "--kf-max-dist",
+                "--disable-kf",
+                "--bit-depth",
+                "--tile-columns",
+                "--tile-rows",
+                "--film-grain-table",
+                "--color-primaries",
+                "--transfer-characteristics",
+                "--matrix-coefficients",
+            ],
+        }
+    }
+
+    fn is_locked(self, flag: &str, tq: bool) -> bool {
+        self.locked_flags().contains(&flag) || (tq && self.rate_flags().contains(&flag))
+    }
+
+    fn kept_raw(self, flag: &str, tq: bool) -> bool {
+        let grain = match self {
+            Self::Svt => "--fgs-table",
+            Self::Rav1e | Self::Aom => "--film-grain-table",
+        };
+        self.is_locked(flag, tq) || flag == grain || (self == Self::Aom && flag == "--bit-depth")
+    }
+}
+
+fn flag_groups<S: AsRef<std::ffi::OsStr>>(
+    tokens: impl IntoIterator<Item = S>,
+) -> Vec<(String, Vec<std::ffi::OsString>)> {
+    let mut groups: Vec<(String, Vec<std::ffi::OsString>)> = Vec::new();
+    for token in tokens {
+        let token = token.as_ref();
+        let text = token.to_string_lossy();
+        let is_flag = text.starts_with('-') && text.len() > 1 && text.parse::<f64>().is_err();
+        match groups.last_mut() {
+            Some((_, group)) if !is_flag => group.push(token.to_os_string()),
+            _ => {
+                let name = if is_flag { text.split('=').next().unwrap_or_default() } else { "" };
+                groups.push((name.to_string(), vec![token.to_os_string()]));
+            }
+        }
+    }
+    groups
+}
+
+fn chunk_params(global: &str, chunk: Option<&str>) -> String {
+    let Some(chunk) = chunk else { return global.to_string() };
+    let own = flag_groups(chunk.split_whitespace());
+    let flags: HashSet<String> = own.iter().map(|(flag, _)| flag.clone()).collect();
+    flag_groups(global.split_whitespace())
+        .into_iter()
+        .filter(|(flag, _)| flag.is_empty() || !flags.contains(flag))
+        .chain(own)
+        .flat_map(|(_, tokens)| tokens)
+        .map(|t| t.to_string_lossy().into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn warn_param_conflicts(args: &crate::Args) {
+    #[cfg(feature = "vship")]
+    let tq = args.target_quality.is_some();
+    #[cfg(not(feature = "vship"))]
+    let tq = false;
+    let tq = tq || args.lossless;
+
+    let enc = args.encoder;
+    let mut seen = HashSet::new();
+    for (flag, _) in flag_groups(args.params.split_whitespace()) {
+        if flag.is_empty() || !seen.insert(flag.clone()) {
+            continue;
+        }
+        if enc.is_locked(&flag, tq) {
+            args.out.warn(&format!("{flag} in -p is controlled by xav and will be ignored"));
+        } else if !args.raw_params
+            && (enc.owned_flags().contains(&flag.as_str())
+                || enc.rate_flags().contains(&flag.as_str()))
+        {
+            args.out.warn(&format!("{flag} in -p overrides the value set by xav"));
+        }
+    }
+}
+
+fn get_keyint(args: &crate::Args, inf: &VidInf) -> Option<usize> {
+    let keyint = args.keyint.as_deref()?;
+    if let Some(secs) = keyint.strip_suffix('s') {
+        let secs: f64 = secs.parse().ok()?;
+        Some((secs * f64::from(inf.fps_num) / f64::from(inf.fps_den)).round().max(1.0) as usize)
+    } else {
+        keyint.parse().ok()
+    }
+}
+
 struct ChunkData {
     idx: usize,
     frames: Vec<Vec<u8>>,
+    params: Option<String>,
+    /// Source frames of the chunk that failed to decode and are missing from `frames`.
+    dropped: Vec<usize>,
 }
 
 struct EncConfig<'a> {
+    args: &'a crate::Args,
     inf: &'a VidInf,
     params: &'a str,
     crf: f32,
@@ -52,18 +370,101 @@ struct EncConfig<'a> {
 }
 
 fn make_enc_cmd(cfg: &EncConfig, quiet: bool) -> Command {
-    let mut cmd = Command::new("SvtAv1EncApp");
+    let enc = cfg.args.encoder;
+    let base = match enc {
+        Encoder::Svt => make_svt_cmd(cfg, quiet),
+        Encoder::Rav1e => make_rav1e_cmd(cfg, quiet),
+        Encoder::Aom => make_aom_cmd(cfg, quiet),
+    };
+
+    let tq = cfg.crf >= 0.0 || cfg.args.lossless;
+    let user = flag_groups(cfg.params.split_whitespace());
+    let user_flags: HashSet<&str> = user.iter().map(|(flag, _)| flag.as_str()).collect();
+
+    let mut cmd = Command::new(base.get_program());
+    for (flag, tokens) in flag_groups(base.get_args()) {
+        if user_flags.contains(flag.as_str()) && !enc.is_locked(&flag, tq)
+            || cfg.args.raw_params && !flag.is_empty() && !enc.kept_raw(&flag, tq)
+        {
+            continue;
+        }
+        cmd.args(tokens);
+    }
+    for (flag, tokens) in user {
+        if !enc.is_locked(&flag, tq) {
+            cmd.args(tokens);
+        }
+    }
+    if cfg.args.lossless {
+        cmd.args(enc.lossless_args());
+    }
+    if cfg.args.no_denoise && cfg.grain_table.is_some() {
+        cmd.args(enc.no_denoise_args());
+    }
+
+    match cfg.args.encoder {
+        Encoder::Svt => cmd.arg("-b").arg(cfg.output),
+        Encoder::Rav1e | Encoder::Aom => cmd.arg("-o").arg(cfg.output).arg("-"),
+    };
+
+    cmd.stdin(Stdio::piped()).stderr(Stdio::piped());
+
+    cmd
+}
+
+struct TeeLog<R> {
+    inner: R,
+    log: Option<fs::File>,
+}
+
+impl<R: Read> Read for TeeLog<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(ref mut log) = self.log {
+            let _ = log.write_all(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+fn watch_stderr(
+    child: &mut Child,
+    prog: Option<&ProgsTrack>,
+    log: Option<PathBuf>,
+    idx: usize,
+    track_frames: bool,
+    crf_score: Option<(f32, Option<f64>)>,
+) {
+    let log = log.and_then(|path| fs::File::create(path).ok());
+    let Some(stderr) = child.stderr.take() else { return };
+
+    match (prog, log) {
+        (Some(p), log) => p.watch_enc(TeeLog { inner: stderr, log }, idx, track_frames, crf_score),
+        (None, Some(mut log)) => {
+            thread::spawn(move || {
+                let mut stderr = stderr;
+                let _ = std::io::copy(&mut stderr, &mut log);
+            });
+        }
+        (None, None) => child.stderr = Some(stderr),
+    }
+}
+
+fn make_svt_cmd(cfg: &EncConfig, quiet: bool) -> Command {
+    let mut cmd = Command::new(Encoder::Svt.binary());
 
     let width_str = cfg.inf.width.to_string();
     let height_str = cfg.inf.height.to_string();
     let fps_num_str = cfg.inf.fps_num.to_string();
     let fps_den_str = cfg.inf.fps_den.to_string();
+    let keyint_str =
+        get_keyint(cfg.args, cfg.inf).map_or_else(|| "-1".to_string(), |k| k.to_string());
 
     let base_args = [
         "-i",
         "stdin",
         "--input-depth",
-        "10",
+        if cfg.inf.out_10bit { "10" } else { "8" },
         "--width",
         &width_str,
         "--forced-max-frame-width",
@@ -77,7 +478,7 @@ fn make_enc_cmd(cfg: &EncConfig, quiet: bool) -> Command {
         "--fps-denom",
         &fps_den_str,
         "--keyint",
-        "-1",
+        &keyint_str,
         "--rc",
         "0",
         "--scd",
@@ -99,22 +500,49 @@ fn make_enc_cmd(cfg: &EncConfig, quiet: bool) -> Command {
 
     colorize(&mut cmd, cfg.inf);
 
-    let (tile_cols, tile_rows) = get_tile_params(cfg.inf.width, cfg.inf.height);
-    cmd.args(["--tile-columns", tile_cols, "--tile-rows", tile_rows]);
+    let (tile_cols, tile_rows) = get_tiles(cfg);
+    cmd.args(["--tile-columns", &tile_cols.to_string(), "--tile-rows", &tile_rows.to_string()]);
 
     if let Some(grain_path) = cfg.grain_table {
         cmd.arg("--fgs-table").arg(grain_path);
     }
 
+    if cfg.args.stats_report {
+        cmd.args(["--enable-stat-report", "1"]);
+    }
+
     if quiet {
         cmd.arg("--no-progress").arg("1");
     }
 
-    cmd.args(cfg.params.split_whitespace())
-        .arg("-b")
-        .arg(cfg.output)
-        .stdin(Stdio::piped())
-        .stderr(Stdio::piped());
+    cmd
+}
+
+fn make_rav1e_cmd(cfg: &EncConfig, quiet: bool) -> Command {
+    let mut cmd = Command::new(Encoder::Rav1e.binary());
+
+    let keyint = get_keyint(cfg.args, cfg.inf).unwrap_or(0).to_string();
+    cmd.args(["--keyint", &keyint, "--no-scene-detection"]);
+
+    if cfg.crf >= 0.0 {
+        let quantizer = (cfg.crf * 255.0 / 63.0).round().clamp(0.0, 255.0) as u8;
+        cmd.arg("--quantizer").arg(quantizer.to_string());
+    }
+
+    colorize_rav1e(&mut cmd, cfg.inf);
+
+    let (tile_cols, tile_rows) = get_tiles(cfg);
+    let tile_cols = (1u32 << tile_cols).to_string();
+    let tile_rows = (1u32 << tile_rows).to_string();
+    cmd.args(["--tile-cols", &tile_cols, "--tile-rows", &tile_rows]);
+
+    if let Some(grain_path) = cfg.grain_table {
+        cmd.arg("--film-grain-table").arg(grain_path);
+    }
+
+    if quiet {
+        cmd.arg("--quiet");
+    }
 
     cmd
 }
@@ -143,61 +571,334 @@ fn colorize(cmd: &mut Command, inf: &VidInf) {
     }
 }
 
-fn get_max_chunk_size(inf: &VidInf) -> usize {
+fn make_aom_cmd(cfg: &EncConfig, quiet: bool) -> Command {
+    let mut cmd = Command::new(Encoder::Aom.binary());
+
+    cmd.args(["--ivf", "--passes=1", "--enable-fwd-kf=0"]);
+    if let Some(keyint) = get_keyint(cfg.args, cfg.inf) {
+        cmd.arg(format!("--kf-max-dist={keyint}"));
+    } else {
+        cmd.arg("--disable-kf");
+    }
+    cmd.arg(if cfg.inf.out_10bit { "--bit-depth=10" } else { "--bit-depth=8" });
+    if cfg.inf.gray {
+        cmd.arg("--monochrome");
+    }
+
+    if cfg.crf >= 0.0 {
+        let cq_level = cfg.crf.round().clamp(0.0, 63.0) as u8;
+        if let Some(kbps) = cfg.args.max_bitrate {
+            cmd.arg("--end-usage=cq").arg(format!("--target-bitrate={kbps}"));
+        } else {
+            cmd.arg("--end-usage=q");
+        }
+        cmd.arg(format!("--cq-level={cq_level}"));
+    }
+
+    colorize_aom(&mut cmd, cfg.inf);
+
+    let (tile_cols, tile_rows) = get_tiles(cfg);
+    cmd.arg(format!("--tile-columns={tile_cols}")).arg(format!("--tile-rows={tile_rows}"));
+
+    if let Some(grain_path) = cfg.grain_table {
+        cmd.arg(format!("--film-grain-table={}", grain_path.display()));
+    }
+
+    if quiet {
+        cmd.arg("--quiet");
+    }
+
+    cmd
+}
+
+fn colorize_aom(cmd: &mut Command, inf: &VidInf) {
+    let primaries = inf.color_primaries.and_then(|v| match v {
+        1 => Some("bt709"),
+        4 => Some("bt470m"),
+        5 => Some("bt470bg"),
+        6 => Some("bt601"),
+        7 => Some("smpte240"),
+        8 => Some("film"),
+        9 => Some("bt2020"),
+        10 => Some("xyz"),
+        11 => Some("smpte431"),
+        12 => Some("smpte432"),
+        22 => Some("ebu3213"),
+        _ => None,
+    });
+    let transfer = inf.transfer_characteristics.and_then(|v| match v {
+        1 => Some("bt709"),
+        4 => Some("bt470m"),
+        5 => Some("bt470bg"),
+        6 => Some("bt601"),
+        7 => Some("smpte240"),
+        8 => Some("lin"),
+        9 => Some("log100"),
+        10 => Some("log100sq10"),
+        11 => Some("iec61966"),
+        12 => Some("bt1361"),
+        13 => Some("srgb"),
+        14 => Some("bt2020-10bit"),
+        15 => Some("bt2020-12bit"),
+        16 => Some("smpte2084"),
+        17 => Some("smpte428"),
+        18 => Some("hlg"),
+        _ => None,
+    });
+    let matrix = inf.matrix_coefficients.and_then(|v| match v {
+        0 => Some("identity"),
+        1 => Some("bt709"),
+        4 => Some("fcc73"),
+        5 => Some("bt470bg"),
+        6 => Some("bt601"),
+        7 => Some("smpte240"),
+        8 => Some("ycgco"),
+        9 => Some("bt2020ncl"),
+        10 => Some("bt2020cl"),
+        11 => Some("smpte2085"),
+        12 => Some("chromncl"),
+        13 => Some("chromcl"),
+        14 => Some("ictcp"),
+        _ => None,
+    });
+
+    if let Some(cp) = primaries {
+        cmd.arg(format!("--color-primaries={cp}"));
+    }
+    if let Some(tc) = transfer {
+        cmd.arg(format!("--transfer-characteristics={tc}"));
+    }
+    if let Some(mc) = matrix {
+        cmd.arg(format!("--matrix-coefficients={mc}"));
+    }
+}
+
+fn colorize_rav1e(cmd: &mut Command, inf: &VidInf) {
+    let primaries = inf.color_primaries.and_then(|v| match v {
+        1 => Some("BT709"),
+        4 => Some("BT470M"),
+        5 => Some("BT470BG"),
+        6 => Some("BT601"),
+        7 => Some("SMPTE240"),
+        8 => Some("GenericFilm"),
+        9 => Some("BT2020"),
+        10 => Some("XYZ"),
+        11 => Some("SMPTE431"),
+        12 => Some("SMPTE432"),
+        22 => Some("EBU3213"),
+        _ => None,
+    });
+    let transfer = inf.transfer_characteristics.and_then(|v| match v {
+        1 => Some("BT709"),
+        4 => Some("BT470M"),
+        5 => Some("BT470BG"),
+        6 => Some("BT601"),
+        7 => Some("SMPTE240"),
+        8 => Some("Linear"),
+        9 => Some("Log100"),
+        10 => Some("Log100Sqrt10"),
+        11 => Some("IEC61966"),
+        12 => Some("BT1361"),
+        13 => Some("SRGB"),
+        14 => Some("BT2020_10Bit"),
+        15 => Some("BT2020_12Bit"),
+        16 => Some("SMPTE2084"),
+        17 => Some("SMPTE428"),
+        18 => Some("HLG"),
+        _ => None,
+    });
+    let matrix = inf.matrix_coefficients.and_then(|v| match v {
+        0 => Some("Identity"),
+        1 => Some("BT709"),
+        4 => Some("FCC"),
+        5 => Some("BT470BG"),
+        6 => Some("BT601"),
+        7 => Some("SMPTE240"),
+        8 => Some("YCgCo"),
+        9 => Some("BT2020NCL"),
+        10 => Some("BT2020CL"),
+        11 => Some("SMPTE2085"),
+        12 => Some("ChromatNCL"),
+        13 => Some("ChromatCL"),
+        14 => Some("ICtCp"),
+        _ => None,
+    });
+
+    if let Some(cp) = primaries {
+        cmd.args(["--primaries", cp]);
+    }
+    if let Some(tc) = transfer {
+        cmd.args(["--transfer", tc]);
+    }
+    if let Some(mc) = matrix {
+        cmd.args(["--matrix", mc]);
+    }
+    if let Some(cr) = inf.color_range {
+        cmd.args(["--range", if cr == 1 { "full" } else { "limited" }]);
+    }
+    if let Some(ref md) = inf.mastering_display {
+        cmd.args(["--mastering-display", md]);
+    }
+    if let Some(ref cl) = inf.content_light {
+        cmd.args(["--content-light", cl]);
+    }
+}
+
+fn y4m_header(inf: &VidInf) -> String {
+    let (csp, range) = (
+        if inf.out_10bit { "C420p10 XYSCSS=420P10" } else { "C420jpeg XYSCSS=420JPEG" },
+        if inf.color_range == Some(1) { "FULL" } else { "LIMITED" },
+    );
+    format!(
+        "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 {csp} XCOLORRANGE={range}\n",
+        inf.width, inf.height, inf.fps_num, inf.fps_den
+    )
+}
+
+pub fn get_max_chunk_size(inf: &VidInf) -> usize {
     ((inf.fps_num * 10 + inf.fps_den / 2) / inf.fps_den).min(300) as usize
 }
 
+fn buffer_len(chunks: &[Chunk], inf: &VidInf) -> usize {
+    chunks.iter().map(|c| c.end - c.start).max().unwrap_or(0).max(get_max_chunk_size(inf))
+}
+
+/// Set to stop handing out new chunks. Chunks already being encoded still finish.
+pub static STOP: AtomicBool = AtomicBool::new(false);
+
+/// Drains the pipeline after a worker failed, so its error can be returned.
+fn stop_all(_: &XavError) {
+    STOP.store(true, Ordering::Relaxed);
+}
+
+fn spawn_err(encoder: Encoder, e: &std::io::Error) -> XavError {
+    XavError::Encode(format!("Failed to start {}: {e}", encoder.binary()))
+}
+
+fn send_chunk(tx: &Sender<ChunkData>, mut data: ChunkData) -> bool {
+    loop {
+        match tx.send_timeout(data, Duration::from_millis(100)) {
+            Ok(()) => return true,
+            Err(SendTimeoutError::Timeout(d)) if !STOP.load(Ordering::Relaxed) => data = d,
+            Err(_) => return false,
+        }
+    }
+}
+
 fn dec_10bit(
     chunks: &[Chunk],
     source: *mut std::ffi::c_void,
     inf: &VidInf,
     tx: &Sender<ChunkData>,
-) {
+    warn: &Sink,
+) -> Result<usize, XavError> {
     let frame_size = calc_10bit_size(inf);
     let packed_size = calc_packed_size(inf);
     let mut frame_buf = vec![0u8; frame_size];
 
-    let max_chunk_size = get_max_chunk_size(inf);
+    let max_chunk_size = buffer_len(chunks, inf);
     let mut frames_buffer: Vec<Vec<u8>> =
         (0..max_chunk_size).map(|_| vec![0u8; packed_size]).collect();
-
+    #[cfg(feature = "vship")]
+    let mut tonemapper = inf
+        .tonemap
+        .as_ref()
+        .map(|tm| crate::zimg::Tonemapper::new(inf.width, inf.height, tm))
+        .transpose()
+        .map_err(|e| XavError::Decode(format!("Failed to set up tone mapping: {e}")))?;
+
+    let mut decoded = 0;
     for chunk in chunks {
+        if STOP.load(Ordering::Relaxed) {
+            break;
+        }
         let mut valid = 0;
-
-        for (i, idx) in (chunk.start..chunk.end).enumerate() {
-            if extr_10bit(source, idx, &mut frame_buf).is_err() {
-                continue;
+        let mut dropped = Vec::new();
+
+        for idx in chunk.start..chunk.end {
+            match extr_10bit(source, idx, inf, &mut frame_buf) {
+                Ok(()) => {}
+                Err(FrameErr::Get(_)) => {
+                    dropped.push(idx);
+                    continue;
+                }
+                Err(FrameErr::Dims(e)) => return Err(e),
+            }
+            #[cfg(feature = "vship")]
+            if let Some(tm) = tonemapper.as_mut() {
+                tm.apply(&mut frame_buf).map_err(|e| {
+                    XavError::Decode(format!("Failed to tone map frame {idx}: {e}"))
+                })?;
             }
 
-            pack_10bit(&frame_buf, &mut frames_buffer[i]);
+            pack_10bit(&frame_buf, &mut frames_buffer[valid]);
             valid += 1;
         }
 
-        if valid > 0 {
-            tx.send(ChunkData { idx: chunk.idx, frames: frames_buffer[..valid].to_vec() }).ok();
+        if valid == 0 {
+            warn.warn(&format!("Chunk {} produced no frames", chunk.idx));
+        } else if !send_chunk(
+            tx,
+            ChunkData {
+                idx: chunk.idx,
+                frames: frames_buffer[..valid].to_vec(),
+                params: chunk.params.clone(),
+                dropped,
+            },
+        ) {
+            break;
         }
+        decoded += valid;
     }
+
+    Ok(decoded)
 }
 
-fn dec_8bit(chunks: &[Chunk], source: *mut std::ffi::c_void, inf: &VidInf, tx: &Sender<ChunkData>) {
-    let max_chunk_size = get_max_chunk_size(inf);
+fn dec_8bit(
+    chunks: &[Chunk],
+    source: *mut std::ffi::c_void,
+    inf: &VidInf,
+    tx: &Sender<ChunkData>,
+    warn: &Sink,
+) -> Result<usize, XavError> {
+    let max_chunk_size = buffer_len(chunks, inf);
     let frame_size = calc_8bit_size(inf);
     let mut frames_buffer: Vec<Vec<u8>> =
         (0..max_chunk_size).map(|_| vec![0u8; frame_size]).collect();
 
+    let mut decoded = 0;
     for chunk in chunks {
+        if STOP.load(Ordering::Relaxed) {
+            break;
+        }
         let mut valid = 0;
+        let mut dropped = Vec::new();
 
-        for (i, idx) in (chunk.start..chunk.end).enumerate() {
-            if extr_8bit(source, idx, &mut frames_buffer[i]).is_ok() {
-                valid += 1;
+        for idx in chunk.start..chunk.end {
+            match extr_8bit(source, idx, inf, &mut frames_buffer[valid]) {
+                Ok(()) => valid += 1,
+                Err(FrameErr::Get(_)) => dropped.push(idx),
+                Err(FrameErr::Dims(e)) => return Err(e),
             }
         }
 
-        if valid > 0 {
-            tx.send(ChunkData { idx: chunk.idx, frames: frames_buffer[..valid].to_vec() }).ok();
+        if valid == 0 {
+            warn.warn(&format!("Chunk {} produced no frames", chunk.idx));
+        } else if !send_chunk(
+            tx,
+            ChunkData {
+                idx: chunk.idx,
+                frames: frames_buffer[..valid].to_vec(),
+                params: chunk.params.clone(),
+                dropped,
+            },
+        ) {
+            break;
         }
+        decoded += valid;
     }
+
+    Ok(decoded)
 }
 
 fn decode_chunks(
@@ -206,42 +907,83 @@ fn decode_chunks(
     inf: &VidInf,
     tx: &Sender<ChunkData>,
     skip_indices: &HashSet<usize>,
-) {
+    warn: &Sink,
+) -> Result<(), XavError> {
     let threads =
         std::thread::available_parallelism().map_or(8, |n| n.get().try_into().unwrap_or(8));
-    let Ok(source) = thr_vid_src(idx, threads) else { return };
+    let source = thr_vid_src(idx, threads).inspect_err(stop_all)?;
     let filtered: Vec<Chunk> =
         chunks.iter().filter(|c| !skip_indices.contains(&c.idx)).cloned().collect();
 
-    if inf.is_10bit {
-        dec_10bit(&filtered, source, inf, tx);
+    let decoded = if inf.is_10bit {
+        dec_10bit(&filtered, source, inf, tx, warn)
     } else {
-        dec_8bit(&filtered, source, inf, tx);
-    }
+        dec_8bit(&filtered, source, inf, tx, warn)
+    };
 
     destroy_vid_src(source);
+    let decoded = decoded.inspect_err(stop_all)?;
+
+    let expected: usize = filtered.iter().map(|c| c.end - c.start).sum();
+    if decoded != expected && !STOP.load(Ordering::Relaxed) {
+        warn.warn(&format!(
+            "Decoded {decoded} frames but expected {expected}. The source frame count may be \
+             wrong, try --frames"
+        ));
+    }
+    Ok(())
+}
+
+fn conv_frame<'a>(
+    frame: &'a [u8],
+    inf: &VidInf,
+    buf: &'a mut [u8],
+    buf_8bit: &'a mut [u8],
+) -> &'a [u8] {
+    match (inf.is_10bit, inf.out_10bit) {
+        (true, true) => {
+            unpack_10bit(frame, buf);
+            buf
+        }
+        (true, false) => {
+            unpack_10bit(frame, buf);
+            conv_to_8bit(buf, buf_8bit, inf);
+            buf_8bit
+        }
+        (false, true) => {
+            conv_to_10bit(frame, buf);
+            buf
+        }
+        (false, false) => frame,
+    }
 }
 
 fn write_frames(
     child: &mut std::process::Child,
     frames: Vec<Vec<u8>>,
     inf: &VidInf,
+    encoder: Encoder,
     conversion_buf: &mut Option<Vec<u8>>,
 ) -> usize {
     let Some(mut stdin) = child.stdin.take() else {
         return 0;
     };
 
+    if encoder.y4m() && stdin.write_all(y4m_header(inf).as_bytes()).is_err() {
+        return 0;
+    }
+
+    let mut buf_8bit =
+        if inf.is_10bit && !inf.out_10bit { vec![0u8; calc_8bit_size(inf)] } else { Vec::new() };
     let mut written = 0;
 
     for frame in frames {
+        if encoder.y4m() && stdin.write_all(b"FRAME\n").is_err() {
+            break;
+        }
+
         let result = if let Some(buf) = conversion_buf {
-            if inf.is_10bit {
-                unpack_10bit(&frame, buf);
-            } else {
-                conv_to_10bit(&frame, buf);
-            }
-            stdin.write_all(buf)
+            stdin.write_all(conv_frame(&frame, inf, buf, &mut buf_8bit))
         } else {
             stdin.write_all(&frame)
         };
@@ -255,7 +997,56 @@ fn write_frames(
     written
 }
 
+/// Kills the encoder when feeding it frames takes past its deadline. It is stopped before the
+/// child is waited on, so the pid it kills always still belongs to the child.
+struct Watchdog {
+    done: Sender<()>,
+    fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    fn start(child: &Child, limit: Duration) -> Self {
+        let (done, rx) = bounded::<()>(1);
+        let fired = Arc::new(AtomicBool::new(false));
+        let pid = libc::pid_t::try_from(child.id()).unwrap_or(0);
+        let flag = Arc::clone(&fired);
+        thread::spawn(move || {
+            if pid > 0 && rx.recv_timeout(limit) == Err(RecvTimeoutError::Timeout) {
+                flag.store(true, Ordering::Relaxed);
+                unsafe {
+                    libc::kill(pid, libc::SIGKILL);
+                }
+            }
+        });
+        Self { done, fired }
+    }
+
+    fn stop(self) -> bool {
+        let _ = self.done.send(());
+        self.fired.load(Ordering::Relaxed)
+    }
+}
+
+/// Waits for the encoder, killing it once `deadline` passes. Returns `None` if it was killed.
+fn wait_until(
+    child: &mut Child,
+    deadline: Instant,
+) -> std::io::Result<Option<std::process::ExitStatus>> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
 struct ProcConfig<'a> {
+    args: &'a crate::Args,
     inf: &'a VidInf,
     params: &'a str,
     quiet: bool,
@@ -268,31 +1059,57 @@ fn proc_chunk(
     config: &ProcConfig,
     prog: Option<&ProgsTrack>,
     conversion_buf: &mut Option<Vec<u8>>,
-) -> (usize, Option<ChunkComp>) {
+) -> Result<(usize, Option<ChunkComp>), XavError> {
     let output = config.work_dir.join("encode").join(format!("{:04}.ivf", data.idx));
+    let params = chunk_params(config.params, data.params.as_deref());
     let enc_cfg = EncConfig {
+        args: config.args,
         inf: config.inf,
-        params: config.params,
+        params: &params,
         crf: -1.0,
         output: &output,
         grain_table: config.grain_table,
     };
     let mut cmd = make_enc_cmd(&enc_cfg, config.quiet);
-    let mut child = cmd.spawn().unwrap_or_else(|_| std::process::exit(1));
+    let mut child = cmd.spawn().map_err(|e| spawn_err(config.args.encoder, &e))?;
 
-    if !config.quiet
-        && let Some(stderr) = child.stderr.take()
-        && let Some(p) = prog
-    {
-        p.watch_enc(stderr, data.idx, true, None);
-    }
+    let log =
+        config.args.keep.then(|| config.work_dir.join("logs").join(format!("{:04}.log", data.idx)));
+    watch_stderr(&mut child, prog.filter(|_| !config.quiet), log, data.idx, true, None);
 
     let frame_count = data.frames.len();
-    let written = write_frames(&mut child, data.frames, config.inf, conversion_buf);
-
-    let status = child.wait().unwrap();
+    let deadline = config.args.chunk_timeout.map(|limit| (Instant::now() + limit, limit));
+    let watchdog = deadline.map(|(_, limit)| Watchdog::start(&child, limit));
+    let written =
+        write_frames(&mut child, data.frames, config.inf, config.args.encoder, conversion_buf);
+
+    let killed = watchdog.is_some_and(Watchdog::stop);
+    let status = match deadline {
+        Some(_) if killed => {
+            let _ = child.wait();
+            None
+        }
+        Some((deadline, _)) => wait_until(&mut child, deadline)?,
+        None => Some(child.wait()?),
+    };
+    let Some(status) = status else {
+        config.args.out.warn(&format!(
+            "Chunk {} passed --chunk-timeout and was killed, rerun with -r to encode it",
+            data.idx
+        ));
+        let _ = fs::remove_file(&output);
+        return Ok((0, None));
+    };
     if !status.success() {
-        std::process::exit(1);
+        if STOP.load(Ordering::Relaxed) {
+            let _ = fs::remove_file(&output);
+            return Ok((0, None));
+        }
+        return Err(XavError::Encode(format!(
+            "{} failed on chunk {} ({status})",
+            config.args.encoder.binary(),
+            data.idx
+        )));
     }
 
     let completion = std::fs::metadata(&output).ok().map(|metadata| ChunkComp {
@@ -300,13 +1117,19 @@ fn proc_chunk(
         frames: frame_count,
         size: metadata.len(),
     });
+    if completion.is_some() {
+        crate::chunk::save_dropped(config.work_dir, &data.dropped)?;
+    }
 
-    (written, completion)
+    Ok((written, completion))
 }
 
 struct WorkerCtx<'a> {
+    args: &'a crate::Args,
     quiet: bool,
     grain_table: Option<&'a Path>,
+    id: usize,
+    throttle: Option<&'a Throttle>,
 }
 
 fn run_worker(
@@ -314,48 +1137,248 @@ fn run_worker(
     inf: &VidInf,
     params: &str,
     ctx: &WorkerCtx,
-    stats: Option<&Arc<WorkerStats>>,
+    stats: &Arc<WorkerStats>,
     prog: Option<&Arc<ProgsTrack>>,
     work_dir: &Path,
-) {
+) -> Result<(), XavError> {
     let mut conversion_buf = Some(vec![0u8; calc_10bit_size(inf)]);
 
-    while let Ok(data) = rx.recv() {
-        let config =
-            ProcConfig { inf, params, quiet: ctx.quiet, work_dir, grain_table: ctx.grain_table };
+    loop {
+        if let Some(throttle) = ctx.throttle {
+            throttle.wait(ctx.id);
+        }
+        let Ok(data) = rx.recv() else { break };
+
+        let config = ProcConfig {
+            args: ctx.args,
+            inf,
+            params,
+            quiet: ctx.quiet,
+            work_dir,
+            grain_table: ctx.grain_table,
+        };
         let (written, completion) =
-            proc_chunk(data, &config, prog.map(AsRef::as_ref), &mut conversion_buf);
+            proc_chunk(data, &config, prog.map(AsRef::as_ref), &mut conversion_buf)
+                .inspect_err(stop_all)?;
+
+        if let Some(comp) = completion {
+            stats.completed.fetch_add(1, Ordering::Relaxed);
+            stats.add_completion(comp, written, work_dir);
+        } else {
+            stats.frames_done.fetch_add(written, Ordering::Relaxed);
+        }
+
+        if let Some(throttle) = ctx.throttle {
+            throttle.finish_chunk(written);
+        }
+    }
+    Ok(())
+}
+
+struct ThrottleState {
+    active: usize,
+    grow: bool,
+    since: Instant,
+    frames: usize,
+    chunks: usize,
+    last_fps: f64,
+}
+
+struct Throttle {
+    max: usize,
+    state: std::sync::Mutex<ThrottleState>,
+    cvar: std::sync::Condvar,
+}
+
+impl Throttle {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            state: std::sync::Mutex::new(ThrottleState {
+                active: max.div_ceil(2),
+                grow: true,
+                since: Instant::now(),
+                frames: 0,
+                chunks: 0,
+                last_fps: 0.0,
+            }),
+            cvar: std::sync::Condvar::new(),
+        }
+    }
+
+    fn wait(&self, id: usize) {
+        let state = self.state.lock().unwrap();
+        drop(self.cvar.wait_while(state, |s| id >= s.active).unwrap());
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn finish_chunk(&self, frames: usize) {
+        let mut s = self.state.lock().unwrap();
+        s.frames += frames;
+        s.chunks += 1;
+        if s.chunks < s.active {
+            return;
+        }
+
+        let fps = s.frames as f64 / s.since.elapsed().as_secs_f64().max(0.001);
+        if fps < s.last_fps * 0.97 {
+            s.grow = !s.grow;
+        }
+        let next = if s.grow { s.active + 1 } else { s.active - 1 }.clamp(1, self.max);
+        if next == s.active {
+            s.grow = !s.grow;
+        }
+
+        s.active = next;
+        s.last_fps = fps;
+        s.frames = 0;
+        s.chunks = 0;
+        s.since = Instant::now();
+        drop(s);
+        self.cvar.notify_all();
+    }
+
+    fn release(&self) {
+        self.state.lock().unwrap().active = self.max;
+        self.cvar.notify_all();
+    }
+}
 
-        if let Some(s) = stats {
-            s.completed.fetch_add(1, Ordering::Relaxed);
-            s.frames_done.fetch_add(written, Ordering::Relaxed);
+const HOOK_QUEUE: usize = 64;
 
-            if let Some(comp) = completion {
-                s.add_completion(comp, work_dir);
+/// Runs `--on-chunk` off the workers, one hook at a time. A full queue drops the call instead of
+/// holding up encoding.
+struct ChunkHook {
+    tx: Option<Sender<(ChunkComp, PathBuf)>>,
+    handle: Option<thread::JoinHandle<()>>,
+    warn: Sink,
+}
+
+impl ChunkHook {
+    fn new(cmd: String, warn: Sink) -> Self {
+        let (tx, rx) = bounded::<(ChunkComp, PathBuf)>(HOOK_QUEUE);
+        let hook_warn = warn.clone();
+        let handle = thread::spawn(move || {
+            for (comp, path) in rx {
+                let (idx, frames, size) =
+                    (comp.idx.to_string(), comp.frames.to_string(), comp.size.to_string());
+                let status = Command::new("sh")
+                    .arg("-c")
+                    .arg(&cmd)
+                    .arg("xav")
+                    .args([&idx, &frames, &size])
+                    .arg(&path)
+                    .env("XAV_CHUNK", &idx)
+                    .env("XAV_FRAMES", &frames)
+                    .env("XAV_SIZE", &size)
+                    .env("XAV_OUTPUT", &path)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::null())
+                    .stderr(Stdio::null())
+                    .status();
+                if !status.is_ok_and(|s| s.success()) {
+                    hook_warn.warn(&format!("--on-chunk failed for chunk {idx}"));
+                }
             }
+        });
+        Self { tx: Some(tx), handle: Some(handle), warn }
+    }
+
+    fn send(&self, comp: ChunkComp, path: PathBuf) {
+        if let Some(ref tx) = self.tx
+            && tx.try_send((comp, path)).is_err()
+        {
+            self.warn.warn(&format!("--on-chunk is falling behind, skipped chunk {}", comp.idx));
+        }
+    }
+}
+
+impl Drop for ChunkHook {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
         }
     }
 }
 
 struct WorkerStats {
     completed: Arc<AtomicUsize>,
-    frames_done: AtomicUsize,
+    frames_done: Arc<AtomicUsize>,
     completions: Arc<std::sync::Mutex<ResumeInf>>,
+    partial: Option<std::sync::Mutex<PartialOut>>,
+    compact: Option<(u64, std::sync::Mutex<PartialOut>)>,
+    hook: Option<ChunkHook>,
+    in_order: Option<std::sync::Mutex<InOrderOut>>,
 }
 
 impl WorkerStats {
-    fn new(initial_completed: usize, init_frames: usize, initial_data: ResumeInf) -> Self {
+    fn new(initial_data: ResumeInf, args: &crate::Args, work_dir: &Path) -> Self {
+        let initial_completed = initial_data.chnks_done.len();
+        let init_frames = initial_data.chnks_done.iter().map(|c| c.frames).sum();
+
+        let compact = args.max_workdir.map(|cap| {
+            let out = PartialOut::resume(
+                prefix_path(work_dir),
+                work_dir.join("encode"),
+                work_dir.join("prefix.txt"),
+            );
+            out.prune();
+            (cap, std::sync::Mutex::new(out))
+        });
+
+        let partial = args.partial.then(|| {
+            let mut out = PartialOut::new(partial_path(&args.output), work_dir.join("encode"));
+            if let Some((_, ref prefix)) = compact {
+                let _ = out.seed(&prefix.lock().unwrap());
+            }
+            let _ = out.append_ready(&initial_data.chnks_done);
+            std::sync::Mutex::new(out)
+        });
+
+        let in_order = args.in_order.as_deref().and_then(|path| {
+            let mut out = InOrderOut::new(path, work_dir.join("encode"), args.out.clone())
+                .inspect_err(|e| args.out.warn(&format!("Cannot open {}: {e}", path.display())))
+                .ok()?;
+            for comp in &initial_data.chnks_done {
+                out.push(comp.idx);
+            }
+            Some(std::sync::Mutex::new(out))
+        });
+
         Self {
             completed: Arc::new(AtomicUsize::new(initial_completed)),
-            frames_done: AtomicUsize::new(init_frames),
+            frames_done: Arc::new(AtomicUsize::new(init_frames)),
             completions: Arc::new(std::sync::Mutex::new(initial_data)),
+            partial,
+            compact,
+            hook: args.on_chunk.clone().map(|cmd| ChunkHook::new(cmd, args.out.clone())),
+            in_order,
         }
     }
 
-    fn add_completion(&self, completion: ChunkComp, work_dir: &Path) {
+    fn add_completion(&self, completion: ChunkComp, written: usize, work_dir: &Path) {
+        if let Some(ref hook) = self.hook {
+            let path = work_dir.join("encode").join(format!("{:04}.ivf", completion.idx));
+            hook.send(completion, path);
+        }
         let mut data = self.completions.lock().unwrap();
         data.chnks_done.push(completion);
+        self.frames_done.fetch_add(written, Ordering::Relaxed);
         let _ = save_resume(&data, work_dir);
+        if let Some(ref partial) = self.partial {
+            let _ = partial.lock().unwrap().append_ready(&data.chnks_done);
+        }
+        if let Some(ref in_order) = self.in_order {
+            in_order.lock().unwrap().push(completion.idx);
+        }
+        if let Some((cap, ref prefix)) = self.compact
+            && dir_size(&work_dir.join("encode")) > cap
+        {
+            let mut prefix = prefix.lock().unwrap();
+            let _ = prefix.append_ready(&data.chnks_done);
+            prefix.prune();
+        }
         drop(data);
     }
 }
@@ -367,7 +1390,7 @@ pub fn encode_all(
     idx: &Arc<VidIdx>,
     work_dir: &Path,
     grain_table: Option<&PathBuf>,
-) {
+) -> Result<(), XavError> {
     let resume_data = if args.resume {
         get_resume(work_dir).unwrap_or(ResumeInf { chnks_done: Vec::new() })
     } else {
@@ -378,32 +1401,32 @@ pub fn encode_all(
     {
         let is_tq = args.target_quality.is_some() && args.qp_range.is_some();
         if is_tq {
-            encode_tq(chunks, inf, args, idx, work_dir, grain_table);
-            return;
+            return encode_tq(chunks, inf, args, idx, work_dir, grain_table);
         }
     }
 
     let skip_indices: HashSet<usize> = resume_data.chnks_done.iter().map(|c| c.idx).collect();
-    let completed_count = skip_indices.len();
-    let completed_frames: usize = resume_data.chnks_done.iter().map(|c| c.frames).sum();
 
-    let stats = if args.quiet {
-        None
-    } else {
-        Some(Arc::new(WorkerStats::new(completed_count, completed_frames, resume_data)))
-    };
+    let stats = Arc::new(WorkerStats::new(resume_data, args, work_dir));
 
     let prog = if args.quiet {
         None
     } else {
-        Some(Arc::new(ProgsTrack::new(
-            chunks,
-            inf,
-            args.worker,
-            completed_frames,
-            Arc::clone(&stats.as_ref().unwrap().completed),
-            Arc::clone(&stats.as_ref().unwrap().completions),
-        )))
+        Some(Arc::new(
+            ProgsTrack::new(
+                chunks,
+                inf,
+                args.worker,
+                Arc::clone(&stats.completed),
+                Arc::clone(&stats.completions),
+                Arc::clone(&stats.frames_done),
+                &args.out,
+            )
+            .with_fifo(args.progress_fifo.as_deref())
+            .with_stats(args.stats_report.then_some(work_dir))
+            .with_refresh(args.refresh_ms)
+            .with_heartbeat(args.heartbeat),
+        ))
     };
 
     let buffer_size = 0;
@@ -414,40 +1437,143 @@ pub fn encode_all(
         let chunks = chunks.to_vec();
         let idx = Arc::clone(idx);
         let inf = inf.clone();
-        thread::spawn(move || decode_chunks(&chunks, &idx, &inf, &tx, &skip_indices))
+        let warn = args.out.clone();
+        thread::spawn(move || decode_chunks(&chunks, &idx, &inf, &tx, &skip_indices, &warn))
     };
 
+    let throttle = args.adaptive_workers.then(|| Arc::new(Throttle::new(args.worker)));
+
     let mut workers = Vec::new();
     let quiet = args.quiet;
-    for _ in 0..args.worker {
+    for id in 0..args.worker {
         let rx = Arc::clone(&rx);
         let inf = inf.clone();
         let params = args.params.clone();
-        let stats = stats.clone();
+        let stats = Arc::clone(&stats);
         let prog = prog.clone();
         let grain = grain_table.cloned();
         let work_dir = work_dir.to_path_buf();
+        let args = args.clone();
+        let throttle = throttle.clone();
 
         let handle = thread::spawn(move || {
-            let ctx = WorkerCtx { quiet, grain_table: grain.as_deref() };
-            run_worker(&rx, &inf, &params, &ctx, stats.as_ref(), prog.as_ref(), &work_dir);
+            let ctx = WorkerCtx {
+                args: &args,
+                quiet,
+                grain_table: grain.as_deref(),
+                id,
+                throttle: throttle.as_deref(),
+            };
+            run_worker(&rx, &inf, &params, &ctx, &stats, prog.as_ref(), &work_dir)
         });
         workers.push(handle);
     }
 
-    decoder.join().unwrap();
+    let dec_res = decoder.join().unwrap();
 
-    for handle in workers {
-        handle.join().unwrap();
+    if let Some(ref throttle) = throttle {
+        throttle.release();
     }
 
+    let results: Vec<Result<(), XavError>> =
+        workers.into_iter().map(|handle| handle.join().unwrap()).collect();
+
     if let Some(ref p) = prog {
         p.final_update();
     }
+    dec_res?;
+    results.into_iter().collect()
+}
+
+pub fn encode_stream<R: std::io::BufRead>(
+    reader: &mut crate::y4m::Y4mReader<R>,
+    cuts: &[(usize, Option<String>)],
+    max_len: usize,
+    args: &crate::Args,
+    work_dir: &Path,
+    grain_table: Option<&PathBuf>,
+) -> Result<Vec<Chunk>, XavError> {
+    let stats = Arc::new(WorkerStats::new(ResumeInf { chnks_done: Vec::new() }, args, work_dir));
+    let (tx, rx) = bounded::<ChunkData>(0);
+    let rx = Arc::new(rx);
+
+    let worker = {
+        let inf = reader.inf.clone();
+        let stats = Arc::clone(&stats);
+        let grain = grain_table.cloned();
+        let work_dir = work_dir.to_path_buf();
+        let args = args.clone();
+        thread::spawn(move || {
+            let ctx = WorkerCtx {
+                args: &args,
+                quiet: args.quiet,
+                grain_table: grain.as_deref(),
+                id: 0,
+                throttle: None,
+            };
+            run_worker(&rx, &inf, &args.params, &ctx, &stats, None, &work_dir)
+        })
+    };
+
+    let mut chunks = Vec::new();
+    let mut frames = Vec::new();
+    let mut params = None;
+    let mut cuts = cuts.iter().peekable();
+    let mut pos = 0;
+
+    let mut flush = |frames: &mut Vec<Vec<u8>>, params: &Option<String>, end: usize| {
+        if frames.is_empty() || STOP.load(Ordering::Relaxed) {
+            return;
+        }
+        let idx = chunks.len();
+        chunks.push(Chunk { idx, start: end - frames.len(), end, params: params.clone() });
+        send_chunk(
+            &tx,
+            ChunkData {
+                idx,
+                frames: std::mem::take(frames),
+                params: params.clone(),
+                dropped: Vec::new(),
+            },
+        );
+    };
+
+    let res = loop {
+        if STOP.load(Ordering::Relaxed) {
+            break Ok(());
+        }
+        match reader.next_frame() {
+            Ok(Some(frame)) => {
+                while cuts.next_if(|&&(f, _)| f < pos).is_some() {}
+                if let Some((_, cut_params)) = cuts.next_if(|&&(f, _)| f == pos) {
+                    flush(&mut frames, &params, pos);
+                    params.clone_from(cut_params);
+                } else if frames.len() >= max_len {
+                    flush(&mut frames, &params, pos);
+                }
+                frames.push(frame.to_vec());
+                pos += 1;
+            }
+            Ok(None) => {
+                flush(&mut frames, &params, pos);
+                break Ok(());
+            }
+            Err(e) => break Err(e),
+        }
+    };
+
+    drop(tx);
+    let worker_res = worker.join().unwrap();
+    res?;
+    worker_res?;
+
+    reader.inf.frames = pos;
+    Ok(chunks)
 }
 
 #[cfg(feature = "vship")]
 pub struct ProbeConfig<'a> {
+    pub args: &'a crate::Args,
     pub yuv_frames: &'a [Vec<u8>],
     pub inf: &'a VidInf,
     pub params: &'a str,
@@ -460,9 +1586,13 @@ pub struct ProbeConfig<'a> {
 }
 
 #[cfg(feature = "vship")]
-pub fn encode_single_probe(config: &ProbeConfig, prog: Option<&Arc<ProgsTrack>>) {
+pub fn encode_single_probe(
+    config: &ProbeConfig,
+    prog: Option<&Arc<ProgsTrack>>,
+) -> Result<(), XavError> {
     let output = config.work_dir.join("split").join(config.probe_name);
     let enc_cfg = EncConfig {
+        args: config.args,
         inf: config.inf,
         params: config.params,
         crf: config.crf,
@@ -470,24 +1600,40 @@ pub fn encode_single_probe(config: &ProbeConfig, prog: Option<&Arc<ProgsTrack>>)
         grain_table: config.grain_table,
     };
     let mut cmd = make_enc_cmd(&enc_cfg, false);
-    let mut child = cmd.spawn().unwrap_or_else(|_| std::process::exit(1));
+    let mut child = cmd.spawn().map_err(|e| spawn_err(config.args.encoder, &e))?;
 
-    if let Some(p) = prog
-        && let Some(stderr) = child.stderr.take()
-    {
-        p.watch_enc(stderr, config.idx, false, config.crf_score);
-    }
+    let log = config.args.keep.then(|| {
+        config.work_dir.join("logs").join(Path::new(config.probe_name).with_extension("log"))
+    });
+    watch_stderr(&mut child, prog.map(AsRef::as_ref), log, config.idx, false, config.crf_score);
 
     let mut buf = Some(vec![0u8; calc_10bit_size(config.inf)]);
-    write_frames(&mut child, config.yuv_frames.to_vec(), config.inf, &mut buf);
-    child.wait().unwrap();
+    write_frames(&mut child, config.yuv_frames.to_vec(), config.inf, config.args.encoder, &mut buf);
+    let status = child.wait()?;
+    if !status.success() {
+        if STOP.load(Ordering::Relaxed) {
+            let _ = fs::remove_file(&output);
+            return Err(XavError::Stopped(format!("Probe of chunk {} was stopped", config.idx)));
+        }
+        return Err(XavError::Encode(format!(
+            "{} failed on a CRF {:.2} probe of chunk {} ({status})",
+            config.args.encoder.binary(),
+            config.crf,
+            config.idx
+        )));
+    }
+    Ok(())
 }
 
 #[cfg(feature = "vship")]
-fn create_tq_worker(
+pub fn create_tq_worker(
     inf: &VidInf,
     stride: u32,
-) -> (crate::zimg::ZimgProcessor, crate::zimg::ZimgProcessor, crate::vship::VshipProcessor) {
+) -> Result<
+    (crate::zimg::ZimgProcessor, crate::zimg::ZimgProcessor, crate::vship::VshipProcessor),
+    XavError,
+> {
+    let metric = |e: Box<dyn std::error::Error>| XavError::Metric(e.to_string());
     let ref_zimg = crate::zimg::ZimgProcessor::new(
         stride,
         inf.width,
@@ -500,13 +1646,13 @@ fn create_tq_worker(
             color_range: inf.color_range,
         },
     )
-    .unwrap();
+    .map_err(metric)?;
 
     let dist_zimg = crate::zimg::ZimgProcessor::new(
         stride,
         inf.width,
         inf.height,
-        true,
+        inf.out_10bit,
         crate::zimg::ColorParams {
             matrix: inf.matrix_coefficients,
             transfer: inf.transfer_characteristics,
@@ -514,20 +1660,21 @@ fn create_tq_worker(
             color_range: inf.color_range,
         },
     )
-    .unwrap();
+    .map_err(metric)?;
 
     let vship = crate::vship::VshipProcessor::new(
         inf.width,
         inf.height,
         inf.fps_num as f32 / inf.fps_den as f32,
     )
-    .unwrap();
+    .map_err(metric)?;
 
-    (ref_zimg, dist_zimg, vship)
+    Ok((ref_zimg, dist_zimg, vship))
 }
 
 #[cfg(feature = "vship")]
 struct TQChunkConfig<'a> {
+    args: &'a crate::Args,
     chunks: &'a [Chunk],
     inf: &'a VidInf,
     params: &'a str,
@@ -536,9 +1683,8 @@ struct TQChunkConfig<'a> {
     work_dir: &'a Path,
     prog: Option<&'a Arc<ProgsTrack>>,
     stride: u32,
-    rgb_size: usize,
     probe_info: &'a crate::tq::ProbeInfoMap,
-    stats: Option<&'a Arc<WorkerStats>>,
+    stats: &'a Arc<WorkerStats>,
     grain_table: Option<&'a Path>,
 }
 
@@ -549,37 +1695,116 @@ fn process_tq_chunk(
     ref_zimg: &mut crate::zimg::ZimgProcessor,
     dist_zimg: &mut crate::zimg::ZimgProcessor,
     vship: &crate::vship::VshipProcessor,
-) {
+    bufs: &mut crate::tq::TqBuffers,
+) -> Result<(), XavError> {
+    let params = chunk_params(config.params, data.params.as_deref());
     let mut ctx = crate::tq::QualityContext {
+        args: config.args,
         chunk: &config.chunks[data.idx],
         yuv_frames: &data.frames,
         inf: config.inf,
-        params: config.params,
+        params: &params,
         work_dir: config.work_dir,
         prog: config.prog,
         ref_zimg,
         dist_zimg,
         vship,
         stride: config.stride,
-        rgb_size: config.rgb_size,
+        bufs,
         grain_table: config.grain_table,
     };
 
-    if let Some(best) =
-        crate::tq::find_target_quality(&mut ctx, config.tq, config.qp, config.probe_info)
-    {
+    let best =
+        match crate::tq::find_target_quality(&mut ctx, config.tq, config.qp, config.probe_info) {
+            Err(XavError::Stopped(_)) => return Ok(()),
+            res => res?,
+        };
+    if let Some(best) = best {
         let src = config.work_dir.join("split").join(&best);
         let dst = config.work_dir.join("encode").join(format!("{:04}.ivf", data.idx));
-        std::fs::copy(&src, &dst).unwrap();
+        std::fs::copy(&src, &dst)?;
 
-        if let Some(s) = config.stats {
-            let meta = std::fs::metadata(&dst).unwrap();
-            let comp = ChunkComp { idx: data.idx, frames: data.frames.len(), size: meta.len() };
-            s.frames_done.fetch_add(data.frames.len(), Ordering::Relaxed);
-            s.completed.fetch_add(1, Ordering::Relaxed);
-            s.add_completion(comp, config.work_dir);
-        }
+        let meta = std::fs::metadata(&dst)?;
+        let comp = ChunkComp { idx: data.idx, frames: data.frames.len(), size: meta.len() };
+        crate::chunk::save_dropped(config.work_dir, &data.dropped)?;
+        config.stats.completed.fetch_add(1, Ordering::Relaxed);
+        config.stats.add_completion(comp, data.frames.len(), config.work_dir);
+    }
+    Ok(())
+}
+
+#[cfg(feature = "vship")]
+pub fn crf_curve(
+    chunks: &[Chunk],
+    inf: &VidInf,
+    args: &crate::Args,
+    idx: &Arc<VidIdx>,
+    work_dir: &Path,
+    grain_table: Option<&PathBuf>,
+    crfs: &[f64],
+) -> Result<Vec<(usize, Vec<crate::tq::CurvePoint>)>, XavError> {
+    let (tx, rx) = bounded::<ChunkData>(0);
+    let rx = Arc::new(rx);
+
+    let dec = {
+        let c = chunks.to_vec();
+        let i = Arc::clone(idx);
+        let inf = inf.clone();
+        let warn = args.out.clone();
+        thread::spawn(move || decode_chunks(&c, &i, &inf, &tx, &HashSet::new(), &warn))
+    };
+
+    let results = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut workers = Vec::new();
+    for _ in 0..args.worker.min(chunks.len()) {
+        let rx = Arc::clone(&rx);
+        let c = chunks.to_vec();
+        let inf = inf.clone();
+        let args = args.clone();
+        let wd = work_dir.to_path_buf();
+        let grain = grain_table.cloned();
+        let crfs = crfs.to_vec();
+        let results = Arc::clone(&results);
+
+        workers.push(thread::spawn(move || -> Result<(), XavError> {
+            let stride = (inf.width * 2).div_ceil(32) * 32;
+            let (mut ref_zimg, mut dist_zimg, vship) =
+                create_tq_worker(&inf, stride).inspect_err(stop_all)?;
+            let mut bufs = crate::tq::TqBuffers::new(&inf).inspect_err(stop_all)?;
+
+            while let Ok(data) = rx.recv() {
+                let params = chunk_params(&args.params, data.params.as_deref());
+                let mut ctx = crate::tq::QualityContext {
+                    args: &args,
+                    chunk: &c[data.idx],
+                    yuv_frames: &data.frames,
+                    inf: &inf,
+                    params: &params,
+                    work_dir: &wd,
+                    prog: None,
+                    ref_zimg: &mut ref_zimg,
+                    dist_zimg: &mut dist_zimg,
+                    vship: &vship,
+                    stride,
+                    bufs: &mut bufs,
+                    grain_table: grain.as_deref(),
+                };
+                let points = match crate::tq::probe_curve(&mut ctx, &crfs) {
+                    Err(XavError::Stopped(_)) => continue,
+                    res => res.inspect_err(stop_all)?,
+                };
+                results.lock().unwrap().push((data.frames.len(), points));
+            }
+            Ok(())
+        }));
     }
+
+    let dec_res = dec.join().unwrap();
+    let done: Vec<Result<(), XavError>> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+    dec_res?;
+    done.into_iter().collect::<Result<(), XavError>>()?;
+
+    Ok(Arc::try_unwrap(results).map(|r| r.into_inner().unwrap()).unwrap_or_default())
 }
 
 #[cfg(feature = "vship")]
@@ -590,7 +1815,7 @@ fn encode_tq(
     idx: &Arc<VidIdx>,
     work_dir: &Path,
     grain_table: Option<&PathBuf>,
-) {
+) -> Result<(), XavError> {
     let resume_data = if args.resume {
         get_resume(work_dir).unwrap_or(ResumeInf { chnks_done: Vec::new() })
     } else {
@@ -598,24 +1823,23 @@ fn encode_tq(
     };
 
     let skip_indices: HashSet<usize> = resume_data.chnks_done.iter().map(|c| c.idx).collect();
-    let completed_count = skip_indices.len();
-    let completed_frames: usize = resume_data.chnks_done.iter().map(|c| c.frames).sum();
-
-    let stats = if args.quiet {
-        None
-    } else {
-        Some(Arc::new(WorkerStats::new(completed_count, completed_frames, resume_data)))
-    };
-
-    let prog = stats.as_ref().map(|s| {
-        Arc::new(ProgsTrack::new(
-            chunks,
-            inf,
-            args.worker,
-            0,
-            Arc::clone(&s.completed),
-            Arc::clone(&s.completions),
-        ))
+    let stats = Arc::new(WorkerStats::new(resume_data, args, work_dir));
+
+    let prog = (!args.quiet).then(|| {
+        Arc::new(
+            ProgsTrack::new(
+                chunks,
+                inf,
+                args.worker,
+                Arc::clone(&stats.completed),
+                Arc::clone(&stats.completions),
+                Arc::clone(&stats.frames_done),
+                &args.out,
+            )
+            .with_fifo(args.progress_fifo.as_deref())
+            .with_refresh(args.refresh_ms)
+            .with_heartbeat(args.heartbeat),
+        )
     });
 
     let probe_info = Arc::new(std::sync::Mutex::new(std::collections::HashMap::new()));
@@ -627,9 +1851,8 @@ fn encode_tq(
         let c = chunks.to_vec();
         let i = Arc::clone(idx);
         let inf = inf.clone();
-        thread::spawn(move || {
-            decode_chunks(&c, &i, &inf, &tx, &skip_indices);
-        })
+        let warn = args.out.clone();
+        thread::spawn(move || decode_chunks(&c, &i, &inf, &tx, &skip_indices, &warn))
     };
 
     let mut workers = Vec::new();
@@ -641,18 +1864,20 @@ fn encode_tq(
         let params = args.params.clone();
         let tq = args.target_quality.clone().unwrap();
         let qp = args.qp_range.clone().unwrap();
-        let stats = stats.clone();
+        let stats = Arc::clone(&stats);
         let prog = prog.clone();
         let wd = work_dir.to_path_buf();
         let grain = grain_table.cloned();
+        let args = args.clone();
 
-        workers.push(thread::spawn(move || {
+        workers.push(thread::spawn(move || -> Result<(), XavError> {
             let stride = (inf.width * 2).div_ceil(32) * 32;
-            let rgb_size = (inf.width * inf.height * 2) as usize;
-
-            let (mut ref_zimg, mut dist_zimg, vship) = create_tq_worker(&inf, stride);
+            let (mut ref_zimg, mut dist_zimg, vship) =
+                create_tq_worker(&inf, stride).inspect_err(stop_all)?;
+            let mut bufs = crate::tq::TqBuffers::new(&inf).inspect_err(stop_all)?;
 
             let config = TQChunkConfig {
+                args: &args,
                 chunks: &c,
                 inf: &inf,
                 params: &params,
@@ -661,23 +1886,25 @@ fn encode_tq(
                 work_dir: &wd,
                 prog: prog.as_ref(),
                 stride,
-                rgb_size,
                 probe_info: &probe_info,
-                stats: stats.as_ref(),
+                stats: &stats,
                 grain_table: grain.as_deref(),
             };
 
             while let Ok(data) = rx.recv() {
-                process_tq_chunk(&data, &config, &mut ref_zimg, &mut dist_zimg, &vship);
+                process_tq_chunk(&data, &config, &mut ref_zimg, &mut dist_zimg, &vship, &mut bufs)
+                    .inspect_err(stop_all)?;
             }
+            Ok(())
         }));
     }
 
-    dec.join().unwrap();
-    for w in workers {
-        w.join().unwrap();
-    }
+    let dec_res = dec.join().unwrap();
+    let results: Vec<Result<(), XavError>> =
+        workers.into_iter().map(|w| w.join().unwrap()).collect();
     if let Some(p) = prog {
         p.final_update();
     }
+    dec_res?;
+    results.into_iter().collect()
 }
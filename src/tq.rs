@@ -2,9 +2,13 @@ use std::path::Path;
 use std::sync::Arc;
 
 use crate::chunk::Chunk;
+use crate::error::XavError;
 use crate::ffms::VidInf;
 use crate::interp::{akima, lerp, natural_cubic, pchip};
 
+const VERIFY_RUNS: u32 = 40;
+const VERIFY_RUN_LEN: usize = 24;
+
 pub type ProbeInfoMap = Arc<std::sync::Mutex<std::collections::HashMap<usize, (f32, Option<f64>)>>>;
 
 #[derive(Clone)]
@@ -36,7 +40,36 @@ impl TQConfig {
     }
 }
 
+pub struct TqBuffers {
+    ref_rgb: [crate::vship::PinnedBuffer; 3],
+    dist_rgb: [crate::vship::PinnedBuffer; 3],
+    unpacked: Vec<u8>,
+}
+
+#[allow(clippy::needless_pass_by_value)]
+fn metric(e: Box<dyn std::error::Error>) -> XavError {
+    XavError::Metric(e.to_string())
+}
+
+impl TqBuffers {
+    pub fn new(inf: &VidInf) -> Result<Self, XavError> {
+        let rgb_size = (inf.width * inf.height * 2) as usize;
+        let pinned = || -> Result<[crate::vship::PinnedBuffer; 3], XavError> {
+            Ok([
+                crate::vship::PinnedBuffer::new(rgb_size).map_err(metric)?,
+                crate::vship::PinnedBuffer::new(rgb_size).map_err(metric)?,
+                crate::vship::PinnedBuffer::new(rgb_size).map_err(metric)?,
+            ])
+        };
+        let unpacked =
+            if inf.is_10bit { vec![0u8; crate::ffms::calc_10bit_size(inf)] } else { Vec::new() };
+
+        Ok(Self { ref_rgb: pinned()?, dist_rgb: pinned()?, unpacked })
+    }
+}
+
 pub struct QualityContext<'a> {
+    pub args: &'a crate::Args,
     pub chunk: &'a Chunk,
     pub yuv_frames: &'a [Vec<u8>],
     pub inf: &'a VidInf,
@@ -47,7 +80,7 @@ pub struct QualityContext<'a> {
     pub dist_zimg: &'a mut crate::zimg::ZimgProcessor,
     pub vship: &'a crate::vship::VshipProcessor,
     pub stride: u32,
-    pub rgb_size: usize,
+    pub bufs: &'a mut TqBuffers,
     pub grain_table: Option<&'a Path>,
 }
 
@@ -59,10 +92,15 @@ fn binary_search(min: f64, max: f64) -> f64 {
     round_crf(f64::midpoint(min, max))
 }
 
-fn encode_probe(ctx: &QualityContext, crf: f64, last_score: Option<f64>) -> String {
+fn encode_probe(
+    ctx: &QualityContext,
+    crf: f64,
+    last_score: Option<f64>,
+) -> Result<String, XavError> {
     let probe_name = format!("{:04}_{:.2}.ivf", ctx.chunk.idx, crf);
     crate::svt::encode_single_probe(
         &crate::svt::ProbeConfig {
+            args: ctx.args,
             yuv_frames: ctx.yuv_frames,
             inf: ctx.inf,
             params: ctx.params,
@@ -74,8 +112,17 @@ fn encode_probe(ctx: &QualityContext, crf: f64, last_score: Option<f64>) -> Stri
             grain_table: ctx.grain_table,
         },
         ctx.prog,
-    );
-    probe_name
+    )?;
+    Ok(probe_name)
+}
+
+/// Destroys an FFMS video source on every exit path.
+struct SrcGuard(*mut libc::c_void);
+
+impl Drop for SrcGuard {
+    fn drop(&mut self) {
+        crate::ffms::destroy_vid_src(self.0);
+    }
 }
 
 fn measure_quality(
@@ -83,56 +130,66 @@ fn measure_quality(
     probe_path: &Path,
     crf: f32,
     last_score: Option<f64>,
-) -> f64 {
-    let idx = crate::ffms::VidIdx::new(probe_path, true).unwrap();
+) -> Result<f64, XavError> {
+    let idx = crate::ffms::VidIdx::new(
+        probe_path,
+        None,
+        &crate::ffms::IdxOpts {
+            cache: Some(crate::ffms::idx_path(probe_path)),
+            seek_mode: None,
+            track: None,
+        },
+    )?;
     let threads =
         std::thread::available_parallelism().map_or(8, |n| n.get().try_into().unwrap_or(8));
-    let output_source = crate::ffms::thr_vid_src(&idx, threads).unwrap();
+    let output_source = SrcGuard(crate::ffms::thr_vid_src(&idx, threads)?);
 
-    ctx.vship.reset().unwrap();
+    ctx.vship.reset().map_err(metric)?;
 
     let mut last_frame_score = 0.0;
+    let mut frame_scores = Vec::new();
     let start = std::time::Instant::now();
     let tot = ctx.yuv_frames.len();
 
     for (frame_idx, input_yuv_packed) in ctx.yuv_frames.iter().enumerate() {
-        let output_frame = crate::ffms::get_frame(output_source, frame_idx).unwrap();
+        let output_frame = crate::ffms::get_frame(output_source.0, frame_idx)?;
+        let bufs = &mut *ctx.bufs;
 
-        let input_yuv = if ctx.inf.is_10bit {
-            let mut unpacked = vec![0u8; crate::ffms::calc_10bit_size(ctx.inf)];
-            crate::ffms::unpack_10bit(input_yuv_packed, &mut unpacked);
-            unpacked
+        let input_yuv: &[u8] = if ctx.inf.is_10bit {
+            crate::ffms::unpack_10bit(input_yuv_packed, &mut bufs.unpacked);
+            &bufs.unpacked
         } else {
-            input_yuv_packed.clone()
+            input_yuv_packed
         };
 
-        let mut ref_rgb = [
-            crate::vship::PinnedBuffer::new(ctx.rgb_size).unwrap(),
-            crate::vship::PinnedBuffer::new(ctx.rgb_size).unwrap(),
-            crate::vship::PinnedBuffer::new(ctx.rgb_size).unwrap(),
-        ];
-        let mut dist_rgb = [
-            crate::vship::PinnedBuffer::new(ctx.rgb_size).unwrap(),
-            crate::vship::PinnedBuffer::new(ctx.rgb_size).unwrap(),
-            crate::vship::PinnedBuffer::new(ctx.rgb_size).unwrap(),
-        ];
-
         ctx.ref_zimg
             .conv_yuv_to_rgb(
-                &input_yuv,
+                input_yuv,
                 ctx.inf.width,
                 ctx.inf.height,
-                &mut ref_rgb,
+                &mut bufs.ref_rgb,
                 ctx.inf.is_10bit,
             )
-            .unwrap();
-        ctx.dist_zimg.convert_ffms_frame_to_rgb(output_frame, &mut dist_rgb).unwrap();
-
-        let ref_planes = [ref_rgb[0].as_ptr(), ref_rgb[1].as_ptr(), ref_rgb[2].as_ptr()];
-        let dist_planes = [dist_rgb[0].as_ptr(), dist_rgb[1].as_ptr(), dist_rgb[2].as_ptr()];
-
-        last_frame_score =
-            ctx.vship.compute_cvvdp(ref_planes, dist_planes, i64::from(ctx.stride)).unwrap();
+            .map_err(metric)?;
+        ctx.dist_zimg
+            .convert_ffms_frame_to_rgb(output_frame, &mut bufs.dist_rgb)
+            .map_err(metric)?;
+
+        let ref_planes = bufs.ref_rgb.each_ref().map(crate::vship::PinnedBuffer::as_ptr);
+        let dist_planes = bufs.dist_rgb.each_ref().map(crate::vship::PinnedBuffer::as_ptr);
+
+        if ctx.args.tq_percentile.is_some() {
+            last_frame_score = ctx
+                .vship
+                .compute_frame_cvvdp(ref_planes, dist_planes, i64::from(ctx.stride))
+                .map_err(metric)?;
+            frame_scores.push(last_frame_score);
+        } else {
+            last_frame_score = ctx
+                .vship
+                .compute_cvvdp(ref_planes, dist_planes, i64::from(ctx.stride))
+                .map_err(metric)?;
+        }
 
         if let Some(p) = ctx.prog {
             let elapsed = start.elapsed().as_secs_f32().max(0.001);
@@ -141,9 +198,39 @@ fn measure_quality(
         }
     }
 
-    crate::ffms::destroy_vid_src(output_source);
+    drop(output_source);
+
+    Ok(ctx.args.tq_percentile.map_or(last_frame_score, |p| percentile(&mut frame_scores, p)))
+}
 
-    last_frame_score
+#[allow(clippy::cast_precision_loss)]
+fn percentile(scores: &mut [f64], p: f64) -> f64 {
+    if scores.is_empty() {
+        return 0.0;
+    }
+    scores.sort_by(f64::total_cmp);
+    let pos = (p / 100.0 * (scores.len() - 1) as f64).round() as usize;
+    scores[pos.min(scores.len() - 1)]
+}
+
+fn keep_probe(ctx: &QualityContext, probe_path: &Path, crf: f64, score: f64) {
+    let dir = ctx.args.output.with_extension("probes");
+    let dst = dir.join(format!("{:04}_{crf:.2}_{score:.4}.ivf", ctx.chunk.idx));
+    if let Err(e) = std::fs::create_dir_all(&dir).and_then(|()| std::fs::copy(probe_path, dst)) {
+        ctx.args.out.warn(&format!("Failed to keep probe {}: {e}", probe_path.display()));
+    }
+}
+
+fn write_probe_csv(ctx: &QualityContext, probes: &[Probe]) {
+    let mut csv = String::from("crf,score\n");
+    for p in probes {
+        use std::fmt::Write;
+        let _ = writeln!(csv, "{:.2},{:.4}", p.crf, p.score);
+    }
+    let path = ctx.args.output.with_extension("probes").join(format!("{:04}.csv", ctx.chunk.idx));
+    if let Err(e) = std::fs::write(&path, csv) {
+        ctx.args.out.warn(&format!("Failed to write {}: {e}", path.display()));
+    }
 }
 
 fn interpolate_crf(probes: &[Probe], target: f64, round: usize) -> Option<f64> {
@@ -172,7 +259,7 @@ pub fn find_target_quality(
     tq_range: &str,
     qp_range: &str,
     probe_info: &ProbeInfoMap,
-) -> Option<String> {
+) -> Result<Option<String>, XavError> {
     let config = TQConfig::new(tq_range, qp_range);
     let mut probes = Vec::new();
     let mut search_min = config.min_crf;
@@ -188,10 +275,10 @@ pub fn find_target_quality(
         .clamp(search_min, search_max);
 
         let last_score_val = probes.last().map(|p| p.score);
-        let probe_name = encode_probe(ctx, crf, last_score_val);
+        let probe_name = encode_probe(ctx, crf, last_score_val)?;
         let probe_path = ctx.work_dir.join("split").join(&probe_name);
 
-        let score = measure_quality(ctx, &probe_path, crf as f32, last_score_val);
+        let score = measure_quality(ctx, &probe_path, crf as f32, last_score_val)?;
 
         {
             let mut info = probe_info.lock().unwrap();
@@ -200,8 +287,15 @@ pub fn find_target_quality(
 
         probes.push(Probe { crf, score });
 
+        if ctx.args.probe_keep {
+            keep_probe(ctx, &probe_path, crf, score);
+        }
+
         if config.in_range(score) {
-            return Some(probe_name);
+            if ctx.args.probe_keep {
+                write_probe_csv(ctx, &probes);
+            }
+            return Ok(Some(probe_name));
         }
 
         if score < config.target - config.tolerance {
@@ -215,11 +309,96 @@ pub fn find_target_quality(
         }
     }
 
+    if ctx.args.probe_keep {
+        write_probe_csv(ctx, &probes);
+    }
+
     probes.sort_by(|a, b| {
         let diff_a = (a.score - config.target).abs();
         let diff_b = (b.score - config.target).abs();
         diff_a.partial_cmp(&diff_b).unwrap()
     });
 
-    probes.first().map(|p| format!("{:04}_{:.2}.ivf", ctx.chunk.idx, p.crf))
+    Ok(probes.first().map(|p| format!("{:04}_{:.2}.ivf", ctx.chunk.idx, p.crf)))
+}
+
+pub struct CurvePoint {
+    pub crf: f64,
+    pub score: f64,
+    pub size: u64,
+}
+
+/// Scores the chunk at every CRF without keeping any of the probes.
+pub fn probe_curve(ctx: &mut QualityContext, crfs: &[f64]) -> Result<Vec<CurvePoint>, XavError> {
+    let mut points = Vec::with_capacity(crfs.len());
+    for &crf in crfs {
+        let probe_name = encode_probe(ctx, crf, None)?;
+        let probe_path = ctx.work_dir.join("split").join(&probe_name);
+        let score = measure_quality(ctx, &probe_path, crf as f32, None)?;
+        let size = std::fs::metadata(&probe_path).map_or(0, |m| m.len());
+
+        let _ = std::fs::remove_file(crate::ffms::idx_path(&probe_path));
+        let _ = std::fs::remove_file(&probe_path);
+        points.push(CurvePoint { crf, score, size });
+    }
+    Ok(points)
+}
+
+pub fn verify(
+    src_idx: &Arc<crate::ffms::VidIdx>,
+    inf: &VidInf,
+    chunks: &[Chunk],
+    output: &Path,
+) -> Result<Option<(f64, usize)>, XavError> {
+    let src_frames: Vec<usize> = chunks.iter().flat_map(|c| c.start..c.end).collect();
+    if src_frames.is_empty() {
+        return Ok(None);
+    }
+
+    let out_idx = crate::ffms::VidIdx::new(
+        output,
+        None,
+        &crate::ffms::IdxOpts { cache: None, seek_mode: None, track: None },
+    )?;
+    let threads =
+        std::thread::available_parallelism().map_or(8, |n| n.get().try_into().unwrap_or(8));
+    let src = SrcGuard(crate::ffms::thr_vid_src(src_idx, threads)?);
+    let dst = SrcGuard(crate::ffms::thr_vid_src(&out_idx, threads)?);
+
+    let stride = (inf.width * 2).div_ceil(32) * 32;
+    let (mut ref_zimg, mut dist_zimg, vship) = crate::svt::create_tq_worker(inf, stride)?;
+    let TqBuffers { mut ref_rgb, mut dist_rgb, .. } = TqBuffers::new(inf)?;
+
+    let run_len = VERIFY_RUN_LEN.min(src_frames.len());
+    let runs = u32::try_from(src_frames.len() / run_len.max(1))
+        .unwrap_or(VERIFY_RUNS)
+        .clamp(1, VERIFY_RUNS);
+    let span = (src_frames.len() - run_len) / (runs as usize - 1).max(1);
+    let mut total = 0.0;
+    let mut sampled = 0;
+
+    for run in 0..runs as usize {
+        vship.reset().map_err(metric)?;
+        let mut score = 0.0;
+        for (frame_idx, &src_idx) in src_frames.iter().enumerate().skip(run * span).take(run_len) {
+            let src_frame = crate::ffms::get_frame(src.0, src_idx)?;
+            ref_zimg.convert_ffms_frame_to_rgb(src_frame, &mut ref_rgb).map_err(metric)?;
+            dist_zimg
+                .convert_ffms_frame_to_rgb(crate::ffms::get_frame(dst.0, frame_idx)?, &mut dist_rgb)
+                .map_err(metric)?;
+
+            let ref_planes = [ref_rgb[0].as_ptr(), ref_rgb[1].as_ptr(), ref_rgb[2].as_ptr()];
+            let dist_planes = [dist_rgb[0].as_ptr(), dist_rgb[1].as_ptr(), dist_rgb[2].as_ptr()];
+            score =
+                vship.compute_cvvdp(ref_planes, dist_planes, i64::from(stride)).map_err(metric)?;
+        }
+        total += score;
+        sampled += run_len;
+    }
+    let score = total / f64::from(runs);
+
+    drop((src, dst));
+    drop(out_idx);
+
+    Ok(Some((score, sampled)))
 }
@@ -51,6 +51,19 @@ unsafe extern "C" {
     fn Vship_PinnedFree(ptr: *mut std::ffi::c_void) -> VshipException;
 }
 
+pub fn check_device() -> Result<(), String> {
+    unsafe {
+        let ret = Vship_SetDevice(0);
+        if ret as i32 != 0 {
+            let mut err_msg = vec![0i8; 1024];
+            Vship_GetErrorMessage(ret, err_msg.as_mut_ptr(), 1024);
+            let err = std::ffi::CStr::from_ptr(err_msg.as_ptr()).to_string_lossy();
+            return Err(format!("Failed to set VSHIP device: {err}"));
+        }
+        Ok(())
+    }
+}
+
 pub struct VshipProcessor {
     handler: VshipCVVDPHandler,
 }
@@ -123,6 +136,16 @@ impl VshipProcessor {
             Ok(score)
         }
     }
+
+    pub fn compute_frame_cvvdp(
+        &self,
+        planes1: [*const u8; 3],
+        planes2: [*const u8; 3],
+        stride: i64,
+    ) -> Result<f64, Box<dyn std::error::Error>> {
+        self.reset()?;
+        self.compute_cvvdp(planes1, planes2, stride)
+    }
 }
 
 impl Drop for VshipProcessor {
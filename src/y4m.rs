@@ -0,0 +1,120 @@
+use std::io::BufRead;
+
+use crate::error::XavError;
+use crate::ffms::{VidInf, calc_8bit_size, calc_10bit_size, calc_packed_size, pack_10bit};
+
+pub struct Y4mReader<R> {
+    reader: R,
+    pub inf: VidInf,
+    raw: Vec<u8>,
+    frame: Vec<u8>,
+    pub bytes: u64,
+}
+
+fn parse_header(line: &str) -> Result<VidInf, XavError> {
+    let bad = || XavError::Decode(format!("Invalid Y4M header: {}", line.trim()));
+    let mut tokens = line.split_whitespace();
+    if tokens.next() != Some("YUV4MPEG2") {
+        return Err(XavError::Decode("Input is not a Y4M stream".into()));
+    }
+
+    let (mut width, mut height, mut fps) = (0, 0, (0, 1));
+    let (mut is_10bit, mut chroma, mut range) = (false, None, None);
+    for token in tokens {
+        let (tag, val) = token.split_at_checked(1).ok_or_else(bad)?;
+        match tag {
+            "W" => width = val.parse().map_err(|_| bad())?,
+            "H" => height = val.parse().map_err(|_| bad())?,
+            "F" => {
+                let (num, den) = val.split_once(':').ok_or_else(bad)?;
+                fps = (num.parse().map_err(|_| bad())?, den.parse().map_err(|_| bad())?);
+            }
+            "I" if !matches!(val, "p" | "?") => {
+                return Err(XavError::Decode("Interlaced Y4M is not supported".into()));
+            }
+            "C" => {
+                (is_10bit, chroma) = match val {
+                    "420" | "420jpeg" => (false, None),
+                    "420mpeg2" => (false, Some(1)),
+                    "420paldv" => (false, Some(2)),
+                    "420p10" => (true, None),
+                    _ => {
+                        return Err(XavError::Decode(format!(
+                            "Y4M colorspace {val} is not supported, use 4:2:0 8 or 10-bit"
+                        )));
+                    }
+                };
+            }
+            "X" => {
+                range = match val {
+                    "COLORRANGE=FULL" => Some(1),
+                    "COLORRANGE=LIMITED" => Some(0),
+                    _ => range,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    if width == 0 || height == 0 || fps.0 == 0 || fps.1 == 0 {
+        return Err(bad());
+    }
+
+    Ok(VidInf {
+        width,
+        height,
+        fps_num: fps.0,
+        fps_den: fps.1,
+        frames: 0,
+        color_primaries: None,
+        transfer_characteristics: None,
+        matrix_coefficients: None,
+        is_10bit,
+        out_10bit: true,
+        vfr: false,
+        gray: false,
+        color_range: range,
+        chroma_sample_position: chroma,
+        mastering_display: None,
+        content_light: None,
+        #[cfg(feature = "vship")]
+        tonemap: None,
+    })
+}
+
+impl<R: BufRead> Y4mReader<R> {
+    pub fn new(mut reader: R) -> Result<Self, XavError> {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let inf = parse_header(&line)?;
+
+        let (raw, frame) = if inf.is_10bit {
+            (vec![0u8; calc_10bit_size(&inf)], vec![0u8; calc_packed_size(&inf)])
+        } else {
+            (Vec::new(), vec![0u8; calc_8bit_size(&inf)])
+        };
+
+        Ok(Self { reader, inf, raw, frame, bytes: line.len() as u64 })
+    }
+
+    pub fn next_frame(&mut self) -> Result<Option<&[u8]>, XavError> {
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.starts_with(b"FRAME") {
+            return Err(XavError::Decode("Corrupt Y4M stream, expected FRAME".into()));
+        }
+
+        if self.inf.is_10bit {
+            self.reader.read_exact(&mut self.raw)?;
+            pack_10bit(&self.raw, &mut self.frame);
+            self.bytes += (line.len() + self.raw.len()) as u64;
+        } else {
+            self.reader.read_exact(&mut self.frame)?;
+            self.bytes += (line.len() + self.frame.len()) as u64;
+        }
+
+        Ok(Some(&self.frame))
+    }
+}
@@ -1,6 +1,6 @@
 use std::ptr;
 
-use crate::ffms::FFMS_Frame;
+use crate::ffms::{FFMS_Frame, Tonemap};
 use crate::vship::PinnedBuffer;
 
 #[derive(Copy, Clone)]
@@ -78,6 +78,7 @@ const ZIMG_API_VERSION: u32 = (2 << 8) | 4;
 const ZIMG_BUFFER_MAX: u32 = !0u32;
 const ZIMG_PIXEL_BYTE: i32 = 0;
 const ZIMG_PIXEL_WORD: i32 = 1;
+const ZIMG_PIXEL_FLOAT: i32 = 3;
 const ZIMG_COLOR_RGB: i32 = 1;
 const ZIMG_COLOR_YUV: i32 = 2;
 const ZIMG_RANGE_LIMITED: i32 = 0;
@@ -86,6 +87,7 @@ const ZIMG_CPU_AUTO: i32 = 1;
 const ZIMG_MATRIX_RGB: i32 = 0;
 const ZIMG_MATRIX_BT709: i32 = 1;
 const ZIMG_TRANSFER_BT709: i32 = 1;
+const ZIMG_TRANSFER_LINEAR: i32 = 8;
 const ZIMG_PRIMARIES_BT709: i32 = 1;
 
 unsafe extern "C" {
@@ -144,7 +146,7 @@ impl ZimgProcessor {
                 Some(p) => p,
             };
             let range = match color_params.color_range {
-                Some(2) => ZIMG_RANGE_FULL,
+                Some(1) => ZIMG_RANGE_FULL,
                 _ => ZIMG_RANGE_LIMITED,
             };
 
@@ -338,6 +340,230 @@ impl ZimgProcessor {
     }
 }
 
+const SDR_WHITE: f64 = 203.0;
+
+unsafe fn build_graph(
+    src: &ZimgImageFormat,
+    dst: &ZimgImageFormat,
+    params: &ZimgGraphBuilderParams,
+) -> Result<*mut libc::c_void, Box<dyn std::error::Error>> {
+    unsafe {
+        let graph =
+            zimg_filter_graph_build(ptr::from_ref(src), ptr::from_ref(dst), ptr::from_ref(params));
+        if graph.is_null() {
+            let mut err_msg = vec![0i8; 1024];
+            zimg_get_last_error(err_msg.as_mut_ptr(), 1024);
+            let err = std::ffi::CStr::from_ptr(err_msg.as_ptr()).to_string_lossy();
+            return Err(format!("Failed to build graph: {err}").into());
+        }
+        Ok(graph)
+    }
+}
+
+/// Converts 10-bit PQ/HLG YUV frames to 10-bit BT.709 YUV with an extended Reinhard curve
+/// on linear luminance, scaled so that 203 nits lands on SDR white.
+pub struct Tonemapper {
+    to_linear: *mut libc::c_void,
+    to_sdr: *mut libc::c_void,
+    tmp_buffer: Vec<u8>,
+    rgb: [Vec<f32>; 3],
+    out: Vec<u8>,
+    width: usize,
+    height: usize,
+    peak: f32,
+}
+
+unsafe impl Send for Tonemapper {}
+
+impl Tonemapper {
+    pub fn new(width: u32, height: u32, tm: &Tonemap) -> Result<Self, Box<dyn std::error::Error>> {
+        let (w, h) = (width as usize, height as usize);
+        let mut tonemapper = Self {
+            to_linear: ptr::null_mut(),
+            to_sdr: ptr::null_mut(),
+            tmp_buffer: Vec::new(),
+            rgb: [vec![0.0; w * h], vec![0.0; w * h], vec![0.0; w * h]],
+            out: vec![0u8; w * h * 3],
+            width: w,
+            height: h,
+            peak: (tm.peak / SDR_WHITE) as f32,
+        };
+
+        unsafe {
+            let mut hdr = std::mem::zeroed::<ZimgImageFormat>();
+            zimg_image_format_default(ptr::from_mut(&mut hdr), ZIMG_API_VERSION);
+            hdr.width = width;
+            hdr.height = height;
+            hdr.pixel_type = ZIMG_PIXEL_WORD;
+            hdr.subsample_w = 1;
+            hdr.subsample_h = 1;
+            hdr.color_family = ZIMG_COLOR_YUV;
+            hdr.matrix_coefficients = tm.matrix;
+            hdr.transfer_characteristics = tm.transfer;
+            hdr.color_primaries = tm.primaries;
+            hdr.depth = 10;
+            hdr.pixel_range = if tm.full_range { ZIMG_RANGE_FULL } else { ZIMG_RANGE_LIMITED };
+
+            let mut sdr = std::mem::zeroed::<ZimgImageFormat>();
+            zimg_image_format_default(ptr::from_mut(&mut sdr), ZIMG_API_VERSION);
+            sdr.width = width;
+            sdr.height = height;
+            sdr.pixel_type = ZIMG_PIXEL_WORD;
+            sdr.subsample_w = 1;
+            sdr.subsample_h = 1;
+            sdr.color_family = ZIMG_COLOR_YUV;
+            sdr.matrix_coefficients = ZIMG_MATRIX_BT709;
+            sdr.transfer_characteristics = ZIMG_TRANSFER_BT709;
+            sdr.color_primaries = ZIMG_PRIMARIES_BT709;
+            sdr.depth = 10;
+            sdr.pixel_range = ZIMG_RANGE_LIMITED;
+
+            let mut linear = std::mem::zeroed::<ZimgImageFormat>();
+            zimg_image_format_default(ptr::from_mut(&mut linear), ZIMG_API_VERSION);
+            linear.width = width;
+            linear.height = height;
+            linear.pixel_type = ZIMG_PIXEL_FLOAT;
+            linear.color_family = ZIMG_COLOR_RGB;
+            linear.matrix_coefficients = ZIMG_MATRIX_RGB;
+            linear.transfer_characteristics = ZIMG_TRANSFER_LINEAR;
+            linear.color_primaries = ZIMG_PRIMARIES_BT709;
+            linear.depth = 32;
+            linear.pixel_range = ZIMG_RANGE_FULL;
+
+            let mut params = std::mem::zeroed::<ZimgGraphBuilderParams>();
+            zimg_graph_builder_params_default(ptr::from_mut(&mut params), ZIMG_API_VERSION);
+            params.cpu_type = ZIMG_CPU_AUTO;
+            params.nominal_peak_luminance = SDR_WHITE;
+
+            tonemapper.to_linear = build_graph(&hdr, &linear, &params)?;
+            tonemapper.to_sdr = build_graph(&linear, &sdr, &params)?;
+
+            let (mut a, mut b) = (0usize, 0usize);
+            zimg_filter_graph_get_tmp_size(tonemapper.to_linear, ptr::from_mut(&mut a));
+            zimg_filter_graph_get_tmp_size(tonemapper.to_sdr, ptr::from_mut(&mut b));
+            tonemapper.tmp_buffer = vec![0u8; a.max(b) + 32];
+        }
+
+        Ok(tonemapper)
+    }
+
+    fn yuv_planes(&self, data: *const u8) -> [(*const u8, isize); 3] {
+        let y_size = self.width * self.height * 2;
+        let uv_size = y_size / 4;
+        let y_stride = isize::try_from(self.width * 2).unwrap();
+        let uv_stride = isize::try_from(self.width).unwrap();
+        unsafe {
+            [
+                (data, y_stride),
+                (data.add(y_size), uv_stride),
+                (data.add(y_size + uv_size), uv_stride),
+            ]
+        }
+    }
+
+    fn run(
+        &mut self,
+        graph: *mut libc::c_void,
+        src: &ZimgImageBufferConst,
+        dst: &ZimgImageBuffer,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        unsafe {
+            let tmp_ptr = self.tmp_buffer.as_mut_ptr() as usize;
+            let tmp_aligned = ((tmp_ptr + 31) & !31) as *mut libc::c_void;
+
+            let ret = zimg_filter_graph_process(
+                graph,
+                ptr::from_ref(src),
+                ptr::from_ref(dst),
+                tmp_aligned,
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null_mut(),
+            );
+
+            if ret != 0 {
+                let mut err_msg = vec![0i8; 1024];
+                zimg_get_last_error(err_msg.as_mut_ptr(), 1024);
+                let err = std::ffi::CStr::from_ptr(err_msg.as_ptr()).to_string_lossy();
+                return Err(format!("ZIMG failed: {err}").into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&mut self, frame: &mut [u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let rgb_stride = isize::try_from(self.width * 4).unwrap();
+        unsafe {
+            let mut src_buf = std::mem::zeroed::<ZimgImageBufferConst>();
+            src_buf.version = ZIMG_API_VERSION;
+            for (plane, (data, stride)) in
+                src_buf.plane.iter_mut().zip(self.yuv_planes(frame.as_ptr()))
+            {
+                *plane = ZimgPlaneConst { data: data.cast(), stride, mask: ZIMG_BUFFER_MAX };
+            }
+            let mut rgb_buf = std::mem::zeroed::<ZimgImageBuffer>();
+            rgb_buf.version = ZIMG_API_VERSION;
+            for (plane, rgb) in rgb_buf.plane.iter_mut().zip(self.rgb.iter_mut()) {
+                *plane = ZimgPlane {
+                    data: rgb.as_mut_ptr().cast(),
+                    stride: rgb_stride,
+                    mask: ZIMG_BUFFER_MAX,
+                };
+            }
+            self.run(self.to_linear, &src_buf, &rgb_buf)?;
+        }
+
+        let white = self.peak * self.peak;
+        let [r, g, b] = &mut self.rgb;
+        for ((r, g), b) in r.iter_mut().zip(g.iter_mut()).zip(b.iter_mut()) {
+            let lum = 0.2126f32.mul_add(*r, 0.7152f32.mul_add(*g, 0.0722 * *b));
+            if lum > 0.0 {
+                let scale = (1.0 + lum / white) / (1.0 + lum);
+                *r = (*r * scale).clamp(0.0, 1.0);
+                *g = (*g * scale).clamp(0.0, 1.0);
+                *b = (*b * scale).clamp(0.0, 1.0);
+            }
+        }
+
+        unsafe {
+            let mut rgb_buf = std::mem::zeroed::<ZimgImageBufferConst>();
+            rgb_buf.version = ZIMG_API_VERSION;
+            for (plane, rgb) in rgb_buf.plane.iter_mut().zip(self.rgb.iter()) {
+                *plane = ZimgPlaneConst {
+                    data: rgb.as_ptr().cast(),
+                    stride: rgb_stride,
+                    mask: ZIMG_BUFFER_MAX,
+                };
+            }
+            let mut dst_buf = std::mem::zeroed::<ZimgImageBuffer>();
+            dst_buf.version = ZIMG_API_VERSION;
+            for (plane, (data, stride)) in
+                dst_buf.plane.iter_mut().zip(self.yuv_planes(self.out.as_ptr()))
+            {
+                *plane = ZimgPlane { data: data.cast_mut().cast(), stride, mask: ZIMG_BUFFER_MAX };
+            }
+            self.run(self.to_sdr, &rgb_buf, &dst_buf)?;
+        }
+
+        frame.copy_from_slice(&self.out[..frame.len()]);
+        Ok(())
+    }
+}
+
+impl Drop for Tonemapper {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.to_linear.is_null() {
+                zimg_filter_graph_free(self.to_linear);
+            }
+            if !self.to_sdr.is_null() {
+                zimg_filter_graph_free(self.to_sdr);
+            }
+        }
+    }
+}
+
 impl Drop for ZimgProcessor {
     fn drop(&mut self) {
         unsafe {